@@ -1,13 +1,212 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    reply::{Response, StaleRequest},
+    request::{ReadBound, Reconfiguration, Request},
+    Codec,
+};
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 pub struct Client {
     pub id: usize,
     pub request_number: usize,
+    // Latest view number the client learned about from the replies.
+    pub view_number: usize,
+    // Highest commit number seen in the replies, reads bounded by it observe our own writes.
+    pub commit_number: usize,
+    // Members of the current epoch, as indexes into the `addresses`.
+    pub replicas: Vec<usize>,
+    // How long the client waits for the reply, before resending the request to every replica.
+    pub request_timeout: Duration,
+    addresses: Vec<SocketAddr>,
+    connections: Vec<Option<Connection>>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    // Bytes received, that don't make up a whole reply yet. A read may time out in the middle of a frame,
+    // the rest of it follows on the next read.
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    /// Waits for a response up to the read timeout, `None` if it hasn't arrived yet.
+    fn receive<R: Codec>(&mut self) -> std::io::Result<Option<Response<R>>> {
+        if let Some(response) = Response::take_from(&mut self.buffer)? {
+            return Ok(Some(response));
+        }
+        let mut chunk = [0u8; 4096];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                self.buffer.extend_from_slice(&chunk[..read]);
+                Response::take_from(&mut self.buffer)
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 impl Client {
-    pub fn new(id: usize) -> Self {
+    pub fn new(id: usize, addresses: Vec<SocketAddr>) -> Self {
+        let connections = addresses.iter().map(|_| None).collect();
         Self {
             id,
             request_number: 0,
+            view_number: 0,
+            commit_number: 0,
+            replicas: (0..addresses.len()).collect(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            addresses,
+            connections,
+        }
+    }
+
+    /// Same rule as `ReplicaConfig::primary_id` uses on the replicas.
+    pub fn primary_id(&self) -> usize {
+        self.replicas[self.view_number % self.replicas.len()]
+    }
+
+    /// Sends the op to the primary and blocks until it's committed, returning its result.
+    /// If the primary doesn't reply in time, the request is resent to the whole cluster.
+    /// The result type has to match the state machine run by the replicas.
+    /// Fails, if the session of the client has moved past the request, the op isn't executed
+    /// and the following requests resume after the latest one of the session.
+    pub fn send<R: Codec>(&mut self, op: impl Codec) -> Result<R, StaleRequest> {
+        let bytes = Request::new(self.id, self.request_number, op).to_bytes();
+        self.execute(&bytes)
+    }
+
+    /// Sends the read-only op, the primary answers it from its state without going through the log.
+    /// Ops, that change the state are still committed as if they were sent with `send`.
+    pub fn read<R: Codec>(&mut self, op: impl Codec) -> Result<R, StaleRequest> {
+        let bytes = Request::read(self.id, self.request_number, op).to_bytes();
+        self.execute(&bytes)
+    }
+
+    /// Sends the read-only op to one of the replicas, picked in turns to spread the load.
+    /// The replica answers once its state satisfies the `bound`.
+    pub fn read_stale<R: Codec>(
+        &mut self,
+        op: impl Codec,
+        bound: ReadBound,
+    ) -> Result<R, StaleRequest> {
+        let replica_id = self.replicas[self.request_number % self.replicas.len()];
+        let bytes = Request::stale_read(self.id, self.request_number, op, bound).to_bytes();
+        self.execute_on(replica_id, &bytes)
+    }
+
+    /// Replaces the members of `epoch_number` with the `replicas` and returns the number of the new epoch,
+    /// the following requests go to its members.
+    pub fn reconfigure(
+        &mut self,
+        epoch_number: usize,
+        replicas: Vec<usize>,
+    ) -> Result<usize, StaleRequest> {
+        let bytes = Reconfiguration {
+            client_id: self.id,
+            request_number: self.request_number,
+            epoch_number,
+            replicas: replicas.clone(),
+        }
+        .to_bytes();
+        let epoch_number: u64 = self.execute(&bytes)?;
+        self.replicas = replicas;
+        Ok(epoch_number as usize)
+    }
+
+    fn execute<R: Codec>(&mut self, bytes: &[u8]) -> Result<R, StaleRequest> {
+        self.execute_on(self.primary_id(), bytes)
+    }
+
+    fn execute_on<R: Codec>(&mut self, replica_id: usize, bytes: &[u8]) -> Result<R, StaleRequest> {
+        let request_number = self.request_number;
+        self.send_to_replica(replica_id, bytes);
+        loop {
+            match self.wait_for_reply(request_number) {
+                Some(Response::Reply(reply)) => {
+                    // The reply comes from the primary of the `reply.view_number`.
+                    self.view_number = self.view_number.max(reply.view_number);
+                    self.commit_number = self.commit_number.max(reply.commit_number);
+                    self.request_number += 1;
+                    return Ok(reply.result);
+                }
+                Some(Response::Stale(stale)) => {
+                    self.request_number = stale.latest_request_number + 1;
+                    return Err(stale);
+                }
+                None => {}
+            }
+            println!(
+                "Request: {} timed out, resending to all replicas",
+                request_number
+            );
+            for replica_id in 0..self.addresses.len() {
+                self.send_to_replica(replica_id, bytes);
+            }
+        }
+    }
+
+    fn send_to_replica(&mut self, replica_id: usize, bytes: &[u8]) {
+        if self.connections[replica_id].is_none() {
+            match TcpStream::connect(self.addresses[replica_id]) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+                    self.connections[replica_id] = Some(Connection {
+                        stream,
+                        buffer: Vec::new(),
+                    });
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Failed to connect to replica with id: {}, {}",
+                        replica_id, e
+                    );
+                    return;
+                }
+            }
+        }
+        let connection = self.connections[replica_id].as_mut().unwrap();
+        if let Err(e) = connection.stream.write_all(bytes) {
+            eprintln!(
+                "Failed to send request to replica with id: {}, {}",
+                replica_id, e
+            );
+            self.connections[replica_id] = None;
+        }
+    }
+
+    fn wait_for_reply<R: Codec>(&mut self, request_number: usize) -> Option<Response<R>> {
+        let deadline = Instant::now() + self.request_timeout;
+        while Instant::now() < deadline {
+            for (replica_id, connection) in self.connections.iter_mut().enumerate() {
+                let Some(receiver) = connection else {
+                    continue;
+                };
+                match receiver.receive() {
+                    // Replies to older requests are retransmissions, ignore them.
+                    Ok(Some(response)) if response.request_number() == request_number => {
+                        return Some(response)
+                    }
+                    Ok(_) => {}
+                    // A malformed reply leaves the stream out of sync, the connection is dropped as well.
+                    Err(e) => {
+                        eprintln!("Lost connection to replica with id: {}, {}", replica_id, e);
+                        *connection = None;
+                    }
+                }
+            }
+            if self.connections.iter().all(Option::is_none) {
+                std::thread::sleep(POLL_INTERVAL);
+            }
         }
+        None
     }
 }
@@ -0,0 +1,84 @@
+/// Encoding of the ops and the results, used both on the wire and on disk.
+pub trait Codec: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+    /// Returns the decoded value along with the number of bytes it took,
+    /// `None` if the bytes are cut short or malformed.
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)>;
+}
+
+impl Codec for u64 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let value = u64::from_le_bytes(bytes.get(0..8)?.try_into().unwrap());
+        Some((value, 8))
+    }
+}
+
+impl Codec for bool {
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        Some((*bytes.first()? != 0, 1))
+    }
+}
+
+/// Variable-length byte string, prefixed with its length.
+impl Codec for Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.len());
+        bytes.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let len = u32::from_le_bytes(bytes.get(0..4)?.try_into().unwrap()) as usize;
+        Some((bytes.get(4..4 + len)?.to_vec(), 4 + len))
+    }
+}
+
+/// Prefixed with a flag byte, the value follows only if it's present.
+impl<T: Codec> Codec for Option<T> {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            None => vec![0],
+            Some(value) => {
+                let mut bytes = vec![1];
+                bytes.extend(value.to_bytes());
+                bytes
+            }
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        match bytes.first()? {
+            0 => Some((None, 1)),
+            1 => {
+                let (value, size) = T::from_bytes(&bytes[1..])?;
+                Some((Some(value), 1 + size))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_or_malformed_bytes_should_fail_to_decode() {
+        let bytes = b"value".to_vec().to_bytes();
+        assert!(Vec::<u8>::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        // Length prefix claiming more bytes, than the frame holds.
+        assert!(Vec::<u8>::from_bytes(&[255, 255, 255, 255, 1]).is_none());
+        assert!(u64::from_bytes(&[1, 2, 3]).is_none());
+        assert!(Option::<u64>::from_bytes(&[2, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(bool::from_bytes(&[]).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    time::Duration,
+};
+
+/// Part, that the replica plays in the cluster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    // Member of the initial epoch.
+    Voter,
+    // Idle until a reconfiguration adds it.
+    Standby,
+    // Follows the log without voting.
+    Learner,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaDescriptor {
+    pub id: usize,
+    pub address: SocketAddr,
+    pub role: Role,
+}
+
+/// Cluster topology shared by the replicas and the clients, loaded from a file like:
+/// ```text
+/// cluster_id = 1
+/// tick_millis = 500
+///
+/// [[replica]]
+/// id = 0
+/// address = "127.0.0.1:1337"
+/// role = "voter"
+/// ```
+/// Replica ids have to be numbered from zero. Timeouts left out keep their defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterConfig {
+    // Messages between the replicas of different clusters are dropped.
+    pub cluster_id: u64,
+    // Ordered by the replica id.
+    pub replicas: Vec<ReplicaDescriptor>,
+    pub tick: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub batch_window: Option<Duration>,
+    pub max_batch_size: Option<usize>,
+    pub checkpoint_interval: Option<usize>,
+    pub lease_duration: Option<Duration>,
+}
+
+impl ClusterConfig {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut cluster_id = None;
+        let mut config = Self {
+            cluster_id: 0,
+            replicas: Vec::new(),
+            tick: None,
+            request_timeout: None,
+            batch_window: None,
+            max_batch_size: None,
+            checkpoint_interval: None,
+            lease_duration: None,
+        };
+        // Fields of the `[[replica]]` table being parsed.
+        let mut replica: Option<(Option<usize>, Option<SocketAddr>, Role)> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[replica]]" {
+                if let Some(fields) = replica.take() {
+                    config.replicas.push(finish_replica(fields, line_number)?);
+                }
+                replica = Some((None, None, Role::Voter));
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(invalid(line_number, "expected `key = value`"));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match (&mut replica, key) {
+                (Some((id, _, _)), "id") => *id = Some(parse_number(value, line_number)?),
+                (Some((_, address, _)), "address") => {
+                    let parsed = parse_string(value, line_number)?
+                        .parse()
+                        .map_err(|_| invalid(line_number, "invalid address"))?;
+                    *address = Some(parsed);
+                }
+                (Some((_, _, role)), "role") => {
+                    *role = match parse_string(value, line_number)? {
+                        "voter" => Role::Voter,
+                        "standby" => Role::Standby,
+                        "learner" => Role::Learner,
+                        _ => return Err(invalid(line_number, "unknown role")),
+                    };
+                }
+                (Some(_), _) => return Err(invalid(line_number, "unknown replica key")),
+                (None, "cluster_id") => cluster_id = Some(parse_number(value, line_number)?),
+                (None, "tick_millis") => config.tick = Some(parse_millis(value, line_number)?),
+                (None, "request_timeout_millis") => {
+                    config.request_timeout = Some(parse_millis(value, line_number)?)
+                }
+                (None, "batch_window_millis") => {
+                    config.batch_window = Some(parse_millis(value, line_number)?)
+                }
+                (None, "max_batch_size") => {
+                    config.max_batch_size = Some(parse_number(value, line_number)?)
+                }
+                (None, "checkpoint_interval") => {
+                    config.checkpoint_interval = Some(parse_number(value, line_number)?)
+                }
+                (None, "lease_duration_millis") => {
+                    config.lease_duration = Some(parse_millis(value, line_number)?)
+                }
+                (None, _) => return Err(invalid(line_number, "unknown key")),
+            }
+        }
+        let line_count = text.lines().count();
+        if let Some(fields) = replica.take() {
+            config.replicas.push(finish_replica(fields, line_count)?);
+        }
+
+        config.cluster_id = cluster_id.ok_or_else(|| invalid(line_count, "missing cluster_id"))?;
+        config.replicas.sort_by_key(|replica| replica.id);
+        // The replicas are addressed by their position.
+        if config
+            .replicas
+            .iter()
+            .enumerate()
+            .any(|(index, replica)| replica.id != index)
+        {
+            return Err(invalid(
+                line_count,
+                "replica ids have to be numbered from zero",
+            ));
+        }
+        if config.voters().is_empty() {
+            return Err(invalid(line_count, "no voters in the cluster"));
+        }
+        Ok(config)
+    }
+
+    /// Addresses of all the replicas, indexed by the replica id.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.replicas
+            .iter()
+            .map(|replica| replica.address)
+            .collect()
+    }
+
+    pub fn voters(&self) -> Vec<usize> {
+        self.with_role(Role::Voter)
+    }
+
+    pub fn learners(&self) -> Vec<usize> {
+        self.with_role(Role::Learner)
+    }
+
+    fn with_role(&self, role: Role) -> Vec<usize> {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.role == role)
+            .map(|replica| replica.id)
+            .collect()
+    }
+}
+
+fn finish_replica(
+    (id, address, role): (Option<usize>, Option<SocketAddr>, Role),
+    line_number: usize,
+) -> io::Result<ReplicaDescriptor> {
+    let id = id.ok_or_else(|| invalid(line_number, "replica without an id"))?;
+    let address = address.ok_or_else(|| invalid(line_number, "replica without an address"))?;
+    Ok(ReplicaDescriptor { id, address, role })
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, line_number: usize) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(line_number, "expected a number"))
+}
+
+fn parse_millis(value: &str, line_number: usize) -> io::Result<Duration> {
+    parse_number(value, line_number).map(Duration::from_millis)
+}
+
+fn parse_string(value: &str, line_number: usize) -> io::Result<&str> {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(|| invalid(line_number, "expected a quoted string"))
+}
+
+fn invalid(line_number: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid cluster config at line {}: {}", line_number, reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster_config_should_be_parsed() {
+        let text = r#"
+            # Two voters and a learner.
+            cluster_id = 7
+            tick_millis = 100
+
+            [[replica]]
+            id = 1
+            address = "127.0.0.1:2000"
+
+            [[replica]]
+            id = 0
+            address = "127.0.0.1:1000" # Listed out of order.
+
+            [[replica]]
+            id = 2
+            address = "127.0.0.1:3000"
+            role = "learner"
+        "#;
+        let config = ClusterConfig::parse(text).unwrap();
+        assert_eq!(config.cluster_id, 7);
+        assert_eq!(config.tick, Some(Duration::from_millis(100)));
+        assert_eq!(config.lease_duration, None);
+        assert_eq!(config.voters(), vec![0, 1]);
+        assert_eq!(config.learners(), vec![2]);
+        assert_eq!(config.addresses()[1], "127.0.0.1:2000".parse().unwrap());
+    }
+
+    #[test]
+    fn invalid_cluster_config_should_be_rejected() {
+        let missing_id = "cluster_id = 1\n[[replica]]\naddress = \"127.0.0.1:1000\"";
+        let gap = "cluster_id = 1\n[[replica]]\nid = 1\naddress = \"127.0.0.1:1000\"";
+        let unknown_key = "cluster_id = 1\ntimeout = 5";
+        let no_voters =
+            "cluster_id = 1\n[[replica]]\nid = 0\naddress = \"127.0.0.1:1000\"\nrole = \"standby\"";
+        for text in [missing_id, gap, unknown_key, no_voters] {
+            let error = ClusterConfig::parse(text).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
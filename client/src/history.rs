@@ -0,0 +1,237 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use crate::Codec;
+
+/// Step of a client, as recorded in the history file, one per line:
+/// ```text
+/// invoke <client id> <request number> <time> <op as hex>
+/// complete <client id> <request number> <time> <result as hex>
+/// ```
+/// Times are in microseconds, those of the clients have to come from the same clock.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<Op, R> {
+    Invoke {
+        client_id: usize,
+        request_number: usize,
+        op: Op,
+        time: u64,
+    },
+    Complete {
+        client_id: usize,
+        request_number: usize,
+        result: R,
+        time: u64,
+    },
+}
+
+impl<Op: Codec, R: Codec> Event<Op, R> {
+    pub fn to_line(&self) -> String {
+        match self {
+            Event::Invoke {
+                client_id,
+                request_number,
+                op,
+                time,
+            } => format!(
+                "invoke {} {} {} {}",
+                client_id,
+                request_number,
+                time,
+                to_hex(&op.to_bytes())
+            ),
+            Event::Complete {
+                client_id,
+                request_number,
+                result,
+                time,
+            } => format!(
+                "complete {} {} {} {}",
+                client_id,
+                request_number,
+                time,
+                to_hex(&result.to_bytes())
+            ),
+        }
+    }
+
+    pub fn from_line(line: &str, line_number: usize) -> io::Result<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [kind, client_id, request_number, time, payload] = fields[..] else {
+            return Err(invalid(line_number, "expected 5 fields"));
+        };
+        let number = |field: &str| {
+            field
+                .parse::<u64>()
+                .map_err(|_| invalid(line_number, "expected a number"))
+        };
+        let client_id = number(client_id)? as usize;
+        let request_number = number(request_number)? as usize;
+        let time = number(time)?;
+        let payload = from_hex(payload).ok_or_else(|| invalid(line_number, "invalid hex"))?;
+        match kind {
+            "invoke" => Ok(Event::Invoke {
+                client_id,
+                request_number,
+                op: Op::from_bytes(&payload)
+                    .ok_or_else(|| invalid(line_number, "malformed op"))?
+                    .0,
+                time,
+            }),
+            "complete" => Ok(Event::Complete {
+                client_id,
+                request_number,
+                result: R::from_bytes(&payload)
+                    .ok_or_else(|| invalid(line_number, "malformed result"))?
+                    .0,
+                time,
+            }),
+            _ => Err(invalid(line_number, "unknown event")),
+        }
+    }
+}
+
+/// Invoked op, `result` and `completed_at` are `None` until the reply arrives.
+/// An op, that never completes might have taken effect or not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation<Op, R> {
+    pub client_id: usize,
+    pub request_number: usize,
+    pub op: Op,
+    pub invoked_at: u64,
+    pub result: Option<R>,
+    pub completed_at: Option<u64>,
+}
+
+/// Ops invoked by the clients, in the order of their invocations.
+/// Only the linearizable requests belong to it, the stale reads don't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct History<Op, R> {
+    pub operations: Vec<Operation<Op, R>>,
+}
+
+impl<Op, R> Default for History<Op, R> {
+    fn default() -> Self {
+        Self {
+            operations: Vec::new(),
+        }
+    }
+}
+
+impl<Op: Codec, R: Codec> History<Op, R> {
+    /// Merges the histories recorded by the clients, each file holds the events of some of them.
+    pub fn load(paths: &[impl AsRef<Path>]) -> io::Result<Self> {
+        let mut events = Vec::new();
+        for path in paths {
+            let text = fs::read_to_string(path)?;
+            for (index, line) in text.lines().enumerate() {
+                if !line.trim().is_empty() {
+                    events.push(Event::from_line(line, index + 1)?);
+                }
+            }
+        }
+        let time = |event: &Event<Op, R>| match event {
+            Event::Invoke { time, .. } | Event::Complete { time, .. } => *time,
+        };
+        events.sort_by_key(time);
+        let mut history = Self::default();
+        for event in events {
+            history.record(event);
+        }
+        Ok(history)
+    }
+}
+
+impl<Op, R> History<Op, R> {
+    /// Completions of the unknown ops are ignored.
+    pub fn record(&mut self, event: Event<Op, R>) {
+        match event {
+            Event::Invoke {
+                client_id,
+                request_number,
+                op,
+                time,
+            } => self.operations.push(Operation {
+                client_id,
+                request_number,
+                op,
+                invoked_at: time,
+                result: None,
+                completed_at: None,
+            }),
+            Event::Complete {
+                client_id,
+                request_number,
+                result,
+                time,
+            } => {
+                let operation = self.operations.iter_mut().rev().find(|operation| {
+                    operation.client_id == client_id
+                        && operation.request_number == request_number
+                        && operation.result.is_none()
+                });
+                if let Some(operation) = operation {
+                    operation.result = Some(result);
+                    operation.completed_at = Some(time);
+                }
+            }
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid(line_number: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid history at line {}: {}", line_number, reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Op;
+
+    #[test]
+    fn events_should_survive_the_round_trip() {
+        let invoke: Event<Op, u64> = Event::Invoke {
+            client_id: 7,
+            request_number: 3,
+            op: Op::Add(42),
+            time: 1_000,
+        };
+        let complete: Event<Op, u64> = Event::Complete {
+            client_id: 7,
+            request_number: 3,
+            result: 69,
+            time: 2_000,
+        };
+        for event in [invoke.clone(), complete.clone()] {
+            assert_eq!(Event::from_line(&event.to_line(), 1).unwrap(), event);
+        }
+        assert!(Event::<Op, u64>::from_line("invoke 7 3 1000 zz", 1).is_err());
+
+        let mut history = History::default();
+        history.record(invoke);
+        history.record(complete);
+        let operation = &history.operations[0];
+        assert_eq!(operation.result, Some(69));
+        assert_eq!(operation.completed_at, Some(2_000));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::Codec;
+
+// Discriminator table (singular byte)
+// 0 => Put
+// 1 => Get
+// 2 => Delete
+// 3 => CompareAndSwap
+
+/// Operations of the replicated key-value store, keys and values are arbitrary byte strings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvOp {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Get {
+        key: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    // Sets the `new` value, only if the current one equals to `expected`,
+    // `None` stands for the missing key.
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+    },
+}
+
+// Discriminator table (singular byte)
+// 0 => Value
+// 1 => CompareAndSwap
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvResult {
+    // Value of the key before a `Put` or `Delete`, the current value for a `Get`.
+    // `None` if the key was not found.
+    Value(Option<Vec<u8>>),
+    // Whether the swap took place, along with the value of the key before it.
+    CompareAndSwap {
+        swapped: bool,
+        current: Option<Vec<u8>>,
+    },
+}
+
+impl Codec for KvOp {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            KvOp::Put { key, value } => {
+                bytes.push(0);
+                bytes.extend(key.to_bytes());
+                bytes.extend(value.to_bytes());
+            }
+            KvOp::Get { key } => {
+                bytes.push(1);
+                bytes.extend(key.to_bytes());
+            }
+            KvOp::Delete { key } => {
+                bytes.push(2);
+                bytes.extend(key.to_bytes());
+            }
+            KvOp::CompareAndSwap { key, expected, new } => {
+                bytes.push(3);
+                bytes.extend(key.to_bytes());
+                bytes.extend(expected.to_bytes());
+                bytes.extend(new.to_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let discriminator = *bytes.first()?;
+        let (key, key_size) = Vec::from_bytes(&bytes[1..])?;
+        let mut position = 1 + key_size;
+        let op = match discriminator {
+            0 => {
+                let (value, size) = Vec::from_bytes(&bytes[position..])?;
+                position += size;
+                KvOp::Put { key, value }
+            }
+            1 => KvOp::Get { key },
+            2 => KvOp::Delete { key },
+            3 => {
+                let (expected, size) = Option::from_bytes(&bytes[position..])?;
+                position += size;
+                let (new, size) = Vec::from_bytes(&bytes[position..])?;
+                position += size;
+                KvOp::CompareAndSwap { key, expected, new }
+            }
+            _ => return None,
+        };
+        Some((op, position))
+    }
+}
+
+impl Codec for KvResult {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            KvResult::Value(value) => {
+                bytes.push(0);
+                bytes.extend(value.to_bytes());
+            }
+            KvResult::CompareAndSwap { swapped, current } => {
+                bytes.push(1);
+                bytes.extend(swapped.to_bytes());
+                bytes.extend(current.to_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        match bytes.first()? {
+            0 => {
+                let (value, size) = Option::from_bytes(&bytes[1..])?;
+                Some((KvResult::Value(value), 1 + size))
+            }
+            1 => {
+                let (swapped, swapped_size) = bool::from_bytes(&bytes[1..])?;
+                let (current, size) = Option::from_bytes(&bytes[1 + swapped_size..])?;
+                Some((
+                    KvResult::CompareAndSwap { swapped, current },
+                    1 + swapped_size + size,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializing_and_deserializing_kv_ops_should_maintain_correct_schema() {
+        let ops = [
+            KvOp::Put {
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+            },
+            KvOp::Get { key: Vec::new() },
+            KvOp::Delete {
+                key: b"key".to_vec(),
+            },
+            KvOp::CompareAndSwap {
+                key: b"key".to_vec(),
+                expected: None,
+                new: b"new".to_vec(),
+            },
+            KvOp::CompareAndSwap {
+                key: b"key".to_vec(),
+                expected: Some(b"old".to_vec()),
+                new: Vec::new(),
+            },
+        ];
+        let mut bytes: Vec<u8> = ops.iter().flat_map(|op| op.to_bytes()).collect();
+        // Followed by the next log entry.
+        bytes.push(42);
+
+        let mut position = 0;
+        for op in ops {
+            let (parsed, size) = KvOp::from_bytes(&bytes[position..]).unwrap();
+            assert_eq!(parsed, op);
+            position += size;
+        }
+        assert_eq!(position, bytes.len() - 1);
+    }
+
+    #[test]
+    fn serializing_and_deserializing_kv_results_should_maintain_correct_schema() {
+        let results = [
+            KvResult::Value(None),
+            KvResult::Value(Some(b"value".to_vec())),
+            KvResult::CompareAndSwap {
+                swapped: true,
+                current: Some(b"old".to_vec()),
+            },
+        ];
+        for result in results {
+            let bytes = result.to_bytes();
+            assert_eq!(KvResult::from_bytes(&bytes), Some((result, bytes.len())));
+        }
+    }
+
+    #[test]
+    fn malformed_kv_op_should_fail_to_decode() {
+        let bytes = KvOp::Put {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+        }
+        .to_bytes();
+        assert!(KvOp::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        let mut bytes = bytes;
+        bytes[0] = 7;
+        assert!(KvOp::from_bytes(&bytes).is_none());
+    }
+}
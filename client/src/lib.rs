@@ -1,19 +1,26 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+pub use client::Client;
+pub use codec::Codec;
+
+mod client;
+mod codec;
+pub mod config;
+pub mod history;
+pub mod kv;
+pub mod reply;
+pub mod request;
 
 // Discriminator table (singular byte)
 // 0 => Nop
 // 1 => Add
 
-pub const MAX_OP_SIZE: usize = 1 + 8;
-
 #[derive(Debug, Clone, PartialEq)]
 pub enum Op {
     Nop,
     Add(u64),
 }
 
-impl Op {
-    pub fn to_bytes(&self) -> Vec<u8> {
+impl Codec for Op {
+    fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         match self {
             Op::Nop => {
@@ -27,21 +34,14 @@ impl Op {
         bytes
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (Self, usize) {
-        let discriminator = bytes[0];
-        match discriminator {
-            0 => (Op::Nop, 1),
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        match bytes.first()? {
+            0 => Some((Op::Nop, 1)),
             1 => {
-                let value = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
-                (Op::Add(value), 9)
+                let (value, size) = u64::from_bytes(&bytes[1..])?;
+                Some((Op::Add(value), 1 + size))
             }
-            _ => unreachable!(),
+            _ => None,
         }
     }
 }
-
-pub const ADDRESSES: [SocketAddr; 3] = [
-    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1337),
-    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2137),
-    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6969),
-];
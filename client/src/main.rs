@@ -1,37 +1,171 @@
-use ::client::{Op, ADDRESSES};
-use client::Client;
-use request::Request;
-use std::io::Write;
+use ::client::{
+    config::ClusterConfig,
+    history::Event,
+    kv::{KvOp, KvResult},
+    reply::StaleRequest,
+    request::ReadBound,
+    Client, Codec, Op,
+};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{net::TcpStream, thread, time::Duration};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+};
+use std::{thread, time::Duration};
 
-pub(crate) mod client;
-pub(crate) mod request;
-
-const CLIENT_ID: usize = 69;
+const DEFAULT_CONFIG_PATH: &str = "cluster.conf";
 
 fn main() {
-    let mut client = Client::new(CLIENT_ID);
-
-    // TODO: Connect to the whole cluster.
-    // Assume that the first replica in the list is primary.
-    let primary_addr = ADDRESSES[0];
-    let mut stream = TcpStream::connect(primary_addr).unwrap();
-    let mut request_num = 0;
-    //loop {
-    let client = &mut client;
-    let stream = &mut stream;
-    let value = generate_random_number();
-    let request = Request::new(client.id, request_num, Op::Add(value));
-    client.request_number = request_num;
-    request_num += 1;
-
-    let bytes = request.to_bytes();
-    let _ = stream.write(&bytes).unwrap();
-    // TODO: Read the response
-    thread::sleep(Duration::from_millis(1000));
-    //}
-    loop {}
+    // The same file, that the replicas are started with.
+    let config_path = std::env::args()
+        .skip_while(|arg| arg != "--config")
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+    let config = ClusterConfig::load(&config_path).expect("Failed to load the cluster config");
+    // Clients running side by side need their own ids. A restarted client starts over
+    // with its request numbers, so it picks a new id, unless it's given one.
+    let client_id = std::env::args()
+        .skip_while(|arg| arg != "--client-id")
+        .nth(1)
+        .map(|id| id.parse().expect("Invalid client id"))
+        .unwrap_or_else(generate_client_id);
+    let mut client = Client::new(client_id, config.addresses());
+    client.replicas = config.voters();
+    if let Some(request_timeout) = config.request_timeout {
+        client.request_timeout = request_timeout;
+    }
+    // `--reconfigure <epoch> <replica ids...>` replaces the members of the epoch and exits.
+    if let Some(epoch_number) = std::env::args()
+        .skip_while(|arg| arg != "--reconfigure")
+        .nth(1)
+    {
+        let epoch_number = epoch_number.parse().expect("Invalid epoch number");
+        let replicas = std::env::args()
+            .skip_while(|arg| arg != "--reconfigure")
+            .skip(2)
+            .filter_map(|arg| arg.parse().ok())
+            .collect();
+        match client.reconfigure(epoch_number, replicas) {
+            Ok(epoch_number) => println!("Cluster entered epoch: {}", epoch_number),
+            Err(stale) => eprintln!("{}", stale),
+        }
+        return;
+    }
+    // With `--kv`, the replicas run the key-value store instead of the counter.
+    let kv = std::env::args().any(|arg| arg == "--kv");
+    // With `--history`, the invocations and completions of the requests are appended to the file,
+    // for the server to check with `--check-history`.
+    let mut history = std::env::args()
+        .skip_while(|arg| arg != "--history")
+        .nth(1)
+        .map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("Failed to open the history file")
+        });
+    loop {
+        let value = generate_random_number();
+        let request_number = client.request_number;
+        let result = if kv {
+            // Half of the reads go to any replica, that has seen our latest write.
+            // The stale reads aren't linearizable, those are left out of the history.
+            let result: Result<KvResult, _> = match generate_kv_op(value) {
+                op @ KvOp::Get { .. } if value % 32 < 16 => {
+                    send_recorded(&mut client, &mut history, op, Client::read)
+                }
+                op @ KvOp::Get { .. } => {
+                    let bound = ReadBound::MinCommitNumber(client.commit_number);
+                    client.read_stale(op, bound)
+                }
+                op => send_recorded(&mut client, &mut history, op, Client::send),
+            };
+            result.map(|result| format!("{:?}", result))
+        } else {
+            send_recorded::<_, u64>(&mut client, &mut history, Op::Add(value), Client::send)
+                .map(|result| result.to_string())
+        };
+        match result {
+            Ok(result) => println!(
+                "Received reply for request: {}, result: {}, in view: {}",
+                request_number, result, client.view_number
+            ),
+            // The request numbers resume after the latest request of the session.
+            Err(stale) => eprintln!("{}", stale),
+        }
+        thread::sleep(Duration::from_millis(1000));
+    }
+}
+
+/// Sends the op with `send`, recording its invocation and completion in the `history`.
+/// A stale request never completes, it's left pending.
+fn send_recorded<Op: Codec + Clone, R: Codec + Clone>(
+    client: &mut Client,
+    history: &mut Option<File>,
+    op: Op,
+    send: impl FnOnce(&mut Client, Op) -> Result<R, StaleRequest>,
+) -> Result<R, StaleRequest> {
+    let (client_id, request_number) = (client.id, client.request_number);
+    record(
+        history,
+        Event::<Op, R>::Invoke {
+            client_id,
+            request_number,
+            op: op.clone(),
+            time: now_micros(),
+        },
+    );
+    let result = send(client, op)?;
+    record(
+        history,
+        Event::<Op, R>::Complete {
+            client_id,
+            request_number,
+            result: result.clone(),
+            time: now_micros(),
+        },
+    );
+    Ok(result)
+}
+
+fn record<Op: Codec, R: Codec>(history: &mut Option<File>, event: Event<Op, R>) {
+    if let Some(file) = history {
+        writeln!(file, "{}", event.to_line()).expect("Failed to write the history");
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_micros() as u64
+}
+
+/// Picks the op by the lowest bits of `value`, so that all of them hit a handful of keys.
+fn generate_kv_op(value: u64) -> KvOp {
+    let key = format!("key-{}", (value >> 2) % 4).into_bytes();
+    match value % 4 {
+        0 => KvOp::Put {
+            key,
+            value: value.to_string().into_bytes(),
+        },
+        1 => KvOp::Get { key },
+        2 => KvOp::Delete { key },
+        _ => KvOp::CompareAndSwap {
+            key,
+            expected: None,
+            new: value.to_string().into_bytes(),
+        },
+    }
+}
+
+fn generate_client_id() -> usize {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos();
+    (now as usize) ^ ((std::process::id() as usize) << 32)
 }
 
 fn generate_random_number() -> u64 {
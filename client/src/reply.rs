@@ -0,0 +1,174 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+};
+
+use crate::Codec;
+
+#[derive(Debug)]
+pub struct Reply<R> {
+    pub view_number: usize,
+    pub request_number: usize,
+    // Commit number of the replica at the time of the reply, it covers the replied op.
+    pub commit_number: usize,
+    pub result: R,
+}
+
+impl<R: Codec> Reply<R> {
+    /// Fails on anything but a reply, the bytes might come from a stream that went out of sync,
+    /// the result has to fill the rest of the frame.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < 25 || bytes[0] != 12 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Expected a reply message",
+            ));
+        }
+        let view_number = usize::from_le_bytes(bytes[1..9].try_into().unwrap());
+        let request_number = usize::from_le_bytes(bytes[9..17].try_into().unwrap());
+        let commit_number = usize::from_le_bytes(bytes[17..25].try_into().unwrap());
+        let result = match R::from_bytes(&bytes[25..]) {
+            Some((result, size)) if size == bytes.len() - 25 => result,
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "Malformed result")),
+        };
+        Ok(Self {
+            view_number,
+            request_number,
+            commit_number,
+            result,
+        })
+    }
+}
+
+/// Sent instead of the reply, once the session of the client has moved past the request.
+/// The client restarted its request numbers, or another one shares its id.
+#[derive(Debug, PartialEq)]
+pub struct StaleRequest {
+    pub request_number: usize,
+    // Latest request of the session, the client resumes after it.
+    pub latest_request_number: usize,
+}
+
+impl fmt::Display for StaleRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Request: {} is stale, the session of the client is at request: {}",
+            self.request_number, self.latest_request_number
+        )
+    }
+}
+
+impl std::error::Error for StaleRequest {}
+
+/// Answer of a replica to a request.
+#[derive(Debug)]
+pub enum Response<R> {
+    Reply(Reply<R>),
+    Stale(StaleRequest),
+}
+
+impl<R: Codec> Response<R> {
+    pub fn request_number(&self) -> usize {
+        match self {
+            Response::Reply(reply) => reply.request_number,
+            Response::Stale(stale) => stale.request_number,
+        }
+    }
+
+    /// Fails on anything but a reply or a stale request.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() == 17 && bytes[0] == 22 {
+            return Ok(Response::Stale(StaleRequest {
+                request_number: usize::from_le_bytes(bytes[1..9].try_into().unwrap()),
+                latest_request_number: usize::from_le_bytes(bytes[9..17].try_into().unwrap()),
+            }));
+        }
+        Reply::from_bytes(bytes).map(Response::Reply)
+    }
+
+    /// Takes the first response out of the bytes received so far, `None` until the whole frame has arrived.
+    pub fn take_from(buffer: &mut Vec<u8>) -> io::Result<Option<Self>> {
+        let Some(len) = buffer.get(..4) else {
+            return Ok(None);
+        };
+        let end = 4 + u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if buffer.len() < end {
+            return Ok(None);
+        }
+        let response = Self::from_bytes(&buffer[4..end]);
+        buffer.drain(..end);
+        response.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_reply_should_be_rejected() {
+        let mut bytes = vec![12];
+        for field in [1usize, 2, 3] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend(69u64.to_le_bytes());
+        let reply = Reply::<u64>::from_bytes(&bytes).unwrap();
+        assert_eq!((reply.view_number, reply.result), (1, 69));
+
+        // Payload bytes taken for the discriminator, or a truncated reply.
+        assert!(Reply::<u64>::from_bytes(&bytes[1..]).is_err());
+        assert!(Reply::<u64>::from_bytes(&bytes[..20]).is_err());
+
+        // A short or garbled result body.
+        assert!(Reply::<u64>::from_bytes(&bytes[..30]).is_err());
+        assert!(Reply::<u64>::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        let mut bytes = bytes[..25].to_vec();
+        bytes.extend([0, 0, 0, 0, 1]);
+        assert!(Reply::<Option<u64>>::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn partial_frame_should_wait_for_the_rest() {
+        let mut frame = Vec::new();
+        frame.extend((25u32 + 8).to_le_bytes());
+        frame.push(12);
+        for field in [1usize, 2, 3] {
+            frame.extend(field.to_le_bytes());
+        }
+        frame.extend(69u64.to_le_bytes());
+        let mut stream = [frame.clone(), frame].concat();
+
+        // The read timed out in the middle of the first frame.
+        let mut buffer = stream.drain(..10).collect();
+        assert!(Response::<u64>::take_from(&mut buffer).unwrap().is_none());
+        buffer.extend(stream);
+        for _ in 0..2 {
+            let Some(Response::Reply(reply)) = Response::<u64>::take_from(&mut buffer).unwrap()
+            else {
+                panic!("Expected a reply");
+            };
+            assert_eq!((reply.request_number, reply.result), (2, 69));
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn stale_request_should_be_told_apart_from_the_reply() {
+        let mut frame = Vec::new();
+        frame.extend(17u32.to_le_bytes());
+        frame.push(22);
+        for field in [0usize, 3] {
+            frame.extend(field.to_le_bytes());
+        }
+        let response = Response::<u64>::take_from(&mut frame).unwrap().unwrap();
+        assert_eq!(response.request_number(), 0);
+        assert!(matches!(
+            response,
+            Response::Stale(StaleRequest {
+                latest_request_number: 3,
+                ..
+            })
+        ));
+    }
+}
@@ -1,31 +1,111 @@
-use client::Op;
+use std::time::Duration;
 
-pub struct Request {
+use crate::Codec;
+
+// Sent in place of the staleness bound, when the read bounds the commit number only.
+const UNBOUNDED_STALENESS_MILLIS: u64 = u64::MAX;
+
+/// Freshness, that the replica answering a stale read has to guarantee.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadBound {
+    // The replica has heard from the primary at most this long ago.
+    MaxStaleness(Duration),
+    // The replica has committed at least this op, `Client::commit_number` reads our own writes.
+    MinCommitNumber(usize),
+}
+
+enum Kind {
+    Write,
+    // Answered by the primary without going through the log.
+    Read,
+    // Answered by any replica, that's fresh enough.
+    StaleRead(ReadBound),
+}
+
+pub struct Request<Op> {
     pub client_id: usize,
     pub request_number: usize,
     op: Op,
+    kind: Kind,
 }
 
-impl Request {
+impl<Op: Codec> Request<Op> {
     pub fn new(client_id: usize, request_number: usize, op: Op) -> Self {
         Self {
             client_id,
             request_number,
             op,
+            kind: Kind::Write,
+        }
+    }
+
+    pub fn read(client_id: usize, request_number: usize, op: Op) -> Self {
+        Self {
+            client_id,
+            request_number,
+            op,
+            kind: Kind::Read,
+        }
+    }
+
+    pub fn stale_read(client_id: usize, request_number: usize, op: Op, bound: ReadBound) -> Self {
+        Self {
+            client_id,
+            request_number,
+            op,
+            kind: Kind::StaleRead(bound),
         }
     }
 
-    pub fn to_bytes(self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
         let op_bytes = self.op.to_bytes();
-        let op_len = op_bytes.len();
-        let length = 1 + 8 + 8 + op_len;
+        let (discriminator, bound) = match self.kind {
+            Kind::Write => (1u8, None),
+            Kind::Read => (17u8, None),
+            Kind::StaleRead(bound) => (18u8, Some(bound)),
+        };
+        let bound_len = if bound.is_some() { 8 + 8 } else { 0 };
+        let length = 1 + 8 + 8 + bound_len + op_bytes.len();
         let mut bytes = Vec::with_capacity(length + 4);
-        let discriminator = 1u8;
         bytes.extend_from_slice(&(length as u32).to_le_bytes());
         bytes.extend_from_slice(&discriminator.to_le_bytes());
         bytes.extend_from_slice(&self.client_id.to_le_bytes());
         bytes.extend_from_slice(&self.request_number.to_le_bytes());
+        if let Some(bound) = bound {
+            let (min_commit_number, max_staleness_millis) = match bound {
+                ReadBound::MaxStaleness(staleness) => (0, staleness.as_millis() as u64),
+                ReadBound::MinCommitNumber(commit_number) => {
+                    (commit_number, UNBOUNDED_STALENESS_MILLIS)
+                }
+            };
+            bytes.extend_from_slice(&min_commit_number.to_le_bytes());
+            bytes.extend_from_slice(&max_staleness_millis.to_le_bytes());
+        }
         bytes.extend_from_slice(&op_bytes);
         bytes
     }
 }
+
+/// Replaces the members of `epoch_number`, the replicas are identified by their index in the addresses.
+pub struct Reconfiguration {
+    pub client_id: usize,
+    pub request_number: usize,
+    pub epoch_number: usize,
+    pub replicas: Vec<usize>,
+}
+
+impl Reconfiguration {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let length = 1 + 8 + 8 + 8 + self.replicas.len() * 8;
+        let mut bytes = Vec::with_capacity(length + 4);
+        bytes.extend_from_slice(&(length as u32).to_le_bytes());
+        bytes.extend_from_slice(&19u8.to_le_bytes());
+        bytes.extend_from_slice(&self.client_id.to_le_bytes());
+        bytes.extend_from_slice(&self.request_number.to_le_bytes());
+        bytes.extend_from_slice(&self.epoch_number.to_le_bytes());
+        for replica_id in &self.replicas {
+            bytes.extend_from_slice(&replica_id.to_le_bytes());
+        }
+        bytes
+    }
+}
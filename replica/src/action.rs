@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use crate::message::{Envelope, Message};
+
+/// Effect of handling an event, carried out by the driver of the replica.
+/// The replica itself never touches the network, so its handlers run the same
+/// under the monoio server, the simulator and the tests.
+#[derive(Debug, PartialEq)]
+pub enum Action<Op: Clone, R: Clone> {
+    // The envelope carries our epoch, the message is sent inside of it.
+    SendToReplica {
+        replica_id: usize,
+        envelope: Envelope,
+        message: Message<Op, R>,
+    },
+    // Encoded reply, the reconfiguration is answered with the epoch number instead of a result.
+    SendToClient {
+        client_id: usize,
+        bytes: Vec<u8>,
+    },
+    // The op has been applied, `checksum` identifies its log entry.
+    Commit {
+        op_number: usize,
+        checksum: u64,
+    },
+    // The batch is prepared after the `delay`, unless it's flushed earlier,
+    // the driver calls `Replica::on_batch_window` then.
+    ScheduleFlush {
+        batch_number: usize,
+        delay: Duration,
+    },
+}
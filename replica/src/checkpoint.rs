@@ -0,0 +1,187 @@
+use std::sync::atomic::Ordering;
+
+use crate::{client_table::ClientTable, log, replica::Replica, stm::StateMachine};
+
+// Checksum of the rest of the checkpoint, op number, checksum of the entry, epoch number
+// and the lengths of the replicas, the state and the client table.
+const HEADER_SIZE: usize = 8 + 8 + 8 + 8 + 4 + 4 + 4;
+
+/// Snapshot of the state machine and the client table at a committed op,
+/// the log prefix up to that op is dropped once the checkpoint is taken.
+/// The default checkpoint stands for the empty state before the first op.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checkpoint {
+    pub op_number: usize,
+    // Checksum of the entry at `op_number`, the parent of the first entry following the checkpoint.
+    pub checksum: u64,
+    // Membership at `op_number`, the reconfiguration entries it covers are gone from the log.
+    // Empty in the default checkpoint, which keeps the initial configuration.
+    pub epoch_number: usize,
+    pub replicas: Vec<usize>,
+    pub state: Vec<u8>,
+    pub clients_table: Vec<u8>,
+}
+
+impl Checkpoint {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            HEADER_SIZE + self.replicas.len() * 8 + self.state.len() + self.clients_table.len(),
+        );
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&self.op_number.to_le_bytes());
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.epoch_number.to_le_bytes());
+        bytes.extend_from_slice(&(self.replicas.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.state.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.clients_table.len() as u32).to_le_bytes());
+        for replica_id in &self.replicas {
+            bytes.extend_from_slice(&replica_id.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.state);
+        bytes.extend_from_slice(&self.clients_table);
+        let checksum = log::checksum(&bytes[8..]);
+        bytes[..8].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// `None` if the checkpoint is cut short or fails its checksum.
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let header = bytes.get(..HEADER_SIZE)?;
+        let expected_checksum = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let op_number = usize::from_le_bytes(header[8..16].try_into().unwrap());
+        let checksum = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let epoch_number = usize::from_le_bytes(header[24..32].try_into().unwrap());
+        let replicas_len = u32::from_le_bytes(header[32..36].try_into().unwrap()) as usize;
+        let state_len = u32::from_le_bytes(header[36..40].try_into().unwrap()) as usize;
+        let clients_table_len = u32::from_le_bytes(header[40..44].try_into().unwrap()) as usize;
+        let replicas_end = HEADER_SIZE + replicas_len * 8;
+        let state_end = replicas_end + state_len;
+        let end = state_end + clients_table_len;
+        if log::checksum(bytes.get(8..end)?) != expected_checksum {
+            return None;
+        }
+        let replicas = bytes[HEADER_SIZE..replicas_end]
+            .chunks_exact(8)
+            .map(|chunk| usize::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let checkpoint = Self {
+            op_number,
+            checksum,
+            epoch_number,
+            replicas,
+            state: bytes[replicas_end..state_end].to_vec(),
+            clients_table: bytes[state_end..end].to_vec(),
+        };
+        Some((checkpoint, end))
+    }
+}
+
+impl<S: StateMachine> Replica<S> {
+    pub fn checkpoint_number(&self) -> usize {
+        self.checkpoint.borrow().op_number
+    }
+
+    /// Checkpoints the committed state and drops the log prefix it covers.
+    pub fn take_checkpoint(&self) {
+        let op_number = self.commit_number();
+        let checkpoint_number = self.checkpoint_number();
+        if op_number == checkpoint_number {
+            return;
+        }
+        let checkpoint = Checkpoint {
+            op_number,
+            checksum: self.entry(op_number).checksum,
+            epoch_number: self.epoch_number(),
+            replicas: self.config.borrow().replicas.clone(),
+            state: self.stm.borrow().snapshot(),
+            clients_table: self.clients_table.borrow().to_bytes(),
+        };
+        // The checkpoint has to be durable, before the log prefix is gone.
+        if let Some(journal) = &self.journal {
+            journal
+                .borrow_mut()
+                .write_checkpoint(&checkpoint)
+                .expect("Failed to write the checkpoint");
+        }
+        self.log.borrow_mut().drain(..op_number - checkpoint_number);
+        println!(
+            "Replica: {} took checkpoint at op: {}",
+            self.id, checkpoint.op_number
+        );
+        self.checkpoint.replace(checkpoint);
+    }
+
+    /// Replaces our state with the checkpoint of a peer, that's ahead of us. The log is emptied,
+    /// our uncommitted ops are either covered by the checkpoint or didn't survive.
+    pub fn install_checkpoint(&self, checkpoint: Checkpoint) {
+        if checkpoint.epoch_number > self.epoch_number() {
+            self.adopt_configuration(checkpoint.epoch_number, checkpoint.replicas.clone());
+        }
+        self.stm.borrow_mut().restore(&checkpoint.state);
+        self.clients_table.replace(
+            ClientTable::from_bytes(&checkpoint.clients_table)
+                .expect("The checkpoint is checksummed"),
+        );
+        if let Some(journal) = &self.journal {
+            let mut journal = journal.borrow_mut();
+            journal
+                .truncate(self.commit_number())
+                .and_then(|_| journal.write_checkpoint(&checkpoint))
+                .expect("Failed to install the checkpoint");
+        }
+        self.log.borrow_mut().clear();
+        self.faulty_ops.borrow_mut().clear();
+        self.commit_number
+            .store(checkpoint.op_number, Ordering::Release);
+        self.op_number
+            .store(checkpoint.op_number, Ordering::Release);
+        self.checkpoint.replace(checkpoint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializing_and_deserializing_checkpoint_should_maintain_correct_schema() {
+        let checkpoint = Checkpoint {
+            op_number: 3,
+            checksum: 42,
+            epoch_number: 1,
+            replicas: vec![0, 2, 3],
+            state: 69u64.to_le_bytes().to_vec(),
+            clients_table: vec![1; 24],
+        };
+        let mut bytes = checkpoint.to_bytes();
+        // Followed by the log entries on the wire.
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        let (parsed, size) = Checkpoint::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed, checkpoint);
+        assert_eq!(size, bytes.len() - 3);
+    }
+
+    #[test]
+    fn corrupt_or_truncated_checkpoint_should_fail_to_decode() {
+        let checkpoint = Checkpoint {
+            op_number: 3,
+            replicas: vec![0, 2, 3],
+            state: 69u64.to_le_bytes().to_vec(),
+            ..Default::default()
+        };
+        let bytes = checkpoint.to_bytes();
+        for length in 0..bytes.len() {
+            assert_eq!(Checkpoint::from_bytes(&bytes[..length]), None);
+        }
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(Checkpoint::from_bytes(&corrupt), None);
+
+        // A length pointing way past the end.
+        let mut corrupt = bytes;
+        corrupt[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Checkpoint::from_bytes(&corrupt), None);
+    }
+}
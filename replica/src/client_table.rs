@@ -1,2 +1,168 @@
-#[derive(Default)]
-pub struct ClientTable {}
+use std::collections::BTreeMap;
+
+use crate::{log::LogEntry, stm::Codec};
+
+#[derive(Debug, PartialEq)]
+pub enum RequestStatus<R> {
+    // Request that has not been seen yet.
+    New,
+    // Request older than the latest one, along with the latest request number.
+    // The client is told, it never gets a reply to it.
+    Stale(usize),
+    // Latest request, that's not committed yet.
+    InProgress,
+    // Latest request, that's already committed, along with its result.
+    Committed(R),
+}
+
+#[derive(Debug)]
+struct ClientSession<R> {
+    // Latest request number, that has been prepared.
+    request_number: usize,
+    // Latest request number, that has been committed, along with its result.
+    reply: Option<(usize, R)>,
+}
+
+pub struct ClientTable<R> {
+    // Ordered, so that the checkpoints of the replicas are the same byte for byte.
+    sessions: BTreeMap<usize, ClientSession<R>>,
+}
+
+impl<R> Default for ClientTable<R> {
+    fn default() -> Self {
+        Self {
+            sessions: BTreeMap::new(),
+        }
+    }
+}
+
+impl<R: Codec + Clone> ClientTable<R> {
+    pub fn check(&self, client_id: usize, request_number: usize) -> RequestStatus<R> {
+        let Some(session) = self.sessions.get(&client_id) else {
+            return RequestStatus::New;
+        };
+        if request_number > session.request_number {
+            return RequestStatus::New;
+        }
+        if request_number < session.request_number {
+            return RequestStatus::Stale(session.request_number);
+        }
+        match &session.reply {
+            Some((committed, result)) if *committed == request_number => {
+                RequestStatus::Committed(result.clone())
+            }
+            _ => RequestStatus::InProgress,
+        }
+    }
+
+    pub fn prepare(&mut self, client_id: usize, request_number: usize) {
+        let session = self
+            .sessions
+            .entry(client_id)
+            .or_insert_with(|| ClientSession {
+                request_number,
+                reply: None,
+            });
+        session.request_number = session.request_number.max(request_number);
+    }
+
+    pub fn commit(&mut self, client_id: usize, request_number: usize, result: R) {
+        self.prepare(client_id, request_number);
+        let session = self.sessions.get_mut(&client_id).unwrap();
+        session.reply = Some((request_number, result));
+    }
+
+    /// Drops the requests, that were prepared but not committed and prepares
+    /// the ones from `uncommitted` log suffix instead.
+    pub fn rebuild<'a, Op: 'a>(&mut self, uncommitted: impl Iterator<Item = &'a LogEntry<Op>>) {
+        self.sessions.retain(|_, session| match session.reply {
+            Some((committed, _)) => {
+                session.request_number = committed;
+                true
+            }
+            None => false,
+        });
+        for entry in uncommitted {
+            self.prepare(entry.client_id, entry.request_number);
+        }
+    }
+
+    /// Serializes the committed requests only, the checkpoint doesn't cover the rest.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (client_id, session) in self.sessions.iter() {
+            if let Some((request_number, result)) = &session.reply {
+                bytes.extend_from_slice(&client_id.to_le_bytes());
+                bytes.extend_from_slice(&request_number.to_le_bytes());
+                bytes.extend_from_slice(&result.to_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// `None` if a session is cut short or its result is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut table = Self::default();
+        let mut position = 0;
+        while position < bytes.len() {
+            let session = &bytes[position..];
+            let client_id = usize::from_le_bytes(session.get(0..8)?.try_into().unwrap());
+            let request_number = usize::from_le_bytes(session.get(8..16)?.try_into().unwrap());
+            let (result, size) = R::from_bytes(&session[16..])?;
+            table.commit(client_id, request_number, result);
+            position += 16 + size;
+        }
+        Some(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client::Op;
+
+    use super::*;
+
+    #[test]
+    fn retransmission_of_committed_request_should_return_cached_result() {
+        let mut table = ClientTable::<u64>::default();
+        assert_eq!(table.check(1, 0), RequestStatus::New);
+        table.prepare(1, 0);
+        assert_eq!(table.check(1, 0), RequestStatus::InProgress);
+        table.commit(1, 0, 69);
+        assert_eq!(table.check(1, 0), RequestStatus::Committed(69));
+        table.prepare(1, 1);
+        assert_eq!(table.check(1, 0), RequestStatus::Stale(1));
+        assert_eq!(table.check(1, 2), RequestStatus::New);
+    }
+
+    #[test]
+    fn rebuilding_should_drop_requests_missing_from_the_log() {
+        let mut table = ClientTable::<u64>::default();
+        table.commit(1, 0, 69);
+        table.prepare(1, 1);
+        table.prepare(2, 0);
+        table.prepare(3, 0);
+
+        let uncommitted = [LogEntry::new(3, 0, Op::Nop)];
+        table.rebuild(uncommitted.iter());
+
+        assert_eq!(table.check(1, 0), RequestStatus::Committed(69));
+        assert_eq!(table.check(1, 1), RequestStatus::New);
+        assert_eq!(table.check(2, 0), RequestStatus::New);
+        assert_eq!(table.check(3, 0), RequestStatus::InProgress);
+    }
+
+    #[test]
+    fn restored_table_should_keep_only_committed_requests() {
+        let mut table = ClientTable::<u64>::default();
+        table.commit(1, 0, 69);
+        table.prepare(1, 1);
+        table.prepare(2, 0);
+
+        let table = ClientTable::<u64>::from_bytes(&table.to_bytes()).unwrap();
+
+        assert_eq!(table.check(1, 0), RequestStatus::Committed(69));
+        assert_eq!(table.check(1, 1), RequestStatus::New);
+        assert_eq!(table.check(2, 0), RequestStatus::New);
+    }
+}
@@ -0,0 +1,44 @@
+use std::{cell::Cell, rc::Rc, time::Instant};
+
+/// Source of time for the replica, timestamps are the microseconds elapsed since an arbitrary epoch.
+/// Those are only compared between the readings of the same clock.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// Monotonic clock of the machine.
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+}
+
+/// Simulated time shared by the replicas, it only moves on between the events.
+#[derive(Clone, Default)]
+pub struct SimClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl SimClock {
+    pub fn set(&self, now: u64) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
@@ -0,0 +1,68 @@
+use client::Op;
+
+use crate::stm::{Codec, StateMachine};
+
+/// Example service, a counter the clients add to.
+#[derive(Default)]
+pub struct Counter {
+    value: u64,
+}
+
+impl StateMachine for Counter {
+    type Op = Op;
+    type Result = u64;
+
+    /// Applies the op and returns the value of the counter afterwards.
+    fn apply(&mut self, op: Op) -> u64 {
+        match op {
+            Op::Add(val) => {
+                self.value += val;
+            }
+            Op::Nop => {
+                println!("Applying No operation to state machine");
+            }
+        }
+        self.value
+    }
+
+    /// `Op::Nop` reads the counter.
+    fn query(&self, op: &Op) -> Option<u64> {
+        match op {
+            Op::Nop => Some(self.value),
+            Op::Add(_) => None,
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.value.to_bytes()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        self.value = match snapshot {
+            [] => 0,
+            bytes => u64::from_bytes(bytes).expect("Corrupt snapshot").0,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restored_counter_should_continue_from_the_snapshot() {
+        let mut counter = Counter::default();
+        assert_eq!(counter.apply(Op::Add(42)), 42);
+        assert_eq!(counter.apply(Op::Nop), 42);
+
+        let mut restored = Counter::default();
+        restored.restore(&counter.snapshot());
+        assert_eq!(restored.apply(Op::Add(27)), 69);
+
+        assert_eq!(restored.query(&Op::Nop), Some(69));
+        assert_eq!(restored.query(&Op::Add(1)), None);
+
+        restored.restore(&[]);
+        assert_eq!(restored.apply(Op::Nop), 0);
+    }
+}
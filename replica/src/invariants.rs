@@ -0,0 +1,249 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use crate::{action::Action, replica::Replica, status::Status, stm::StateMachine};
+
+// Number of the latest events kept for the report, the seed reproduces the whole run.
+const TRACE_LENGTH: usize = 64;
+
+/// Broken safety property along with the events, that led to it.
+/// The trace ends with the event, whose handling broke the property.
+#[derive(Debug)]
+pub struct Violation {
+    pub invariant: String,
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invariant violated: {}", self.invariant)?;
+        writeln!(f, "Last {} events:", self.trace.len())?;
+        for event in &self.trace {
+            writeln!(f, "  {}", event)?;
+        }
+        Ok(())
+    }
+}
+
+/// Watches every replica of the cluster, after each event it handles, and checks the safety of VSR:
+/// - an op committed at an op number never changes on any replica,
+/// - a view has at most one primary in the normal status,
+/// - the commit number never exceeds the op number, nor goes backwards,
+/// - the states of the replicas are the same at the same commit number.
+///
+/// Meant for the debug builds of the simulator, the checks are too slow for a real cluster.
+#[derive(Default)]
+pub struct InvariantChecker {
+    // Checksums of the committed ops by their op numbers.
+    committed: HashMap<usize, u64>,
+    // Replica, that was the primary of the view in the normal status.
+    primaries: HashMap<usize, usize>,
+    // Commit number each replica was last seen with.
+    commit_numbers: HashMap<usize, usize>,
+    // Digests of the state machine by the commit number.
+    digests: HashMap<usize, u64>,
+    trace: VecDeque<String>,
+}
+
+impl InvariantChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the event, that's about to be handled, to the trace.
+    pub fn record(&mut self, event: String) {
+        if self.trace.len() == TRACE_LENGTH {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(event);
+    }
+
+    /// The replica lost its state, its commit number starts over.
+    pub fn crashed(&mut self, replica_id: usize) {
+        self.commit_numbers.remove(&replica_id);
+    }
+
+    /// Checks the replica, that has just handled an event, along with the `actions` it returned.
+    pub fn check<S: StateMachine>(
+        &mut self,
+        replica: &Replica<S>,
+        actions: &[Action<S::Op, S::Result>],
+    ) -> Result<(), Violation> {
+        let id = replica.id;
+        for action in actions {
+            if let Action::Commit {
+                op_number,
+                checksum,
+            } = action
+            {
+                self.check_committed(id, *op_number, *checksum)?;
+            }
+        }
+
+        // The committed entries, that are still in the log, can't have been replaced.
+        let commit_number = replica.commit_number();
+        let (checkpoint_number, checkpoint_checksum) = {
+            let checkpoint = replica.checkpoint.borrow();
+            (checkpoint.op_number, checkpoint.checksum)
+        };
+        if checkpoint_number > 0 {
+            self.check_committed(id, checkpoint_number, checkpoint_checksum)?;
+        }
+        let faulty_ops = replica.faulty_ops.borrow();
+        for (index, entry) in replica.log.borrow().iter().enumerate() {
+            let op_number = checkpoint_number + index + 1;
+            if op_number > commit_number {
+                break;
+            }
+            if !faulty_ops.contains(&op_number) {
+                self.check_committed(id, op_number, entry.checksum)?;
+            }
+        }
+
+        let op_number = replica.op_number();
+        if commit_number > op_number {
+            return Err(self.violation(format!(
+                "replica: {} has commit number: {} past its op number: {}",
+                id, commit_number, op_number
+            )));
+        }
+        if let Some(previous) = self.commit_numbers.insert(id, commit_number) {
+            if commit_number < previous {
+                return Err(self.violation(format!(
+                    "commit number of replica: {} went back from: {} to: {}",
+                    id, previous, commit_number
+                )));
+            }
+        }
+
+        if replica.is_primary() && *replica.status.borrow() == Status::Normal {
+            let view_number = replica.view_number();
+            let primary_id = *self.primaries.entry(view_number).or_insert(id);
+            if primary_id != id {
+                return Err(self.violation(format!(
+                    "replicas: {} and {} are both the primary of view: {}",
+                    primary_id, id, view_number
+                )));
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        replica.stm.borrow().snapshot().hash(&mut hasher);
+        let digest = hasher.finish();
+        if *self.digests.entry(commit_number).or_insert(digest) != digest {
+            return Err(self.violation(format!(
+                "state of replica: {} differs from the others at commit number: {}",
+                id, commit_number
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_committed(
+        &mut self,
+        replica_id: usize,
+        op_number: usize,
+        checksum: u64,
+    ) -> Result<(), Violation> {
+        let committed = *self.committed.entry(op_number).or_insert(checksum);
+        if committed != checksum {
+            return Err(self.violation(format!(
+                "op: {} committed with checksum: {:x}, replica: {} has: {:x} instead",
+                op_number, committed, replica_id, checksum
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reports the broken `invariant` along with the trace.
+    pub fn violation(&self, invariant: String) -> Violation {
+        Violation {
+            invariant,
+            trace: self.trace.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{counter::Counter, message::Message, replica_config::ReplicaConfig};
+    use client::Op;
+    use std::net::SocketAddr;
+
+    fn config() -> ReplicaConfig {
+        ReplicaConfig {
+            addresses: (0..3)
+                .map(|id| SocketAddr::from(([127, 0, 0, 1], 1000 + id)))
+                .collect(),
+            replicas: vec![0, 1, 2],
+            max_batch_size: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn committed_op_should_never_change() {
+        let mut checker = InvariantChecker::new();
+        let replica = Replica::<Counter>::new(0, config());
+        let commit = |checksum| Action::Commit {
+            op_number: 1,
+            checksum,
+        };
+        assert!(checker.check(&replica, &[commit(7)]).is_ok());
+        checker.record("replica: 1 received Prepare".to_string());
+        let violation = checker.check(&replica, &[commit(8)]).unwrap_err();
+        assert!(violation.invariant.contains("op: 1"));
+        assert_eq!(
+            violation.trace,
+            vec!["replica: 1 received Prepare".to_string()]
+        );
+    }
+
+    #[test]
+    fn view_should_have_a_single_primary() {
+        let mut checker = InvariantChecker::new();
+        let primary = Replica::<Counter>::new(0, config());
+        assert!(checker.check(&primary, &[]).is_ok());
+        // Another replica believing it leads the same view.
+        let usurper = Replica::<Counter>::new(1, config());
+        usurper.config.borrow_mut().replicas = vec![1, 0, 2];
+        let violation = checker.check(&usurper, &[]).unwrap_err();
+        assert!(violation.invariant.contains("view: 0"));
+    }
+
+    #[test]
+    fn commit_number_should_not_go_back_unless_the_replica_crashed() {
+        let mut checker = InvariantChecker::new();
+        let primary = Replica::<Counter>::new(0, config());
+        let backup = Replica::<Counter>::new(1, config());
+        let Action::SendToReplica {
+            envelope, message, ..
+        } = primary
+            .on_message(Message::Request {
+                client_id: 1,
+                request_number: 0,
+                op: Op::Add(5),
+            })
+            .remove(0)
+        else {
+            panic!("Expected the prepare");
+        };
+        let Action::SendToReplica {
+            envelope, message, ..
+        } = backup.on_peer_message(envelope, message).remove(0)
+        else {
+            panic!("Expected the ack");
+        };
+        let actions = primary.on_peer_message(envelope, message);
+        assert!(checker.check(&primary, &actions).is_ok());
+
+        let restarted = Replica::<Counter>::new(0, config());
+        assert!(checker.check(&restarted, &[]).is_err());
+        checker.crashed(0);
+        assert!(checker.check(&restarted, &[]).is_ok());
+    }
+}
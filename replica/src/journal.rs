@@ -0,0 +1,800 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    checkpoint::Checkpoint,
+    log::{self, LogEntry},
+    status::Status,
+    stm::Codec,
+};
+
+// Once the segment grows past this size, the next entry starts a new segment.
+const SEGMENT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+const SEGMENT_EXTENSION: &str = "log";
+const METADATA_FILE: &str = "metadata";
+const CHECKPOINT_FILE: &str = "checkpoint";
+// Kept along with the segments following it, in case the latest checkpoint gets corrupt.
+const PREVIOUS_CHECKPOINT_FILE: &str = "checkpoint.prev";
+const METADATA_SIZE: usize = 8 + 8 + 8 + 1;
+// Checksum of the rest of the file, followed by the metadata and the synced op number.
+const METADATA_FILE_SIZE: usize = 8 + METADATA_SIZE + 8;
+// Checksum of the length and the entry, followed by the length.
+const FRAME_HEADER_SIZE: usize = 8 + 4;
+
+/// Replica state, that's persisted next to the log.
+#[derive(Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub view_number: usize,
+    pub last_normal_view: usize,
+    pub commit_number: usize,
+    pub status: Status,
+}
+
+impl Metadata {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(METADATA_SIZE);
+        bytes.extend_from_slice(&self.view_number.to_le_bytes());
+        bytes.extend_from_slice(&self.last_normal_view.to_le_bytes());
+        bytes.extend_from_slice(&self.commit_number.to_le_bytes());
+        let status = match self.status {
+            Status::Normal => 0u8,
+            Status::Recovery => 1u8,
+            Status::ViewChange => 2u8,
+        };
+        bytes.push(status);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let view_number = usize::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let last_normal_view = usize::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let commit_number = usize::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let status = match bytes[24] {
+            0 => Status::Normal,
+            1 => Status::Recovery,
+            2 => Status::ViewChange,
+            _ => return Err(corrupt("Unknown status in the metadata")),
+        };
+        Ok(Self {
+            view_number,
+            last_normal_view,
+            commit_number,
+            status,
+        })
+    }
+}
+
+struct Segment {
+    // Op number of the first entry in the segment.
+    first_op_number: usize,
+    file: File,
+    // Byte offsets of the entries in the file.
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl Segment {
+    fn path(dir: &Path, first_op_number: usize) -> PathBuf {
+        dir.join(format!("{:020}.{}", first_op_number, SEGMENT_EXTENSION))
+    }
+
+    fn create(dir: &Path, first_op_number: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(Self::path(dir, first_op_number))?;
+        Ok(Self {
+            first_op_number,
+            file,
+            offsets: Vec::new(),
+            len: 0,
+        })
+    }
+
+    /// Reads the entries of the segment up to the first frame, that fails its checksum.
+    /// The length is checksummed along with the entry, a corrupt one can't misplace
+    /// the frames following it. A frame, that passes its checksum, but doesn't decode,
+    /// is read as an unreadable entry, to be repaired from a peer.
+    fn open<Op: Codec>(
+        dir: &Path,
+        first_op_number: usize,
+    ) -> io::Result<(Self, Vec<LogEntry<Op>>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(Self::path(dir, first_op_number))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut position = 0;
+        while let Some(entry_bytes) = read_frame(&bytes[position..]) {
+            let entry = match LogEntry::from_bytes(entry_bytes) {
+                Ok((entry, _)) => entry,
+                Err(e) => {
+                    eprintln!(
+                        "Unreadable entry for op: {}, in the journal: {}",
+                        first_op_number + entries.len(),
+                        e
+                    );
+                    LogEntry::unreadable()
+                }
+            };
+            entries.push(entry);
+            offsets.push(position as u64);
+            position += FRAME_HEADER_SIZE + entry_bytes.len();
+        }
+        let len = position as u64;
+        if len < bytes.len() as u64 {
+            eprintln!(
+                "Truncating unreadable bytes at the end of segment: {}",
+                first_op_number
+            );
+            file.set_len(len)?;
+            file.sync_all()?;
+            file.seek(SeekFrom::Start(len))?;
+        }
+        let segment = Self {
+            first_op_number,
+            file,
+            offsets,
+            len,
+        };
+        Ok((segment, entries))
+    }
+
+    /// Writes the entry in a frame, it's durable only after a sync.
+    fn write(&mut self, entry_bytes: &[u8]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(FRAME_HEADER_SIZE + entry_bytes.len());
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&(entry_bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(entry_bytes);
+        let checksum = log::checksum(&bytes[8..]);
+        bytes[..8].copy_from_slice(&checksum.to_le_bytes());
+        self.file.write_all(&bytes)?;
+        self.offsets.push(self.len);
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// Entry bytes of the frame at the start of `bytes`, `None` if it's cut off or fails its checksum.
+fn read_frame(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.len() < FRAME_HEADER_SIZE {
+        return None;
+    }
+    let checksum = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+    let len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+    let frame = bytes.get(8..FRAME_HEADER_SIZE + len)?;
+    (log::checksum(frame) == checksum).then_some(&frame[4..])
+}
+
+/// The checkpoint in the file `name`, `None` if there's none or it's corrupt.
+fn read_checkpoint(dir: &Path, name: &str) -> io::Result<Option<Checkpoint>> {
+    match fs::read(dir.join(name)) {
+        Ok(bytes) => {
+            let checkpoint = Checkpoint::from_bytes(&bytes)
+                .filter(|(_, size)| *size == bytes.len())
+                .map(|(checkpoint, _)| checkpoint);
+            if checkpoint.is_none() {
+                eprintln!("Found corrupt checkpoint in: {}", name);
+            }
+            Ok(checkpoint)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn corrupt(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Write-ahead log of the replica, split into segment files named
+/// after the op number of their first entry. The segments covered
+/// by the latest checkpoint are removed.
+pub struct Journal {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+    // Last metadata written to disk.
+    metadata: Metadata,
+    // Entries up to this op number were synced, and possibly acknowledged. A corrupt one is bit rot,
+    // not a torn write, it can't be dropped.
+    synced_op_number: usize,
+    checkpoint_op_number: usize,
+}
+
+impl Journal {
+    /// Opens the journal in `dir`, creating it if needed.
+    /// Returns the journal along with the latest checkpoint and the log following it.
+    pub fn open<Op: Codec>(
+        dir: impl Into<PathBuf>,
+    ) -> io::Result<(Self, Checkpoint, Vec<LogEntry<Op>>)> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut first_op_numbers = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(first_op_number) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<usize>().ok())
+            {
+                first_op_numbers.push(first_op_number);
+            }
+        }
+        first_op_numbers.sort();
+
+        // A corrupt checkpoint falls back to the previous one, or to the empty state, as long as
+        // the segments bridge to it. Otherwise the state is lost, the replica recovers it from the others.
+        let bridged = |checkpoint: &Checkpoint| {
+            first_op_numbers
+                .first()
+                .is_none_or(|first_op_number| *first_op_number <= checkpoint.op_number + 1)
+        };
+        let (checkpoint, lost) = match read_checkpoint(&dir, CHECKPOINT_FILE)? {
+            Some(checkpoint) => (checkpoint, false),
+            None => match read_checkpoint(&dir, PREVIOUS_CHECKPOINT_FILE)?.filter(bridged) {
+                Some(checkpoint) => (checkpoint, false),
+                None => (Checkpoint::default(), !bridged(&Checkpoint::default())),
+            },
+        };
+        let checkpoint_op_number = checkpoint.op_number;
+
+        // The file is replaced atomically, a corrupt one is bit rot, going on with
+        // a default view number could see the replica vote twice in the same view.
+        let (mut metadata, mut synced_op_number) = match fs::read(dir.join(METADATA_FILE)) {
+            Ok(bytes)
+                if bytes.len() == METADATA_FILE_SIZE
+                    && u64::from_le_bytes(bytes[0..8].try_into().unwrap())
+                        == log::checksum(&bytes[8..]) =>
+            {
+                (
+                    Metadata::from_bytes(&bytes[8..])?,
+                    usize::from_le_bytes(bytes[8 + METADATA_SIZE..].try_into().unwrap()),
+                )
+            }
+            Ok(_) => return Err(corrupt("Corrupt metadata file")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e),
+        };
+        if lost {
+            eprintln!("Lost the checkpoint, recovering the state from the other replicas");
+            metadata.status = Status::Recovery;
+            // None of the segments follow the empty state, those are dropped below.
+            synced_op_number = 0;
+        }
+
+        let mut segments = Vec::new();
+        let mut log = Vec::new();
+        let mut next_op_number = None;
+        let mut first_op_numbers = first_op_numbers.into_iter().peekable();
+        while let Some(first_op_number) = first_op_numbers.next() {
+            // Segments past a gap were left behind by an interrupted truncation.
+            let contiguous = match next_op_number {
+                Some(next_op_number) => first_op_number == next_op_number,
+                None => first_op_number <= checkpoint_op_number + 1,
+            };
+            if !contiguous {
+                fs::remove_file(Segment::path(&dir, first_op_number))?;
+                continue;
+            }
+            let (mut segment, mut entries) = Segment::open(&dir, first_op_number)?;
+            // Synced entries, that can't be read, are kept as unreadable, until they're repaired.
+            // The next segment starts right after the last synced entry of this one.
+            let last_op_number = first_op_numbers
+                .peek()
+                .map_or(synced_op_number, |next| (next - 1).min(synced_op_number));
+            let lost = (last_op_number + 1).saturating_sub(first_op_number + entries.len());
+            if lost > 0 {
+                eprintln!(
+                    "Lost {} synced entries of segment: {}, keeping them for the repair",
+                    lost, first_op_number
+                );
+                for _ in 0..lost {
+                    segment.write(&LogEntry::<Op>::unreadable().to_bytes())?;
+                    entries.push(LogEntry::unreadable());
+                }
+                segment.file.sync_data()?;
+            }
+            next_op_number = Some(first_op_number + entries.len());
+            log.extend(entries);
+            segments.push(segment);
+        }
+        // Drop the entries covered by the checkpoint, the segment holding
+        // the first entry following it can start before it.
+        if let Some(first_op_number) = segments.first().map(|segment| segment.first_op_number) {
+            let covered = (checkpoint_op_number + 1 - first_op_number).min(log.len());
+            log.drain(..covered);
+        }
+
+        let mut journal = Self {
+            dir,
+            segments,
+            metadata,
+            synced_op_number,
+            checkpoint_op_number,
+        };
+        if lost {
+            // The replica stays in recovery across restarts, until it learns the state.
+            journal.write_metadata_file(&journal.metadata, synced_op_number)?;
+        }
+        if log.is_empty() {
+            // Whatever is left precedes the checkpoint.
+            journal.remove_segments()?;
+        }
+        // Corrupt entries at the end of the log, that follow the synced ones, are the remains
+        // of a torn write, those were never acknowledged. The synced ones are left for the repair.
+        let torn = log
+            .iter()
+            .enumerate()
+            .rev()
+            .take_while(|(index, entry)| {
+                checkpoint_op_number + index + 1 > synced_op_number && !entry.is_valid()
+            })
+            .count();
+        if torn > 0 {
+            eprintln!("Truncating {} torn entries at the end of the journal", torn);
+            log.truncate(log.len() - torn);
+            journal.truncate(checkpoint_op_number + log.len())?;
+        }
+        Ok((journal, checkpoint, log))
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn op_number(&self) -> usize {
+        self.segments
+            .last()
+            .map(|segment| segment.first_op_number + segment.offsets.len() - 1)
+            .unwrap_or(self.checkpoint_op_number)
+    }
+
+    /// Writes the entries, those are durable only after `sync`.
+    pub fn append<'a, Op: Codec + 'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = &'a LogEntry<Op>>,
+    ) -> io::Result<()> {
+        for entry in entries {
+            let op_number = self.op_number() + 1;
+            let needs_segment = self
+                .segments
+                .last()
+                .is_none_or(|segment| segment.len >= SEGMENT_MAX_BYTES);
+            if needs_segment {
+                self.sync()?;
+                self.segments.push(Segment::create(&self.dir, op_number)?);
+            }
+            self.segments.last_mut().unwrap().write(&entry.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Makes the entries durable and records them as synced.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.segments.last_mut() {
+            segment.file.sync_data()?;
+        }
+        let op_number = self.op_number();
+        if op_number > self.synced_op_number {
+            self.write_metadata_file(&self.metadata, op_number)?;
+            self.synced_op_number = op_number;
+        }
+        Ok(())
+    }
+
+    /// Removes the entries following `op_number`.
+    pub fn truncate(&mut self, op_number: usize) -> io::Result<()> {
+        // Lowered first, the entries left past it by a crash are dropped at startup, if corrupt.
+        if op_number < self.synced_op_number {
+            self.write_metadata_file(&self.metadata, op_number)?;
+            self.synced_op_number = op_number;
+        }
+        while let Some(segment) = self.segments.last_mut() {
+            if segment.first_op_number > op_number {
+                let segment = self.segments.pop().unwrap();
+                drop(segment.file);
+                fs::remove_file(Segment::path(&self.dir, segment.first_op_number))?;
+                continue;
+            }
+            let keep = op_number + 1 - segment.first_op_number;
+            if keep < segment.offsets.len() {
+                let len = segment.offsets[keep];
+                segment.offsets.truncate(keep);
+                segment.len = len;
+                segment.file.set_len(len)?;
+                segment.file.sync_all()?;
+                segment.file.seek(SeekFrom::Start(len))?;
+            }
+            break;
+        }
+        Ok(())
+    }
+
+    fn remove_segments(&mut self) -> io::Result<()> {
+        for segment in self.segments.drain(..) {
+            drop(segment.file);
+            fs::remove_file(Segment::path(&self.dir, segment.first_op_number))?;
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces the persisted metadata, unless it's unchanged.
+    pub fn write_metadata(&mut self, metadata: Metadata) -> io::Result<()> {
+        if metadata == self.metadata {
+            return Ok(());
+        }
+        self.write_metadata_file(&metadata, self.synced_op_number)?;
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    fn write_metadata_file(&self, metadata: &Metadata, synced_op_number: usize) -> io::Result<()> {
+        let mut bytes = vec![0u8; 8];
+        bytes.extend(metadata.to_bytes());
+        bytes.extend_from_slice(&synced_op_number.to_le_bytes());
+        let checksum = log::checksum(&bytes[8..]);
+        bytes[0..8].copy_from_slice(&checksum.to_le_bytes());
+        self.write_file(METADATA_FILE, &bytes, None)
+    }
+
+    /// Persists the checkpoint and removes the segments covered by the previous one, that's kept
+    /// as a fallback. A checkpoint past the end of the journal, received from a peer,
+    /// replaces the whole journal.
+    pub fn write_checkpoint(&mut self, checkpoint: &Checkpoint) -> io::Result<()> {
+        self.write_file(
+            CHECKPOINT_FILE,
+            &checkpoint.to_bytes(),
+            Some(PREVIOUS_CHECKPOINT_FILE),
+        )?;
+        if checkpoint.op_number >= self.op_number() {
+            self.remove_segments()?;
+        } else {
+            // A segment can be removed, once the next one starts right after the previous checkpoint.
+            let previous_op_number = self.checkpoint_op_number;
+            let covered = self
+                .segments
+                .iter()
+                .skip(1)
+                .take_while(|segment| segment.first_op_number <= previous_op_number + 1)
+                .count();
+            for segment in self.segments.drain(..covered) {
+                drop(segment.file);
+                fs::remove_file(Segment::path(&self.dir, segment.first_op_number))?;
+            }
+        }
+        self.checkpoint_op_number = checkpoint.op_number;
+        Ok(())
+    }
+
+    /// Atomically replaces the file, the replaced one is kept as `previous`, if given.
+    fn write_file(&self, name: &str, bytes: &[u8], previous: Option<&str>) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        let path = self.dir.join(name);
+        if let Some(previous) = previous {
+            match fs::rename(&path, self.dir.join(previous)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        fs::rename(&tmp_path, path)?;
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client::Op;
+
+    use crate::log::Payload;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vsr-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn generate_log(count: usize) -> Vec<LogEntry<Op>> {
+        let mut parent = 0;
+        (0..count)
+            .map(|request_number| {
+                let mut entry = LogEntry::new(1, request_number, Op::Add(69));
+                entry.seal(parent);
+                parent = entry.checksum;
+                entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reopening_journal_should_restore_the_log_and_metadata() {
+        let dir = temp_dir("reopen");
+        let log = generate_log(10);
+        let metadata = Metadata {
+            view_number: 3,
+            last_normal_view: 2,
+            commit_number: 7,
+            status: Status::ViewChange,
+        };
+        {
+            let (mut journal, _, restored) = Journal::open::<Op>(&dir).unwrap();
+            assert!(restored.is_empty());
+            journal.append(&log).unwrap();
+            journal.sync().unwrap();
+            journal.truncate(7).unwrap();
+            journal.append(&log[7..8]).unwrap();
+            journal.sync().unwrap();
+            journal
+                .write_metadata(Metadata {
+                    view_number: 3,
+                    last_normal_view: 2,
+                    commit_number: 7,
+                    status: Status::ViewChange,
+                })
+                .unwrap();
+        }
+        let (journal, _, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored, log[..8]);
+        assert_eq!(journal.metadata(), &metadata);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_metadata_should_fail_to_open() {
+        let dir = temp_dir("metadata");
+        {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal
+                .write_metadata(Metadata {
+                    view_number: 3,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let path = dir.join(METADATA_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[8] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let error = Journal::open::<Op>(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // An unknown status under a valid checksum.
+        bytes[8] ^= 1;
+        bytes[8 + METADATA_SIZE - 1] = 9;
+        let checksum = log::checksum(&bytes[8..]);
+        bytes[0..8].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        let error = Journal::open::<Op>(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_write_at_the_end_of_segment_should_be_truncated() {
+        let dir = temp_dir("torn");
+        let log = generate_log(3);
+        {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal.append(&log).unwrap();
+            journal.sync().unwrap();
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(Segment::path(&dir, 1))
+            .unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let (mut journal, _, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored, log);
+        journal.append(&log[..1]).unwrap();
+        drop(journal);
+        let (_, _, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored.len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undecodable_entry_should_be_restored_as_faulty() {
+        let dir = temp_dir("undecodable");
+        let log = generate_log(3);
+        {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal.append(&log[..1]).unwrap();
+            // Frame, that passes its checksum, around an entry, that doesn't decode.
+            journal
+                .segments
+                .last_mut()
+                .unwrap()
+                .write(&[1, 2, 3])
+                .unwrap();
+            journal.append(&log[2..]).unwrap();
+            journal.sync().unwrap();
+        }
+        let (_, checkpoint, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert_eq!(restored[2], log[2]);
+        assert_eq!(
+            log::find_faulty_ops(&restored, &checkpoint),
+            [2].into_iter().collect()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entries_at_the_end_of_journal_should_be_truncated_unless_synced() {
+        let dir = temp_dir("corrupt-tail");
+        let mut log = generate_log(4);
+        log[3].payload = Payload::Op(Op::Add(70));
+        {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal.append(&log[..3]).unwrap();
+            journal.sync().unwrap();
+            journal.append(&log[3..]).unwrap();
+        }
+        let (mut journal, _, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored, log[..3]);
+
+        // Bit rot in an entry, that might have been acknowledged.
+        journal.append(&log[3..]).unwrap();
+        journal.sync().unwrap();
+        drop(journal);
+        let (_, checkpoint, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored, log);
+        assert_eq!(
+            log::find_faulty_ops(&restored, &checkpoint),
+            [4].into_iter().collect()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn synced_entries_behind_a_corrupt_frame_should_be_kept_as_faulty() {
+        let dir = temp_dir("corrupt-frame");
+        let log = generate_log(5);
+        let offset = {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal.append(&log).unwrap();
+            journal.sync().unwrap();
+            journal.segments[0].offsets[1]
+        };
+        // Bit rot in the length of the second frame, the frames following it can't be found.
+        let mut bytes = fs::read(Segment::path(&dir, 1)).unwrap();
+        bytes[offset as usize + 8] ^= 1;
+        fs::write(Segment::path(&dir, 1), bytes).unwrap();
+
+        for _ in 0..2 {
+            let (_, checkpoint, restored) = Journal::open::<Op>(&dir).unwrap();
+            assert_eq!(restored.len(), 5);
+            assert_eq!(restored[0], log[0]);
+            assert_eq!(
+                log::find_faulty_ops(&restored, &checkpoint),
+                (2..=5).collect()
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn generate_checkpoint(op_number: usize, log: &[LogEntry<Op>]) -> Checkpoint {
+        Checkpoint {
+            op_number,
+            checksum: log[op_number - 1].checksum,
+            epoch_number: 1,
+            replicas: vec![0, 1, 2],
+            state: vec![1, 2, 3],
+            clients_table: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn checkpoint_should_remove_the_segments_covered_by_the_previous_one() {
+        let dir = temp_dir("checkpoint");
+        let log = generate_log(10);
+        let checkpoint = generate_checkpoint(8, &log);
+        {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal.append(&log[..5]).unwrap();
+            journal.sync().unwrap();
+            // Start a new segment for the rest of the log.
+            journal.segments.last_mut().unwrap().len = SEGMENT_MAX_BYTES;
+            journal.append(&log[5..]).unwrap();
+            journal.sync().unwrap();
+            assert_eq!(journal.segments.len(), 2);
+            journal
+                .write_checkpoint(&generate_checkpoint(6, &log))
+                .unwrap();
+            assert_eq!(journal.segments.len(), 2);
+            journal.write_checkpoint(&checkpoint).unwrap();
+            assert_eq!(journal.segments.len(), 1);
+        }
+        let (mut journal, restored_checkpoint, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored_checkpoint, checkpoint);
+        assert_eq!(restored, log[8..]);
+
+        // Checkpoint past the end of the journal.
+        let checkpoint = Checkpoint {
+            op_number: 20,
+            ..Default::default()
+        };
+        journal.write_checkpoint(&checkpoint).unwrap();
+        journal.append(&log[..1]).unwrap();
+        drop(journal);
+        let (_, _, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored, log[..1]);
+        assert!(Segment::path(&dir, 21).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_checkpoint_should_fall_back_to_the_previous_one() {
+        let dir = temp_dir("previous-checkpoint");
+        let log = generate_log(10);
+        {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal.append(&log).unwrap();
+            journal.sync().unwrap();
+            journal
+                .write_checkpoint(&generate_checkpoint(3, &log))
+                .unwrap();
+            journal
+                .write_checkpoint(&generate_checkpoint(6, &log))
+                .unwrap();
+        }
+        let path = dir.join(CHECKPOINT_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        let (journal, restored_checkpoint, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored_checkpoint, generate_checkpoint(3, &log));
+        assert_eq!(restored, log[3..]);
+        assert_eq!(journal.metadata().status, Status::Normal);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lost_checkpoint_should_leave_the_replica_in_recovery() {
+        let dir = temp_dir("lost-checkpoint");
+        let log = generate_log(10);
+        {
+            let (mut journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+            journal
+                .write_checkpoint(&generate_checkpoint(3, &log))
+                .unwrap();
+            // Installed from a peer, the previous checkpoint doesn't bridge to the journal.
+            journal
+                .write_checkpoint(&generate_checkpoint(6, &log))
+                .unwrap();
+            journal.append(&log[6..]).unwrap();
+            journal.sync().unwrap();
+        }
+        fs::write(dir.join(CHECKPOINT_FILE), [1, 2, 3]).unwrap();
+
+        let (journal, restored_checkpoint, restored) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(restored_checkpoint, Checkpoint::default());
+        assert!(restored.is_empty());
+        assert_eq!(journal.metadata().status, Status::Recovery);
+        drop(journal);
+        let (journal, _, _) = Journal::open::<Op>(&dir).unwrap();
+        assert_eq!(journal.metadata().status, Status::Recovery);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use client::kv::{KvOp, KvResult};
+
+use crate::stm::{Codec, StateMachine};
+
+/// Replicated key-value store. The keys are kept ordered, so that the snapshots of the
+/// same state are identical on every replica.
+#[derive(Default)]
+pub struct KvStore {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl StateMachine for KvStore {
+    type Op = KvOp;
+    type Result = KvResult;
+
+    fn apply(&mut self, op: KvOp) -> KvResult {
+        match op {
+            KvOp::Put { key, value } => KvResult::Value(self.entries.insert(key, value)),
+            KvOp::Get { key } => KvResult::Value(self.entries.get(&key).cloned()),
+            KvOp::Delete { key } => KvResult::Value(self.entries.remove(&key)),
+            KvOp::CompareAndSwap { key, expected, new } => {
+                let current = self.entries.get(&key).cloned();
+                let swapped = current == expected;
+                if swapped {
+                    self.entries.insert(key, new);
+                }
+                KvResult::CompareAndSwap { swapped, current }
+            }
+        }
+    }
+
+    fn query(&self, op: &KvOp) -> Option<KvResult> {
+        match op {
+            KvOp::Get { key } => Some(KvResult::Value(self.entries.get(key).cloned())),
+            _ => None,
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (key, value) in self.entries.iter() {
+            bytes.extend(key.to_bytes());
+            bytes.extend(value.to_bytes());
+        }
+        bytes
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        self.entries.clear();
+        let mut position = 0;
+        while position < snapshot.len() {
+            let (key, key_size) = Vec::from_bytes(&snapshot[position..]).expect("Corrupt snapshot");
+            let (value, value_size) =
+                Vec::from_bytes(&snapshot[position + key_size..]).expect("Corrupt snapshot");
+            self.entries.insert(key, value);
+            position += key_size + value_size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &[u8], value: &[u8]) -> KvOp {
+        KvOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    #[test]
+    fn ops_should_return_the_previous_values() {
+        let mut store = KvStore::default();
+        assert_eq!(store.apply(put(b"key", b"1")), KvResult::Value(None));
+        assert_eq!(
+            store.apply(put(b"key", b"2")),
+            KvResult::Value(Some(b"1".to_vec()))
+        );
+        assert_eq!(
+            store.apply(KvOp::Get {
+                key: b"key".to_vec()
+            }),
+            KvResult::Value(Some(b"2".to_vec()))
+        );
+        assert_eq!(
+            store.query(&KvOp::Get {
+                key: b"key".to_vec()
+            }),
+            Some(KvResult::Value(Some(b"2".to_vec())))
+        );
+        assert_eq!(store.query(&put(b"key", b"3")), None);
+        assert_eq!(
+            store.apply(KvOp::Delete {
+                key: b"key".to_vec()
+            }),
+            KvResult::Value(Some(b"2".to_vec()))
+        );
+        assert_eq!(
+            store.apply(KvOp::Get {
+                key: b"key".to_vec()
+            }),
+            KvResult::Value(None)
+        );
+    }
+
+    #[test]
+    fn compare_and_swap_should_succeed_only_on_the_expected_value() {
+        let mut store = KvStore::default();
+        let cas = |expected: Option<&[u8]>, new: &[u8]| KvOp::CompareAndSwap {
+            key: b"key".to_vec(),
+            expected: expected.map(<[u8]>::to_vec),
+            new: new.to_vec(),
+        };
+        assert_eq!(
+            store.apply(cas(None, b"1")),
+            KvResult::CompareAndSwap {
+                swapped: true,
+                current: None
+            }
+        );
+        assert_eq!(
+            store.apply(cas(None, b"2")),
+            KvResult::CompareAndSwap {
+                swapped: false,
+                current: Some(b"1".to_vec())
+            }
+        );
+        assert_eq!(
+            store.apply(cas(Some(b"1"), b"2")),
+            KvResult::CompareAndSwap {
+                swapped: true,
+                current: Some(b"1".to_vec())
+            }
+        );
+    }
+
+    #[test]
+    fn restored_store_should_hold_the_same_entries() {
+        let mut store = KvStore::default();
+        store.apply(put(b"a", b"1"));
+        store.apply(put(b"b", b""));
+        store.apply(put(b"", b"3"));
+
+        let mut restored = KvStore::default();
+        restored.apply(put(b"stale", b"4"));
+        restored.restore(&store.snapshot());
+
+        assert_eq!(restored.entries, store.entries);
+        assert_eq!(restored.snapshot(), store.snapshot());
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, time::Duration};
+
+/// Read-only request, that waits for the primary to confirm its lease.
+pub struct PendingRead<Op> {
+    // Time the read arrived at, any heartbeat acked by the quorum after it confirms the read.
+    pub timestamp: u64,
+    pub client_id: usize,
+    pub request_number: usize,
+    pub op: Op,
+}
+
+/// Read lease of the primary, renewed by the backups acking the `Commit` heartbeats.
+///
+/// A backup acking a heartbeat promises not to help starting a new view for the lease duration,
+/// so while a quorum of backups keeps the promise no other primary can commit an op.
+/// The primary's lease starts once the heartbeat is sent, before the backups' promises start,
+/// it's safe as long as the clocks tick at roughly the same rate.
+/// Timestamps are the microseconds read from the replica's `Clock`.
+pub struct Lease {
+    duration: Duration,
+    // Backup: time until which we don't send the `DoViewChange`.
+    promised_until: u64,
+    // Primary: latest heartbeat timestamp acked by each backup in the current view.
+    acks: HashMap<usize, u64>,
+    // Primary: op number at the start of the view. The ops up to it might have been committed
+    // by the previous primary, so reads wait until we commit them as well.
+    view_op_number: usize,
+}
+
+impl Lease {
+    /// A replica might have promised the lease before it restarted, so it starts out promised.
+    pub fn new(duration: Duration, now: u64) -> Self {
+        Self {
+            duration,
+            promised_until: now + duration.as_micros() as u64,
+            acks: HashMap::new(),
+            view_op_number: 0,
+        }
+    }
+
+    /// Called by the backup acking a heartbeat.
+    pub fn promise(&mut self, now: u64) {
+        self.promised_until = self
+            .promised_until
+            .max(now + self.duration.as_micros() as u64);
+    }
+
+    pub fn is_promised(&self, now: u64) -> bool {
+        now < self.promised_until
+    }
+
+    /// Drops the acks of the previous view, the lease has to be acquired again.
+    pub fn reset(&mut self, view_op_number: usize) {
+        self.acks.clear();
+        self.view_op_number = view_op_number;
+    }
+
+    pub fn view_op_number(&self) -> usize {
+        self.view_op_number
+    }
+
+    pub fn ack(&mut self, replica_id: usize, timestamp: u64) {
+        let acked = self.acks.entry(replica_id).or_default();
+        *acked = (*acked).max(timestamp);
+    }
+
+    /// Latest heartbeat timestamp acked by the quorum, the primary counts towards it as well.
+    pub fn confirmed(&self, quorum: usize, now: u64) -> Option<u64> {
+        let Some(backups) = quorum.checked_sub(1).filter(|backups| *backups > 0) else {
+            // Single replica cluster, there's no one to take over.
+            return Some(now);
+        };
+        let mut acked: Vec<u64> = self.acks.values().copied().collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        acked.get(backups - 1).copied()
+    }
+
+    pub fn is_valid(&self, quorum: usize, now: u64) -> bool {
+        self.confirmed(quorum, now)
+            .is_some_and(|confirmed| confirmed + self.duration.as_micros() as u64 > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lease_should_be_confirmed_by_the_quorum_of_backups() {
+        let mut lease = Lease::new(Duration::from_secs(60), 0);
+        // Five replicas, the primary and two backups make up the quorum.
+        lease.ack(1, 10);
+        assert_eq!(lease.confirmed(3, 40), None);
+        assert!(!lease.is_valid(3, 40));
+        lease.ack(2, 20);
+        lease.ack(3, 30);
+        assert_eq!(lease.confirmed(3, 40), Some(20));
+        assert!(lease.is_valid(3, 40));
+        // Late ack of an older heartbeat.
+        lease.ack(3, 5);
+        assert_eq!(lease.confirmed(3, 40), Some(20));
+
+        lease.reset(7);
+        assert_eq!(lease.confirmed(3, 40), None);
+        assert_eq!(lease.view_op_number(), 7);
+    }
+
+    #[test]
+    fn lapsed_lease_should_not_be_valid() {
+        let mut lease = Lease::new(Duration::from_millis(1), 0);
+        assert!(lease.is_promised(999));
+        lease.ack(1, 1_000);
+        assert!(lease.confirmed(2, 1_000).is_some());
+        assert!(lease.is_valid(2, 1_500));
+        assert!(!lease.is_valid(2, 2_000));
+        assert!(!lease.is_promised(1_000));
+        lease.promise(1_500);
+        assert!(lease.is_promised(2_000));
+    }
+}
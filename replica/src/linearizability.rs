@@ -0,0 +1,313 @@
+use std::{collections::HashSet, fmt};
+
+use client::history::{History, Operation};
+
+use crate::stm::StateMachine;
+
+// Budget of the completed ops, that the shrinking may turn into the pending ones.
+// Every pending op doubles the orders the search might have to try.
+const MAX_RELAXED_OPS: usize = 8;
+
+/// Sub-history, that's not linearizable on its own, so neither is the history it was cut from.
+/// Ops with an unknown result are kept for their effect, the result they got doesn't matter.
+pub struct Violation<Op, R> {
+    pub operations: Vec<Operation<Op, R>>,
+}
+
+impl<Op: fmt::Debug, R: fmt::Debug> fmt::Display for Violation<Op, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "No sequential order of the {} ops matches their results:",
+            self.operations.len()
+        )?;
+        for operation in &self.operations {
+            write!(
+                f,
+                "  client: {}, request: {}, op: {:?}, invoked at: {}",
+                operation.client_id, operation.request_number, operation.op, operation.invoked_at
+            )?;
+            match (&operation.result, operation.completed_at) {
+                (Some(result), Some(completed_at)) => {
+                    writeln!(f, ", result: {:?}, completed at: {}", result, completed_at)?
+                }
+                _ => writeln!(f, ", result: unknown")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decides, whether the ops of the `history` could have taken effect one at a time,
+/// each between its invocation and completion, producing the results the clients got
+/// from the state machine `S`. Ops, that never completed, might have taken effect or not.
+///
+/// Searches the orders like Wing and Gong, with the states already seen memoized as Lowe suggests.
+/// A violation is shrunk down to a minimal failing sub-history.
+pub fn check<S: StateMachine>(
+    history: &History<S::Op, S::Result>,
+) -> Result<(), Violation<S::Op, S::Result>> {
+    let operations = relevant_operations::<S>(&history.operations);
+    if is_linearizable::<S>(&operations) {
+        return Ok(());
+    }
+    Err(Violation {
+        operations: shrink::<S>(operations),
+    })
+}
+
+/// Pending reads don't change the state and nobody saw their results, so they can't matter.
+fn relevant_operations<S: StateMachine>(
+    operations: &[Operation<S::Op, S::Result>],
+) -> Vec<Operation<S::Op, S::Result>> {
+    let model = S::default();
+    operations
+        .iter()
+        .filter(|operation| operation.result.is_some() || model.query(&operation.op).is_none())
+        .cloned()
+        .collect()
+}
+
+fn is_linearizable<S: StateMachine>(operations: &[Operation<S::Op, S::Result>]) -> bool {
+    let words = operations.len().div_ceil(64);
+    let remaining_completed = operations
+        .iter()
+        .filter(|operation| operation.result.is_some())
+        .count();
+    // Linearized ops along with the state they led to, a failed search doesn't have to be repeated.
+    let mut seen: HashSet<(Vec<u64>, Vec<u8>)> = HashSet::new();
+    // Depth first, every frame holds the ops linearized so far.
+    let mut stack = vec![(
+        vec![0u64; words],
+        S::default().snapshot(),
+        remaining_completed,
+    )];
+    while let Some((linearized, snapshot, remaining_completed)) = stack.pop() {
+        if remaining_completed == 0 {
+            return true;
+        }
+        let is_linearized = |index: usize| linearized[index / 64] & (1 << (index % 64)) != 0;
+        // The op, that completes first has to be linearized before any op invoked after it.
+        let deadline = operations
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !is_linearized(*index))
+            .filter_map(|(_, operation)| operation.completed_at)
+            .min()
+            .unwrap_or(u64::MAX);
+        for (index, operation) in operations.iter().enumerate() {
+            if is_linearized(index) || operation.invoked_at > deadline {
+                continue;
+            }
+            let mut model = S::default();
+            model.restore(&snapshot);
+            let result = model.apply(operation.op.clone());
+            if operation
+                .result
+                .as_ref()
+                .is_some_and(|expected| *expected != result)
+            {
+                continue;
+            }
+            let mut next = linearized.clone();
+            next[index / 64] |= 1 << (index % 64);
+            let next_snapshot = model.snapshot();
+            if seen.insert((next.clone(), next_snapshot.clone())) {
+                let completed = usize::from(operation.result.is_some());
+                stack.push((next, next_snapshot, remaining_completed - completed));
+            }
+        }
+    }
+    false
+}
+
+/// Cuts the non-linearizable history down, while it keeps failing:
+/// first to the shortest prefix, then drops the completed reads and forgets the results
+/// of the other ops one by one. Each step only relaxes the history, so it keeps failing
+/// only if the original history does.
+fn shrink<S: StateMachine>(
+    operations: Vec<Operation<S::Op, S::Result>>,
+) -> Vec<Operation<S::Op, S::Result>> {
+    let mut times: Vec<u64> = operations
+        .iter()
+        .flat_map(|operation| [Some(operation.invoked_at), operation.completed_at])
+        .flatten()
+        .collect();
+    times.sort_unstable();
+    times.dedup();
+    // Linearizability is prefix closed, so the failing prefixes are those past some time.
+    let (mut low, mut high) = (0, times.len() - 1);
+    while low < high {
+        let middle = (low + high) / 2;
+        if is_linearizable::<S>(&prefix(&operations, times[middle])) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let mut operations = prefix(&operations, times[low]);
+
+    let model = S::default();
+    let mut relaxed = operations
+        .iter()
+        .filter(|operation| operation.result.is_none())
+        .count();
+    for index in (0..operations.len()).rev() {
+        let operation = &operations[index];
+        if operation.result.is_none() {
+            continue;
+        }
+        let mut candidate = operations.clone();
+        if model.query(&operation.op).is_some() {
+            // A read doesn't change the state, the others don't depend on it.
+            candidate.remove(index);
+        } else if relaxed < MAX_RELAXED_OPS {
+            candidate[index].result = None;
+            candidate[index].completed_at = None;
+        } else {
+            continue;
+        }
+        if !is_linearizable::<S>(&candidate) {
+            if candidate.len() == operations.len() {
+                relaxed += 1;
+            }
+            operations = candidate;
+        }
+    }
+    operations
+}
+
+/// History as it was at the `time`, the ops, that completed later are pending.
+fn prefix<Op: Clone, R: Clone>(
+    operations: &[Operation<Op, R>],
+    time: u64,
+) -> Vec<Operation<Op, R>> {
+    operations
+        .iter()
+        .filter(|operation| operation.invoked_at <= time)
+        .map(|operation| match operation.completed_at {
+            Some(completed_at) if completed_at > time => Operation {
+                result: None,
+                completed_at: None,
+                ..operation.clone()
+            },
+            _ => operation.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{counter::Counter, kv::KvStore};
+    use client::{
+        history::Event,
+        kv::{KvOp, KvResult},
+        Op,
+    };
+
+    fn history<Op, R>(operations: Vec<(usize, Op, Option<R>, u64, u64)>) -> History<Op, R> {
+        let mut history = History::default();
+        let mut events = Vec::new();
+        for (request_number, (client_id, op, result, invoked_at, completed_at)) in
+            operations.into_iter().enumerate()
+        {
+            events.push(Event::Invoke {
+                client_id,
+                request_number,
+                op,
+                time: invoked_at,
+            });
+            if let Some(result) = result {
+                events.push(Event::Complete {
+                    client_id,
+                    request_number,
+                    result,
+                    time: completed_at,
+                });
+            }
+        }
+        events.sort_by_key(|event| match event {
+            Event::Invoke { time, .. } | Event::Complete { time, .. } => *time,
+        });
+        for event in events {
+            history.record(event);
+        }
+        history
+    }
+
+    #[test]
+    fn concurrent_ops_should_be_ordered_either_way() {
+        // The read overlaps both adds and sees only the second one.
+        let history = history(vec![
+            (1, Op::Add(1), Some(1), 0, 10),
+            (2, Op::Add(2), Some(3), 5, 20),
+            (3, Op::Nop, Some(3), 6, 30),
+            // Never completed, but the read after it saw its effect.
+            (4, Op::Add(4), None, 25, 0),
+            (3, Op::Nop, Some(7), 40, 50),
+        ]);
+        assert!(check::<Counter>(&history).is_ok());
+    }
+
+    #[test]
+    fn stale_read_should_be_reported_with_a_minimal_sub_history() {
+        let history = history(vec![
+            (1, Op::Add(1), Some(1), 0, 10),
+            (2, Op::Nop, Some(1), 12, 14),
+            (1, Op::Add(2), Some(3), 20, 30),
+            // Invoked after the second add completed, still sees the old value.
+            (2, Op::Nop, Some(1), 40, 50),
+            (1, Op::Add(3), Some(6), 60, 70),
+        ]);
+        let violation = check::<Counter>(&history).unwrap_err();
+        let ops: Vec<(Op, Option<u64>)> = violation
+            .operations
+            .iter()
+            .map(|operation| (operation.op.clone(), operation.result))
+            .collect();
+        // The first read and the last add don't take part, the result of the first add doesn't matter.
+        assert_eq!(
+            ops,
+            vec![
+                (Op::Add(1), None),
+                (Op::Add(2), Some(3)),
+                (Op::Nop, Some(1))
+            ]
+        );
+        assert_eq!(violation.operations[1].invoked_at, 20);
+        assert!(violation.to_string().contains("result: unknown"));
+    }
+
+    #[test]
+    fn lost_update_should_be_caught_in_the_kv_store() {
+        let put = |value: &str| KvOp::Put {
+            key: b"key".to_vec(),
+            value: value.as_bytes().to_vec(),
+        };
+        let previous =
+            |value: Option<&str>| KvResult::Value(value.map(|value| value.as_bytes().to_vec()));
+        let linearizable = history(vec![
+            (1, put("a"), Some(previous(None)), 0, 10),
+            (2, put("b"), Some(previous(Some("a"))), 5, 20),
+            (
+                3,
+                KvOp::Get {
+                    key: b"key".to_vec(),
+                },
+                Some(previous(Some("b"))),
+                21,
+                30,
+            ),
+        ]);
+        assert!(check::<KvStore>(&linearizable).is_ok());
+
+        // Both puts claim to have replaced the missing key.
+        let lost_update = history(vec![
+            (1, put("a"), Some(previous(None)), 0, 10),
+            (2, put("b"), Some(previous(None)), 5, 20),
+        ]);
+        let violation = check::<KvStore>(&lost_update).unwrap_err();
+        assert_eq!(violation.operations.len(), 2);
+    }
+}
@@ -1,12 +1,420 @@
-use client::Op;
+use crate::{
+    checkpoint::Checkpoint,
+    replica::Replica,
+    stm::{Codec, StateMachine},
+};
+use std::{collections::BTreeSet, io, sync::atomic::Ordering};
 
-use crate::replica::Replica;
-use std::sync::atomic::Ordering;
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+// Checksum, parent, client id and request number.
+const HEADER_SIZE: usize = 8 + 8 + 8 + 8;
 
-impl Replica {
-    pub fn append_to_log(&self, op: Op) {
+/// What the committed entry does, either applies the op to the state machine
+/// or starts the next epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload<Op> {
+    Op(Op),
+    // Membership of the next epoch, no ops follow it in the current one.
+    Reconfiguration { replicas: Vec<usize> },
+}
+
+impl<Op: Codec> Payload<Op> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Payload::Op(op) => {
+                let mut bytes = vec![0u8];
+                bytes.extend_from_slice(&op.to_bytes());
+                bytes
+            }
+            Payload::Reconfiguration { replicas } => {
+                let mut bytes = Vec::with_capacity(1 + 4 + replicas.len() * 8);
+                bytes.push(1u8);
+                bytes.extend_from_slice(&(replicas.len() as u32).to_le_bytes());
+                for replica_id in replicas {
+                    bytes.extend_from_slice(&replica_id.to_le_bytes());
+                }
+                bytes
+            }
+        }
+    }
+
+    /// Fails on an unknown payload, a malformed op or a truncated membership.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<(Self, usize)> {
+        match bytes.first() {
+            Some(0) => {
+                let (op, size) =
+                    Op::from_bytes(&bytes[1..]).ok_or_else(|| malformed("malformed op"))?;
+                Ok((Payload::Op(op), 1 + size))
+            }
+            Some(1) if bytes.len() >= 5 => {
+                let count = u32::from_le_bytes(bytes[1..5].try_into().unwrap()) as usize;
+                let size = count
+                    .checked_mul(8)
+                    .and_then(|size| size.checked_add(5))
+                    .filter(|size| *size <= bytes.len())
+                    .ok_or_else(|| malformed("truncated membership"))?;
+                let replicas = bytes[5..size]
+                    .chunks_exact(8)
+                    .map(|chunk| usize::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect();
+                Ok((Payload::Reconfiguration { replicas }, size))
+            }
+            Some(1) => Err(malformed("truncated membership")),
+            Some(_) => Err(malformed("unknown payload")),
+            None => Err(malformed("missing payload")),
+        }
+    }
+}
+
+/// Client request as stored in the log, the client information is needed
+/// to rebuild the client table from the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry<Op> {
+    // Checksum of the parent, the header and the payload bytes, zero until the entry is sealed.
+    pub checksum: u64,
+    // Checksum of the previous entry in the log, it chains the entries together.
+    pub parent: u64,
+    pub client_id: usize,
+    pub request_number: usize,
+    pub payload: Payload<Op>,
+}
+
+impl<Op: Codec> LogEntry<Op> {
+    pub fn new(client_id: usize, request_number: usize, op: Op) -> Self {
+        Self::with_payload(client_id, request_number, Payload::Op(op))
+    }
+
+    pub fn reconfiguration(client_id: usize, request_number: usize, replicas: Vec<usize>) -> Self {
+        Self::with_payload(
+            client_id,
+            request_number,
+            Payload::Reconfiguration { replicas },
+        )
+    }
+
+    fn with_payload(client_id: usize, request_number: usize, payload: Payload<Op>) -> Self {
+        Self {
+            checksum: 0,
+            parent: 0,
+            client_id,
+            request_number,
+            payload,
+        }
+    }
+
+    /// Stands in for an entry, that couldn't be read back from the journal. It fails its checksum,
+    /// so it's repaired from a peer like any other corrupt entry.
+    pub fn unreadable() -> Self {
+        Self::with_payload(
+            0,
+            0,
+            Payload::Reconfiguration {
+                replicas: Vec::new(),
+            },
+        )
+    }
+
+    pub fn is_reconfiguration(&self) -> bool {
+        matches!(self.payload, Payload::Reconfiguration { .. })
+    }
+
+    /// Chains the entry to its parent, done by the primary before the entry is prepared.
+    pub fn seal(&mut self, parent: u64) {
+        self.parent = parent;
+        self.checksum = self.compute_checksum();
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.checksum == self.compute_checksum()
+    }
+
+    fn compute_checksum(&self) -> u64 {
+        checksum(&self.to_bytes()[8..])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let payload_bytes = self.payload.to_bytes();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + payload_bytes.len());
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.parent.to_le_bytes());
+        bytes.extend_from_slice(&self.client_id.to_le_bytes());
+        bytes.extend_from_slice(&self.request_number.to_le_bytes());
+        bytes.extend_from_slice(&payload_bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<(Self, usize)> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(malformed("truncated header"));
+        }
+        let checksum = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let parent = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let client_id = usize::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let request_number = usize::from_le_bytes(bytes[24..32].try_into().unwrap());
+        let (payload, size) = Payload::from_bytes(&bytes[HEADER_SIZE..])?;
+        let entry = Self {
+            checksum,
+            parent,
+            client_id,
+            request_number,
+            payload,
+        };
+        Ok((entry, HEADER_SIZE + size))
+    }
+}
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed log entry: {}", reason),
+    )
+}
+
+/// FNV-1a, good enough to catch torn writes and bit rot.
+pub(crate) fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Returns the op numbers of the entries following the checkpoint, that fail their checksum
+/// or don't chain to a valid predecessor. The entry following a corrupt one can't be checked
+/// against it, its own checksum vouches for it instead.
+pub fn find_faulty_ops<Op: Codec>(
+    log: &[LogEntry<Op>],
+    checkpoint: &Checkpoint,
+) -> BTreeSet<usize> {
+    let mut faulty_ops = BTreeSet::new();
+    let mut parent = Some(checkpoint.checksum);
+    for (index, entry) in log.iter().enumerate() {
+        let chained = parent.is_none_or(|parent| parent == entry.parent);
+        if entry.is_valid() && chained {
+            parent = Some(entry.checksum);
+        } else {
+            faulty_ops.insert(checkpoint.op_number + index + 1);
+            parent = None;
+        }
+    }
+    faulty_ops
+}
+
+impl<S: StateMachine> Replica<S> {
+    /// Entry with the given op number, the op has to follow the checkpoint.
+    pub fn entry(&self, op_number: usize) -> LogEntry<S::Op> {
+        self.log.borrow()[op_number - self.checkpoint_number() - 1].clone()
+    }
+
+    /// Entries following `op_number`, which can't precede the checkpoint.
+    pub fn log_suffix(&self, op_number: usize) -> Vec<LogEntry<S::Op>> {
+        self.log.borrow()[op_number - self.checkpoint_number()..].to_vec()
+    }
+
+    /// The entry is durable only after `sync_log`.
+    pub fn append_to_log(&self, entry: LogEntry<S::Op>) {
+        if let Some(journal) = &self.journal {
+            journal
+                .borrow_mut()
+                .append([&entry])
+                .expect("Failed to append to the journal");
+        }
         let mut log = self.log.borrow_mut();
-        log.push(op);
+        log.push(entry);
         self.op_number.fetch_add(1, Ordering::AcqRel);
     }
+
+    /// Has to be called before acknowledging the appended ops.
+    pub fn sync_log(&self) {
+        if let Some(journal) = &self.journal {
+            journal
+                .borrow_mut()
+                .sync()
+                .expect("Failed to sync the journal");
+        }
+    }
+
+    /// Removes the ops following `op_number` from the log.
+    pub fn truncate_log(&self, op_number: usize) {
+        self.faulty_ops
+            .borrow_mut()
+            .retain(|faulty_op_number| *faulty_op_number <= op_number);
+        if let Some(journal) = &self.journal {
+            journal
+                .borrow_mut()
+                .truncate(op_number)
+                .expect("Failed to truncate the journal");
+        }
+        self.log
+            .borrow_mut()
+            .truncate(op_number - self.checkpoint_number());
+        self.op_number.store(op_number, Ordering::Release);
+    }
+
+    /// Replaces the log with the one installed by a view change or recovery, `log` holds
+    /// the entries following the `checkpoint`. Only the suffix, that differs from our log
+    /// is rewritten on disk. The new log has been verified by the caller.
+    pub fn replace_log(&self, checkpoint: Checkpoint, log: Vec<LogEntry<S::Op>>) {
+        self.faulty_ops.borrow_mut().clear();
+        let checkpoint_number = self.checkpoint_number();
+        let log = if checkpoint.op_number > self.commit_number() {
+            // Our state is behind the checkpoint, it replaces the state along with the whole log.
+            self.install_checkpoint(checkpoint);
+            log
+        } else if checkpoint.op_number >= checkpoint_number {
+            // The ops between both checkpoints are committed, those are the same in both logs.
+            let mut prefix = self.log.borrow()[..checkpoint.op_number - checkpoint_number].to_vec();
+            prefix.extend(log);
+            prefix
+        } else {
+            log.into_iter()
+                .skip(checkpoint_number - checkpoint.op_number)
+                .collect()
+        };
+        let checkpoint_number = self.checkpoint_number();
+        if let Some(journal) = &self.journal {
+            let common = self
+                .log
+                .borrow()
+                .iter()
+                .zip(log.iter())
+                .take_while(|(current, new)| current == new)
+                .count();
+            let mut journal = journal.borrow_mut();
+            journal
+                .truncate(checkpoint_number + common)
+                .and_then(|_| journal.append(&log[common..]))
+                .and_then(|_| journal.sync())
+                .expect("Failed to replace the journal");
+        }
+        self.op_number
+            .store(checkpoint_number + log.len(), Ordering::Release);
+        *self.log.borrow_mut() = log;
+    }
+
+    /// Checksum of the last entry in the log, the parent of the next one.
+    pub fn head_checksum(&self) -> u64 {
+        self.log
+            .borrow()
+            .last()
+            .map(|entry| entry.checksum)
+            .unwrap_or_else(|| self.checkpoint.borrow().checksum)
+    }
+
+    /// Replaces a corrupt entry with its copy received from a peer.
+    /// Returns whether the copy fits between its neighbours. Only the entry following it
+    /// pins down the checksum of the copy, the head of the log has none, its copy is taken
+    /// only from a prepare of the view.
+    pub fn repair_log(&self, op_number: usize, entry: LogEntry<S::Op>, prepared: bool) -> bool {
+        let mut faulty_ops = self.faulty_ops.borrow_mut();
+        let checkpoint_number = self.checkpoint_number();
+        if !faulty_ops.contains(&op_number)
+            || op_number <= checkpoint_number
+            || op_number > self.op_number()
+            || !entry.is_valid()
+        {
+            return false;
+        }
+        let mut log = self.log.borrow_mut();
+        let index = op_number - checkpoint_number - 1;
+        let parent = match index {
+            0 => Some(self.checkpoint.borrow().checksum),
+            _ if faulty_ops.contains(&(op_number - 1)) => None,
+            _ => Some(log[index - 1].checksum),
+        };
+        let child = log
+            .get(index + 1)
+            .filter(|_| !faulty_ops.contains(&(op_number + 1)))
+            .map(|child| child.parent);
+        let pinned = prepared || child.is_some_and(|child| child == entry.checksum);
+        if parent.is_some_and(|parent| parent != entry.parent) || !pinned {
+            return false;
+        }
+        log[index] = entry;
+        faulty_ops.remove(&op_number);
+        if let Some(journal) = &self.journal {
+            // Entries are not overwritten in place, the suffix is rewritten instead.
+            let mut journal = journal.borrow_mut();
+            journal
+                .truncate(op_number - 1)
+                .and_then(|_| journal.append(&log[index..]))
+                .and_then(|_| journal.sync())
+                .expect("Failed to repair the journal");
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client::Op;
+
+    use super::*;
+
+    fn generate_chain(count: usize) -> Vec<LogEntry<Op>> {
+        let mut parent = 0;
+        (0..count)
+            .map(|request_number| {
+                let mut entry = LogEntry::new(1, request_number, Op::Add(69));
+                entry.seal(parent);
+                parent = entry.checksum;
+                entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sealed_entry_should_survive_the_round_trip() {
+        let entry = generate_chain(1).remove(0);
+        let (parsed, size) = LogEntry::from_bytes(&entry.to_bytes()).unwrap();
+        assert!(parsed.is_valid());
+        assert_eq!(size, entry.to_bytes().len());
+        assert_eq!(parsed, entry);
+    }
+
+    #[test]
+    fn reconfiguration_entry_should_survive_the_round_trip() {
+        let mut entry = LogEntry::<Op>::reconfiguration(1, 7, vec![0, 2, 3]);
+        entry.seal(42);
+        let (parsed, size) = LogEntry::<Op>::from_bytes(&entry.to_bytes()).unwrap();
+        assert!(parsed.is_valid());
+        assert!(parsed.is_reconfiguration());
+        assert_eq!(size, entry.to_bytes().len());
+        assert_eq!(parsed, entry);
+    }
+
+    #[test]
+    fn malformed_entry_should_fail_to_decode() {
+        let mut bytes = generate_chain(1).remove(0).to_bytes();
+        assert!(LogEntry::<Op>::from_bytes(&bytes[..HEADER_SIZE - 1]).is_err());
+        bytes[HEADER_SIZE] = 7;
+        assert!(LogEntry::<Op>::from_bytes(&bytes).is_err());
+        // Membership claiming more replicas, than there are bytes.
+        let mut entry = LogEntry::<Op>::reconfiguration(1, 7, vec![0, 2, 3]);
+        entry.seal(42);
+        let bytes = entry.to_bytes();
+        assert!(LogEntry::<Op>::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(!LogEntry::<Op>::unreadable().is_valid());
+    }
+
+    #[test]
+    fn corrupt_and_unchained_entries_should_be_flagged() {
+        let mut log = generate_chain(6);
+        assert!(find_faulty_ops(&log, &Checkpoint::default()).is_empty());
+
+        // Bit rot in the op of the second entry.
+        log[1].payload = Payload::Op(Op::Add(70));
+        // Valid entry, that doesn't belong after the fourth one.
+        log[4].seal(42);
+        let faulty_ops = find_faulty_ops(&log, &Checkpoint::default());
+        assert_eq!(faulty_ops, BTreeSet::from([2, 5]));
+
+        // Entries following a checkpoint.
+        let checkpoint = Checkpoint {
+            op_number: 2,
+            checksum: log[1].checksum,
+            ..Default::default()
+        };
+        let faulty_ops = find_faulty_ops(&log[2..], &checkpoint);
+        assert_eq!(faulty_ops, BTreeSet::from([5]));
+    }
 }
@@ -1,93 +1,316 @@
-use client::ADDRESSES;
+use action::Action;
+use client::{config::ClusterConfig, history::History};
+use counter::Counter;
+use kv::KvStore;
 use message::Message;
 use monoio::{
-    io::AsyncReadRentExt,
+    io::{AsyncReadRentExt, Splitable},
     net::{TcpListener, TcpStream},
 };
+use network::TcpNetwork;
 use replica::Replica;
 use replica_config::ReplicaConfig;
-use std::{rc::Rc, time::Duration};
+use status::Status;
+use std::{path::PathBuf, rc::Rc, time::Duration};
+use stm::StateMachine;
 
-const TWO_SECONDS: u64 = 2;
+const DEFAULT_CONFIG_PATH: &str = "cluster.conf";
+const DEFAULT_MODEL_CHECK_DEPTH: usize = 6;
 
+pub(crate) mod action;
+pub(crate) mod checkpoint;
 pub(crate) mod client_table;
+pub(crate) mod clock;
+pub(crate) mod counter;
+pub(crate) mod invariants;
+pub(crate) mod journal;
+pub(crate) mod kv;
+pub(crate) mod lease;
+pub(crate) mod linearizability;
 pub(crate) mod log;
 pub(crate) mod message;
+pub(crate) mod model_checker;
+pub(crate) mod network;
+pub(crate) mod pipeline;
 pub(crate) mod replica;
 pub(crate) mod replica_config;
+pub(crate) mod rng;
+pub(crate) mod simulator;
+pub(crate) mod stale_read;
 pub(crate) mod status;
 pub(crate) mod stm;
 
 fn main() {
-    let mut config = ReplicaConfig::default();
-    let mut threads = Vec::new();
+    let args: Vec<String> = std::env::args().collect();
+    let flag_value = |flag: &str| args.iter().skip_while(|arg| *arg != flag).nth(1).cloned();
+    // With `--simulate`, the replicas run in the deterministic simulator instead,
+    // the seed is picked at random unless given.
+    if args.iter().any(|arg| arg == "--simulate") {
+        let seed = flag_value("--simulate")
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(|| rng::Rng::from_entropy().next_u64());
+        match simulator::simulate(seed) {
+            Ok(outcome) => println!("Simulation passed: {:?}", outcome),
+            Err(failure) => {
+                eprintln!("{}", failure);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // With `--model-check [depth]`, every interleaving of the messages and timeouts
+    // of a small cluster is explored up to the depth instead.
+    if args.iter().any(|arg| arg == "--model-check") {
+        let max_depth = flag_value("--model-check")
+            .and_then(|depth| depth.parse().ok())
+            .unwrap_or(DEFAULT_MODEL_CHECK_DEPTH);
+        match model_checker::ModelChecker::new(max_depth).run() {
+            Ok(report) => println!(
+                "Model check passed, explored {} states up to depth: {}",
+                report.states, report.max_depth
+            ),
+            Err(violation) => {
+                eprintln!("{}", violation);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // With `--check-history <paths...>`, the histories recorded by the clients
+    // are checked for linearizability instead, against the key-value store with `--kv`.
+    if args.iter().any(|arg| arg == "--check-history") {
+        let paths: Vec<&String> = args
+            .iter()
+            .skip_while(|arg| *arg != "--check-history")
+            .skip(1)
+            .take_while(|arg| !arg.starts_with("--"))
+            .collect();
+        let linearizable = if args.iter().any(|arg| arg == "--kv") {
+            check_history::<KvStore>(&paths)
+        } else {
+            check_history::<Counter>(&paths)
+        };
+        if !linearizable {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config_path = flag_value("--config").unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+    let cluster = ClusterConfig::load(&config_path).expect("Failed to load the cluster config");
+    let config = ReplicaConfig::from_cluster_config(&cluster).expect("Invalid cluster config");
+    // With `--kv`, the replicas run the key-value store instead of the counter.
+    let kv = args.iter().any(|arg| arg == "--kv");
 
-    for (id, addr) in ADDRESSES.into_iter().enumerate() {
-        config.append_new(id, addr);
+    // With `--replica-id`, the process runs the single replica, which keeps its log
+    // and view state in `--data-dir` and runs the recovery protocol with `--recover`.
+    if let Some(id) = flag_value("--replica-id") {
+        let id: usize = id.parse().expect("Invalid replica id");
+        assert!(
+            id < config.addresses.len(),
+            "Replica: {} is not in the config",
+            id
+        );
+        let data_dir = flag_value("--data-dir").map(PathBuf::from);
+        let recover = args.iter().any(|arg| arg == "--recover");
+        run(id, config, data_dir, recover, kv);
+        return;
     }
 
-    for (id, addr) in ADDRESSES.into_iter().enumerate() {
+    // Otherwise every replica of the config runs in its own thread of this process.
+    // Replicas listed after `--recover` have lost their state,
+    // they run the recovery protocol before taking part in the cluster.
+    let recovering: Vec<usize> = args
+        .iter()
+        .skip_while(|arg| *arg != "--recover")
+        .skip(1)
+        .filter_map(|arg| arg.parse().ok())
+        .collect();
+    // With `--data-dir`, every replica keeps its log and view state in a subdirectory of it,
+    // so that it can be restarted without losing the acknowledged ops.
+    let data_dir: Option<PathBuf> = flag_value("--data-dir").map(PathBuf::from);
+
+    let mut threads = Vec::new();
+    for id in 0..config.addresses.len() {
         let builder = std::thread::Builder::new().name(format!("replica-{id}"));
         let config = config.clone();
+        let recover = recovering.contains(&id);
+        let data_dir = data_dir
+            .as_ref()
+            .map(|data_dir| data_dir.join(format!("replica-{id}")));
         let thread = builder
-            .spawn(move || {
-                let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
-                    .with_entries(256)
-                    .enable_timer()
-                    .build()
-                    .unwrap();
-                rt.block_on(async {
-                    let replica = Rc::new(Replica::new(id, config));
-                    println!("Created node with addr: {}, id: {}", addr, id);
-                    let listener = TcpListener::bind(addr).expect("Failed to bind to socketerino");
-                    loop {
-                        let replica = replica.clone();
-                        match listener.accept().await {
-                            Ok((mut stream, _)) => {
-                                monoio::spawn(async move {
-                                    handle_connection(&mut stream, replica).await
-                                });
-                            }
-                            Err(e) => {
-                                eprintln!("Error when accepting incomming connection: {}", e);
-                            }
-                        }
-                    }
-                });
-            })
+            .spawn(move || run(id, config, data_dir, recover, kv))
             .unwrap();
         threads.push(thread);
     }
     let _: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
 }
 
-async fn handle_connection(stream: &mut TcpStream, replica: Rc<Replica>) {
+fn check_history<S: StateMachine>(paths: &[&String]) -> bool {
+    let history = History::<S::Op, S::Result>::load(paths).expect("Failed to load the history");
+    match linearizability::check::<S>(&history) {
+        Ok(()) => {
+            println!(
+                "History of {} ops is linearizable",
+                history.operations.len()
+            );
+            true
+        }
+        Err(violation) => {
+            eprintln!("{}", violation);
+            false
+        }
+    }
+}
+
+fn run(id: usize, config: ReplicaConfig, data_dir: Option<PathBuf>, recover: bool, kv: bool) {
+    let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
+        .with_entries(256)
+        .enable_timer()
+        .build()
+        .unwrap();
+    if kv {
+        rt.block_on(run_replica::<KvStore>(id, config, data_dir, recover));
+    } else {
+        rt.block_on(run_replica::<Counter>(id, config, data_dir, recover));
+    }
+}
+
+async fn run_replica<S: StateMachine + 'static>(
+    id: usize,
+    config: ReplicaConfig,
+    data_dir: Option<PathBuf>,
+    recover: bool,
+) {
+    let addr = config.get_replica_address(id);
+    let tick_interval = config.tick_interval;
+    let network = Rc::new(TcpNetwork::new(config.addresses.clone()));
+    let replica = match data_dir {
+        Some(data_dir) => Replica::<S>::open(id, config, data_dir)
+            .expect("Failed to restore the replica from disk"),
+        None => Replica::<S>::new(id, config),
+    };
+    let replica = Rc::new(replica);
+    println!("Created node with addr: {}, id: {}", addr, id);
+    let listener = TcpListener::bind(addr).expect("Failed to bind to socketerino");
+    let ticker = replica.clone();
+    let ticker_network = network.clone();
+    monoio::spawn(async move {
+        loop {
+            monoio::time::sleep(tick_interval).await;
+            let actions = ticker.on_timer();
+            execute(&ticker, &ticker_network, actions).await;
+        }
+    });
+    if recover || *replica.status.borrow() == Status::Recovery {
+        let actions = replica.recover();
+        execute(&replica, &network, actions).await;
+    }
+    loop {
+        let replica = replica.clone();
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let network = network.clone();
+                monoio::spawn(async move { handle_connection(stream, replica, network).await });
+            }
+            Err(e) => {
+                eprintln!("Error when accepting incomming connection: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_connection<S: StateMachine + 'static>(
+    stream: TcpStream,
+    replica: Rc<Replica<S>>,
+    network: Rc<TcpNetwork>,
+) {
+    // Clients send their requests and expect the replies over the same connection.
+    let (mut stream, writer) = stream.into_split();
+    let mut writer = Some(writer);
     loop {
         let init_buf = vec![0u8; 4];
-        let read_fut = stream.read_exact(init_buf);
-        let result = monoio::time::timeout(Duration::from_secs(TWO_SECONDS), read_fut).await;
-        match result {
-            Ok(val) => {
-                let (res, init_buf) = val;
-                if let Err(e) = res {
-                    if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                        break;
-                    }
-                }
-                let len = u32::from_le_bytes(init_buf[..].try_into().unwrap());
-                let buf = vec![0u8; len as _];
-                let (res, buf) = stream.read_exact(buf).await;
-                res.unwrap();
+        let (res, init_buf) = stream.read_exact(init_buf).await;
+        if let Err(e) = res {
+            if e.kind() != std::io::ErrorKind::UnexpectedEof {
+                eprintln!("Error when reading from connection: {}", e);
+            }
+            break;
+        }
+        let len = u32::from_le_bytes(init_buf[..].try_into().unwrap());
+        let buf = vec![0u8; len as _];
+        let (res, buf) = stream.read_exact(buf).await;
+        if let Err(e) = res {
+            eprintln!("Error when reading from connection: {}", e);
+            break;
+        }
 
-                let message = Message::parse_message(&buf);
-                println!("Received message: {:?}", message);
-                replica.on_message(message).await;
+        let Some((envelope, message)) = Message::<S::Op, S::Result>::parse_envelope(&buf) else {
+            eprintln!("Dropped malformed message");
+            continue;
+        };
+        // Messages of the replicas come in the envelope, those of the clients don't.
+        if let Some(envelope) = envelope {
+            let actions = replica.on_peer_message(envelope, message);
+            execute(&replica, &network, actions).await;
+            continue;
+        }
+        // The rest of the messages can only come from the replicas.
+        let client_id = match &message {
+            Message::Request { client_id, .. }
+            | Message::Read { client_id, .. }
+            | Message::StaleRead { client_id, .. }
+            | Message::Reconfiguration { client_id, .. } => *client_id,
+            message => {
+                eprintln!("Dropped message without an envelope: {:?}", message);
+                continue;
             }
-            Err(_) => {
-                let thread = std::thread::current();
-                println!("Ticking timer on thread: {:?}", thread);
-                replica.on_timer().await;
+        };
+        if let Some(writer) = writer.take() {
+            network.register_client_connection(client_id, writer);
+        }
+        let actions = replica.on_message(message);
+        execute(&replica, &network, actions).await;
+    }
+}
+
+/// Carries out the actions of the replica, in order.
+async fn execute<S: StateMachine + 'static>(
+    replica: &Rc<Replica<S>>,
+    network: &Rc<TcpNetwork>,
+    actions: Vec<Action<S::Op, S::Result>>,
+) {
+    for action in actions {
+        match action {
+            Action::SendToReplica {
+                replica_id,
+                envelope,
+                message,
+            } => {
+                network
+                    .send_to_replica(replica_id, message.to_envelope_bytes(envelope))
+                    .await;
+            }
+            Action::SendToClient { client_id, bytes } => {
+                network.send_to_client(client_id, bytes).await;
             }
+            Action::Commit { .. } => {}
+            Action::ScheduleFlush {
+                batch_number,
+                delay,
+            } => schedule_flush(replica.clone(), network.clone(), batch_number, delay),
         }
     }
 }
+
+fn schedule_flush<S: StateMachine + 'static>(
+    replica: Rc<Replica<S>>,
+    network: Rc<TcpNetwork>,
+    batch_number: usize,
+    delay: Duration,
+) {
+    monoio::spawn(async move {
+        monoio::time::sleep(delay).await;
+        let actions = replica.on_batch_window(batch_number);
+        execute(&replica, &network, actions).await;
+    });
+}
@@ -1,4 +1,4 @@
-use client::{Op, MAX_OP_SIZE};
+use crate::{checkpoint::Checkpoint, log::LogEntry, stm::Codec};
 
 // Discriminator table (singular byte)
 // 1 => Request
@@ -10,37 +10,69 @@ use client::{Op, MAX_OP_SIZE};
 // 7 => StartView
 // 8 => GetState
 // 9 => NewState
-// TODO: Add variants to handle state transfers.
+// 10 => Recovery
+// 11 => RecoveryResponse
+// 12 => Reply
+// 13 => RequestPrepare
+// 14 => RequestRepair
+// 15 => Repair
+// 16 => CommitOk
+// 17 => Read
+// 18 => StaleRead
+// 19 => Reconfiguration
+// 20 => StartEpoch
+// 21 => EpochStarted
+// 22 => StaleRequest
+//
+// Messages between the replicas are wrapped in an envelope with discriminator 0,
+// followed by the cluster id, the epoch number and the id of the sender.
 
-#[derive(Debug, PartialEq)]
-pub enum Message<Op: Clone> {
+// Envelope discriminator, cluster id, epoch number and replica id.
+const ENVELOPE_SIZE: usize = 1 + 8 + 8 + 8;
+
+/// Sender of a message between the replicas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub cluster_id: u64,
+    pub epoch_number: usize,
+    pub replica_id: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message<Op: Clone, Result: Clone> {
     Request {
         client_id: usize,
         request_number: usize,
         op: Op,
     },
+    // `op_number` is the op number of the last entry in the batch.
     Prepare {
         view_number: usize,
-        op: Op,
         op_number: usize,
         commit_number: usize,
+        entries: Vec<LogEntry<Op>>,
     },
     PrepareOk {
         view_number: usize,
         op_number: usize,
+        replica_id: usize,
     },
+    // Heartbeat of the primary, `timestamp` is echoed back in the `CommitOk` to renew the lease.
     Commit {
         view_number: usize,
         commit_number: usize,
+        timestamp: u64,
     },
     GetState {
         replica_id: usize,
         view_number: usize,
         op_number: usize,
     },
+    // The `checkpoint` is sent only when the log suffix doesn't reach back to the requested op.
     NewState {
         view_number: usize,
-        log: Vec<Op>,
+        checkpoint: Option<Checkpoint>,
+        log: Vec<LogEntry<Op>>,
         op_number: usize,
         commit_number: usize,
     },
@@ -50,41 +82,174 @@ pub enum Message<Op: Clone> {
     },
     DoViewChange {
         view_number: usize,
+        last_normal_view: usize,
         op_number: usize,
         replica_id: usize,
         commit_number: usize,
-        log: Vec<Op>,
+        // The `log` holds the entries following the checkpoint.
+        checkpoint: Checkpoint,
+        log: Vec<LogEntry<Op>>,
     },
     StartView {
         view_number: usize,
         op_number: usize,
         replica_id: usize,
         commit_number: usize,
-        log: Vec<Op>,
+        checkpoint: Checkpoint,
+        log: Vec<LogEntry<Op>>,
+    },
+    Recovery {
+        replica_id: usize,
+        nonce: u64,
+    },
+    // Only the primary fills in the `log`, `op_number` and `commit_number`,
+    // backups respond with an empty log.
+    RecoveryResponse {
+        view_number: usize,
+        nonce: u64,
+        replica_id: usize,
+        checkpoint: Checkpoint,
+        log: Vec<LogEntry<Op>>,
+        op_number: usize,
+        commit_number: usize,
+    },
+    // `commit_number` lets the client ask for a backup read, that observes the reply.
+    Reply {
+        view_number: usize,
+        request_number: usize,
+        commit_number: usize,
+        result: Result,
+    },
+    // Sent instead of the reply, once the session of the client has moved past the request.
+    // The client restarted its request numbers, or another one shares its id.
+    StaleRequest {
+        request_number: usize,
+        latest_request_number: usize,
+    },
+    // Asks for the ops `from_op_number..=to_op_number`, those are sent back as a `Prepare`.
+    RequestPrepare {
+        replica_id: usize,
+        view_number: usize,
+        from_op_number: usize,
+        to_op_number: usize,
+    },
+    // Asks for the copy of an entry, that failed the verification.
+    // Only answered within the same view in the normal status.
+    RequestRepair {
+        replica_id: usize,
+        view_number: usize,
+        op_number: usize,
+    },
+    Repair {
+        view_number: usize,
+        op_number: usize,
+        entry: LogEntry<Op>,
+    },
+    CommitOk {
+        view_number: usize,
+        replica_id: usize,
+        timestamp: u64,
+    },
+    // Read-only request, answered by the primary without going through the log.
+    Read {
+        client_id: usize,
+        request_number: usize,
+        op: Op,
+    },
+    // Read-only request, answered by any replica, that has committed `min_commit_number`
+    // and heard from the primary within `max_staleness_millis`.
+    StaleRead {
+        client_id: usize,
+        request_number: usize,
+        min_commit_number: usize,
+        max_staleness_millis: u64,
+        op: Op,
+    },
+    // Replaces the members of `epoch_number` with the `replicas`, the reply carries the new epoch.
+    Reconfiguration {
+        client_id: usize,
+        request_number: usize,
+        epoch_number: usize,
+        replicas: Vec<usize>,
+    },
+    // Tells a replica, that's behind, to fetch the state of the new epoch from `replica_id`.
+    StartEpoch {
+        epoch_number: usize,
+        view_number: usize,
+        replica_id: usize,
+    },
+    // Sent by the members of the new epoch to the leaving replicas, which shut down
+    // once the quorum has started it.
+    EpochStarted {
+        epoch_number: usize,
+        replica_id: usize,
     },
 }
 
-impl Message<Op> {
-    pub fn parse_message(buf: &[u8]) -> Self {
-        fn parse_op_bytes(buf: &[u8]) -> Vec<Op> {
+fn usize_at(buf: &[u8], position: usize) -> Option<usize> {
+    Some(usize::from_le_bytes(
+        buf.get(position..position + 8)?.try_into().unwrap(),
+    ))
+}
+
+fn u64_at(buf: &[u8], position: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        buf.get(position..position + 8)?.try_into().unwrap(),
+    ))
+}
+
+impl<Op: Codec + Clone, Result: Codec + Clone> Message<Op, Result> {
+    /// Parses the message along with its envelope, the messages of the clients come without one.
+    /// `None` if the message is cut short or malformed.
+    pub fn parse_envelope(buf: &[u8]) -> Option<(Option<Envelope>, Self)> {
+        if *buf.first()? != 0 {
+            return Some((None, Self::parse_message(buf)?));
+        }
+        let envelope = Envelope {
+            cluster_id: u64_at(buf, 1)?,
+            epoch_number: usize_at(buf, 9)?,
+            replica_id: usize_at(buf, 17)?,
+        };
+        let message = Self::parse_message(&buf[ENVELOPE_SIZE..])?;
+        Some((Some(envelope), message))
+    }
+
+    pub fn to_envelope_bytes(&self, envelope: Envelope) -> Vec<u8> {
+        let message_bytes = self.to_bytes();
+        let length = ENVELOPE_SIZE + message_bytes.len() - 4;
+        let mut bytes = Vec::with_capacity(length + 4);
+        bytes.extend_from_slice(&(length as u32).to_le_bytes());
+        bytes.push(0u8);
+        bytes.extend_from_slice(&envelope.cluster_id.to_le_bytes());
+        bytes.extend_from_slice(&envelope.epoch_number.to_le_bytes());
+        bytes.extend_from_slice(&envelope.replica_id.to_le_bytes());
+        bytes.extend_from_slice(&message_bytes[4..]);
+        bytes
+    }
+
+    /// The clients send any bytes they like, a malformed message is rejected, not trusted.
+    pub fn parse_message(buf: &[u8]) -> Option<Self> {
+        // An entry that fails to decode rejects the whole message, one that decodes but fails
+        // its checksum is left for the replica to mark as faulty.
+        fn parse_log_bytes<Op: Codec>(buf: &[u8]) -> Option<Vec<LogEntry<Op>>> {
             let mut position = 0;
             let mut log = Vec::new();
 
             while position < buf.len() {
-                let (op, size) = Op::from_bytes(&buf[position..]);
-                log.push(op);
+                let (entry, size) = LogEntry::from_bytes(&buf[position..]).ok()?;
+                log.push(entry);
                 position += size;
             }
-            log
+            Some(log)
         }
 
-        let discriminator = buf[0];
-        match discriminator {
+        let discriminator = *buf.first()?;
+        let message = match discriminator {
             1 => {
-                let client_id = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let request_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
+                let client_id = usize_at(buf, 1)?;
+                let request_number = usize_at(buf, 9)?;
                 let remainder = &buf[17..];
-                let (op, _) = Op::from_bytes(remainder);
+                let (op, _) = Op::from_bytes(remainder)?;
 
                 Message::Request {
                     client_id,
@@ -93,78 +258,88 @@ impl Message<Op> {
                 }
             }
             2 => {
-                let view_number = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let commit_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
-                let op_number = usize::from_le_bytes(buf[17..25].try_into().unwrap());
+                let view_number = usize_at(buf, 1)?;
+                let commit_number = usize_at(buf, 9)?;
+                let op_number = usize_at(buf, 17)?;
                 let remainder = &buf[25..];
-                let (op, _) = Op::from_bytes(remainder);
+                let entries = parse_log_bytes(remainder)?;
                 Message::Prepare {
                     view_number,
                     commit_number,
                     op_number,
-                    op,
+                    entries,
                 }
             }
             3 => {
-                let view_number = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let op_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
+                let view_number = usize_at(buf, 1)?;
+                let op_number = usize_at(buf, 9)?;
+                let replica_id = usize_at(buf, 17)?;
                 Message::PrepareOk {
                     view_number,
                     op_number,
+                    replica_id,
                 }
             }
             4 => {
-                let view_number = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let commit_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
+                let view_number = usize_at(buf, 1)?;
+                let commit_number = usize_at(buf, 9)?;
+                let timestamp = u64_at(buf, 17)?;
                 Message::Commit {
                     view_number,
                     commit_number,
+                    timestamp,
                 }
             }
             5 => {
-                let view_number = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let replica_id = usize::from_le_bytes(buf[9..17].try_into().unwrap());
+                let view_number = usize_at(buf, 1)?;
+                let replica_id = usize_at(buf, 9)?;
                 Message::StartViewChange {
                     view_number,
                     replica_id,
                 }
             }
             6 => {
-                let view_number = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let op_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
-                let replica_id = usize::from_le_bytes(buf[17..25].try_into().unwrap());
-                let commit_number = usize::from_le_bytes(buf[25..33].try_into().unwrap());
+                let view_number = usize_at(buf, 1)?;
+                let last_normal_view = usize_at(buf, 9)?;
+                let op_number = usize_at(buf, 17)?;
+                let replica_id = usize_at(buf, 25)?;
+                let commit_number = usize_at(buf, 33)?;
 
-                let remainder = &buf[33..];
-                let log = parse_op_bytes(remainder);
+                let (checkpoint, size) = Checkpoint::from_bytes(&buf[41..])?;
+                let remainder = &buf[41 + size..];
+                let log = parse_log_bytes(remainder)?;
                 Message::DoViewChange {
                     view_number,
+                    last_normal_view,
                     op_number,
                     replica_id,
                     commit_number,
+                    checkpoint,
                     log,
                 }
             }
             7 => {
-                let view_number = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let op_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
-                let replica_id = usize::from_le_bytes(buf[17..25].try_into().unwrap());
-                let commit_number = usize::from_le_bytes(buf[25..33].try_into().unwrap());
+                let view_number = usize_at(buf, 1)?;
+                let op_number = usize_at(buf, 9)?;
+                let replica_id = usize_at(buf, 17)?;
+                let commit_number = usize_at(buf, 25)?;
 
-                let remainder = &buf[33..];
-                let log = parse_op_bytes(remainder);
+                let (checkpoint, size) = Checkpoint::from_bytes(&buf[33..])?;
+                let remainder = &buf[33 + size..];
+                let log = parse_log_bytes(remainder)?;
                 Message::StartView {
                     view_number,
                     op_number,
                     replica_id,
                     commit_number,
+                    checkpoint,
                     log,
                 }
             }
             8 => {
-                let replica_id = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let view_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
-                let op_number = usize::from_le_bytes(buf[17..25].try_into().unwrap());
+                let replica_id = usize_at(buf, 1)?;
+                let view_number = usize_at(buf, 9)?;
+                let op_number = usize_at(buf, 17)?;
                 Message::GetState {
                     replica_id,
                     view_number,
@@ -172,20 +347,173 @@ impl Message<Op> {
                 }
             }
             9 => {
-                let view_number = usize::from_le_bytes(buf[1..9].try_into().unwrap());
-                let op_number = usize::from_le_bytes(buf[9..17].try_into().unwrap());
-                let commit_number = usize::from_le_bytes(buf[17..25].try_into().unwrap());
-                let remainder = &buf[25..];
-                let log = parse_op_bytes(buf);
+                let view_number = usize_at(buf, 1)?;
+                let op_number = usize_at(buf, 9)?;
+                let commit_number = usize_at(buf, 17)?;
+                let (checkpoint, size) = match *buf.get(25)? {
+                    0 => (None, 0),
+                    _ => {
+                        let (checkpoint, size) = Checkpoint::from_bytes(&buf[26..])?;
+                        (Some(checkpoint), size)
+                    }
+                };
+                let remainder = &buf[26 + size..];
+                let log = parse_log_bytes(remainder)?;
                 Message::NewState {
                     view_number,
+                    checkpoint,
                     op_number,
                     commit_number,
                     log,
                 }
             }
-            _ => unreachable!(),
-        }
+            10 => {
+                let replica_id = usize_at(buf, 1)?;
+                let nonce = u64_at(buf, 9)?;
+                Message::Recovery { replica_id, nonce }
+            }
+            11 => {
+                let view_number = usize_at(buf, 1)?;
+                let nonce = u64_at(buf, 9)?;
+                let replica_id = usize_at(buf, 17)?;
+                let op_number = usize_at(buf, 25)?;
+                let commit_number = usize_at(buf, 33)?;
+
+                let (checkpoint, size) = Checkpoint::from_bytes(&buf[41..])?;
+                let remainder = &buf[41 + size..];
+                let log = parse_log_bytes(remainder)?;
+                Message::RecoveryResponse {
+                    view_number,
+                    nonce,
+                    replica_id,
+                    checkpoint,
+                    log,
+                    op_number,
+                    commit_number,
+                }
+            }
+            12 => {
+                let view_number = usize_at(buf, 1)?;
+                let request_number = usize_at(buf, 9)?;
+                let commit_number = usize_at(buf, 17)?;
+                let (result, _) = Result::from_bytes(&buf[25..])?;
+                Message::Reply {
+                    view_number,
+                    request_number,
+                    commit_number,
+                    result,
+                }
+            }
+            13 => {
+                let replica_id = usize_at(buf, 1)?;
+                let view_number = usize_at(buf, 9)?;
+                let from_op_number = usize_at(buf, 17)?;
+                let to_op_number = usize_at(buf, 25)?;
+                Message::RequestPrepare {
+                    replica_id,
+                    view_number,
+                    from_op_number,
+                    to_op_number,
+                }
+            }
+            14 => {
+                let replica_id = usize_at(buf, 1)?;
+                let view_number = usize_at(buf, 9)?;
+                let op_number = usize_at(buf, 17)?;
+                Message::RequestRepair {
+                    replica_id,
+                    view_number,
+                    op_number,
+                }
+            }
+            15 => {
+                let view_number = usize_at(buf, 1)?;
+                let op_number = usize_at(buf, 9)?;
+                let (entry, _) = LogEntry::from_bytes(&buf[17..]).ok()?;
+                Message::Repair {
+                    view_number,
+                    op_number,
+                    entry,
+                }
+            }
+            16 => {
+                let view_number = usize_at(buf, 1)?;
+                let replica_id = usize_at(buf, 9)?;
+                let timestamp = u64_at(buf, 17)?;
+                Message::CommitOk {
+                    view_number,
+                    replica_id,
+                    timestamp,
+                }
+            }
+            17 => {
+                let client_id = usize_at(buf, 1)?;
+                let request_number = usize_at(buf, 9)?;
+                let (op, _) = Op::from_bytes(&buf[17..])?;
+                Message::Read {
+                    client_id,
+                    request_number,
+                    op,
+                }
+            }
+            18 => {
+                let client_id = usize_at(buf, 1)?;
+                let request_number = usize_at(buf, 9)?;
+                let min_commit_number = usize_at(buf, 17)?;
+                let max_staleness_millis = u64_at(buf, 25)?;
+                let (op, _) = Op::from_bytes(&buf[33..])?;
+                Message::StaleRead {
+                    client_id,
+                    request_number,
+                    min_commit_number,
+                    max_staleness_millis,
+                    op,
+                }
+            }
+            19 => {
+                let client_id = usize_at(buf, 1)?;
+                let request_number = usize_at(buf, 9)?;
+                let epoch_number = usize_at(buf, 17)?;
+                let replicas = buf[25..]
+                    .chunks_exact(8)
+                    .map(|chunk| usize::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect();
+                Message::Reconfiguration {
+                    client_id,
+                    request_number,
+                    epoch_number,
+                    replicas,
+                }
+            }
+            20 => {
+                let epoch_number = usize_at(buf, 1)?;
+                let view_number = usize_at(buf, 9)?;
+                let replica_id = usize_at(buf, 17)?;
+                Message::StartEpoch {
+                    epoch_number,
+                    view_number,
+                    replica_id,
+                }
+            }
+            21 => {
+                let epoch_number = usize_at(buf, 1)?;
+                let replica_id = usize_at(buf, 9)?;
+                Message::EpochStarted {
+                    epoch_number,
+                    replica_id,
+                }
+            }
+            22 => {
+                let request_number = usize_at(buf, 1)?;
+                let latest_request_number = usize_at(buf, 9)?;
+                Message::StaleRequest {
+                    request_number,
+                    latest_request_number,
+                }
+            }
+            _ => return None,
+        };
+        Some(message)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -209,13 +537,13 @@ impl Message<Op> {
             }
             Message::Prepare {
                 view_number,
-                op,
                 op_number,
                 commit_number,
+                entries,
             } => {
-                let op_bytes = op.to_bytes();
-                let op_len = op_bytes.len();
-                let length = 1 + 8 + 8 + 8 + op_len;
+                let entries_bytes: Vec<u8> =
+                    entries.iter().flat_map(|entry| entry.to_bytes()).collect();
+                let length = 1 + 8 + 8 + 8 + entries_bytes.len();
                 let discriminator = 2u8;
                 let mut bytes = Vec::with_capacity(length + 4);
                 bytes.extend_from_slice(&(length as u32).to_le_bytes());
@@ -223,34 +551,37 @@ impl Message<Op> {
                 bytes.extend_from_slice(&view_number.to_le_bytes());
                 bytes.extend_from_slice(&commit_number.to_le_bytes());
                 bytes.extend_from_slice(&op_number.to_le_bytes());
-                bytes.extend_from_slice(&op_bytes);
+                bytes.extend(entries_bytes);
                 bytes
             }
             Message::PrepareOk {
                 view_number,
                 op_number,
+                replica_id,
             } => {
-                let length = 1 + 8 + 8;
+                let length = 1 + 8 + 8 + 8;
                 let discriminator = 3u8;
                 let mut bytes = Vec::with_capacity(length + 4);
                 bytes.extend_from_slice(&(length as u32).to_le_bytes());
                 bytes.extend_from_slice(&discriminator.to_le_bytes());
                 bytes.extend_from_slice(&view_number.to_le_bytes());
                 bytes.extend_from_slice(&op_number.to_le_bytes());
-
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
                 bytes
             }
             Message::Commit {
                 view_number,
                 commit_number,
+                timestamp,
             } => {
-                let length = 1 + 8 + 8;
+                let length = 1 + 8 + 8 + 8;
                 let discriminator = 4u8;
                 let mut bytes = Vec::with_capacity(length + 4);
                 bytes.extend_from_slice(&(length as u32).to_le_bytes());
                 bytes.extend_from_slice(&discriminator.to_le_bytes());
                 bytes.extend_from_slice(&view_number.to_le_bytes());
                 bytes.extend_from_slice(&commit_number.to_le_bytes());
+                bytes.extend_from_slice(&timestamp.to_le_bytes());
 
                 bytes
             }
@@ -269,22 +600,27 @@ impl Message<Op> {
             }
             Message::DoViewChange {
                 view_number,
+                last_normal_view,
                 op_number,
                 replica_id,
                 commit_number,
+                checkpoint,
                 log,
             } => {
-                let length = 1 + 8 + 8 + 8 + 8 + log.len() * MAX_OP_SIZE;
+                let checkpoint_bytes = checkpoint.to_bytes();
+                let log_bytes: Vec<u8> = log.iter().flat_map(|entry| entry.to_bytes()).collect();
+                let length = 1 + 8 + 8 + 8 + 8 + 8 + checkpoint_bytes.len() + log_bytes.len();
                 let mut bytes = Vec::with_capacity(length + 4);
                 let discriminator = 6u8;
                 bytes.extend_from_slice(&(length as u32).to_le_bytes());
                 bytes.extend_from_slice(&discriminator.to_le_bytes());
                 bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&last_normal_view.to_le_bytes());
                 bytes.extend_from_slice(&op_number.to_le_bytes());
                 bytes.extend_from_slice(&replica_id.to_le_bytes());
                 bytes.extend_from_slice(&commit_number.to_le_bytes());
-                let op_bytes = log.iter().flat_map(|op| op.to_bytes());
-                bytes.extend(op_bytes);
+                bytes.extend(checkpoint_bytes);
+                bytes.extend(log_bytes);
                 bytes
             }
             Message::StartView {
@@ -292,10 +628,13 @@ impl Message<Op> {
                 op_number,
                 replica_id,
                 commit_number,
+                checkpoint,
                 log,
             } => {
-                let length = 1 + 8 + 8 + 8 + 8 + log.len() * MAX_OP_SIZE;
-                let mut bytes = Vec::with_capacity(length);
+                let checkpoint_bytes = checkpoint.to_bytes();
+                let log_bytes: Vec<u8> = log.iter().flat_map(|entry| entry.to_bytes()).collect();
+                let length = 1 + 8 + 8 + 8 + 8 + checkpoint_bytes.len() + log_bytes.len();
+                let mut bytes = Vec::with_capacity(length + 4);
                 let discriminator = 7u8;
                 bytes.extend_from_slice(&(length as u32).to_le_bytes());
                 bytes.extend_from_slice(&discriminator.to_le_bytes());
@@ -303,8 +642,8 @@ impl Message<Op> {
                 bytes.extend_from_slice(&op_number.to_le_bytes());
                 bytes.extend_from_slice(&replica_id.to_le_bytes());
                 bytes.extend_from_slice(&commit_number.to_le_bytes());
-                let op_bytes = log.iter().flat_map(|op| op.to_bytes());
-                bytes.extend(op_bytes);
+                bytes.extend(checkpoint_bytes);
+                bytes.extend(log_bytes);
                 bytes
             }
             Message::GetState {
@@ -313,7 +652,7 @@ impl Message<Op> {
                 op_number,
             } => {
                 let length = 1 + 8 + 8 + 8;
-                let mut bytes = Vec::with_capacity(length);
+                let mut bytes = Vec::with_capacity(length + 4);
                 let discriminator = 8u8;
                 bytes.extend_from_slice(&(length as u32).to_le_bytes());
                 bytes.extend_from_slice(&discriminator.to_le_bytes());
@@ -324,20 +663,239 @@ impl Message<Op> {
             }
             Message::NewState {
                 view_number,
+                checkpoint,
                 log,
                 op_number,
                 commit_number,
             } => {
-                let length = 1 + 8 + 8 + 8 + log.len() * MAX_OP_SIZE;
-                let mut bytes = Vec::with_capacity(length);
+                let checkpoint_bytes = checkpoint
+                    .as_ref()
+                    .map(|checkpoint| checkpoint.to_bytes())
+                    .unwrap_or_default();
+                let log_bytes: Vec<u8> = log.iter().flat_map(|entry| entry.to_bytes()).collect();
+                let length = 1 + 8 + 8 + 8 + 1 + checkpoint_bytes.len() + log_bytes.len();
+                let mut bytes = Vec::with_capacity(length + 4);
                 let discriminator = 9u8;
                 bytes.extend_from_slice(&(length as u32).to_le_bytes());
                 bytes.extend_from_slice(&discriminator.to_le_bytes());
                 bytes.extend_from_slice(&view_number.to_le_bytes());
                 bytes.extend_from_slice(&op_number.to_le_bytes());
                 bytes.extend_from_slice(&commit_number.to_le_bytes());
-                let op_bytes = log.iter().flat_map(|op| op.to_bytes());
-                bytes.extend(op_bytes);
+                bytes.push(checkpoint.is_some() as u8);
+                bytes.extend(checkpoint_bytes);
+                bytes.extend(log_bytes);
+                bytes
+            }
+            Message::Recovery { replica_id, nonce } => {
+                let length = 1 + 8 + 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 10u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
+                bytes.extend_from_slice(&nonce.to_le_bytes());
+                bytes
+            }
+            Message::RecoveryResponse {
+                view_number,
+                nonce,
+                replica_id,
+                checkpoint,
+                log,
+                op_number,
+                commit_number,
+            } => {
+                let checkpoint_bytes = checkpoint.to_bytes();
+                let log_bytes: Vec<u8> = log.iter().flat_map(|entry| entry.to_bytes()).collect();
+                let length = 1 + 8 + 8 + 8 + 8 + 8 + checkpoint_bytes.len() + log_bytes.len();
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 11u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&nonce.to_le_bytes());
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
+                bytes.extend_from_slice(&op_number.to_le_bytes());
+                bytes.extend_from_slice(&commit_number.to_le_bytes());
+                bytes.extend(checkpoint_bytes);
+                bytes.extend(log_bytes);
+                bytes
+            }
+            Message::Reply {
+                view_number,
+                request_number,
+                commit_number,
+                result,
+            } => {
+                let result_bytes = result.to_bytes();
+                let length = 1 + 8 + 8 + 8 + result_bytes.len();
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 12u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&request_number.to_le_bytes());
+                bytes.extend_from_slice(&commit_number.to_le_bytes());
+                bytes.extend(result_bytes);
+                bytes
+            }
+            Message::RequestPrepare {
+                replica_id,
+                view_number,
+                from_op_number,
+                to_op_number,
+            } => {
+                let length = 1 + 8 + 8 + 8 + 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 13u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
+                bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&from_op_number.to_le_bytes());
+                bytes.extend_from_slice(&to_op_number.to_le_bytes());
+                bytes
+            }
+            Message::RequestRepair {
+                replica_id,
+                view_number,
+                op_number,
+            } => {
+                let length = 1 + 8 + 8 + 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 14u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
+                bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&op_number.to_le_bytes());
+                bytes
+            }
+            Message::Repair {
+                view_number,
+                op_number,
+                entry,
+            } => {
+                let entry_bytes = entry.to_bytes();
+                let length = 1 + 8 + 8 + entry_bytes.len();
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 15u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&op_number.to_le_bytes());
+                bytes.extend(entry_bytes);
+                bytes
+            }
+            Message::CommitOk {
+                view_number,
+                replica_id,
+                timestamp,
+            } => {
+                let length = 1 + 8 + 8 + 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 16u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
+                bytes.extend_from_slice(&timestamp.to_le_bytes());
+                bytes
+            }
+            Message::Read {
+                client_id,
+                request_number,
+                op,
+            } => {
+                let op_bytes = op.to_bytes();
+                let length = 1 + 8 + 8 + op_bytes.len();
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 17u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&client_id.to_le_bytes());
+                bytes.extend_from_slice(&request_number.to_le_bytes());
+                bytes.extend_from_slice(&op_bytes);
+                bytes
+            }
+            Message::StaleRead {
+                client_id,
+                request_number,
+                min_commit_number,
+                max_staleness_millis,
+                op,
+            } => {
+                let op_bytes = op.to_bytes();
+                let length = 1 + 8 + 8 + 8 + 8 + op_bytes.len();
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 18u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&client_id.to_le_bytes());
+                bytes.extend_from_slice(&request_number.to_le_bytes());
+                bytes.extend_from_slice(&min_commit_number.to_le_bytes());
+                bytes.extend_from_slice(&max_staleness_millis.to_le_bytes());
+                bytes.extend_from_slice(&op_bytes);
+                bytes
+            }
+            Message::Reconfiguration {
+                client_id,
+                request_number,
+                epoch_number,
+                replicas,
+            } => {
+                let length = 1 + 8 + 8 + 8 + replicas.len() * 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 19u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&client_id.to_le_bytes());
+                bytes.extend_from_slice(&request_number.to_le_bytes());
+                bytes.extend_from_slice(&epoch_number.to_le_bytes());
+                for replica_id in replicas {
+                    bytes.extend_from_slice(&replica_id.to_le_bytes());
+                }
+                bytes
+            }
+            Message::StartEpoch {
+                epoch_number,
+                view_number,
+                replica_id,
+            } => {
+                let length = 1 + 8 + 8 + 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 20u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&epoch_number.to_le_bytes());
+                bytes.extend_from_slice(&view_number.to_le_bytes());
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
+                bytes
+            }
+            Message::EpochStarted {
+                epoch_number,
+                replica_id,
+            } => {
+                let length = 1 + 8 + 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 21u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&epoch_number.to_le_bytes());
+                bytes.extend_from_slice(&replica_id.to_le_bytes());
+                bytes
+            }
+            Message::StaleRequest {
+                request_number,
+                latest_request_number,
+            } => {
+                let length = 1 + 8 + 8;
+                let mut bytes = Vec::with_capacity(length + 4);
+                let discriminator = 22u8;
+                bytes.extend_from_slice(&(length as u32).to_le_bytes());
+                bytes.extend_from_slice(&discriminator.to_le_bytes());
+                bytes.extend_from_slice(&request_number.to_le_bytes());
+                bytes.extend_from_slice(&latest_request_number.to_le_bytes());
                 bytes
             }
         }
@@ -346,16 +904,39 @@ impl Message<Op> {
 
 #[cfg(test)]
 mod tests {
+    use client::Op;
+
     use super::*;
-    fn generate_log() -> Vec<Op> {
-        std::iter::repeat(Op::Add(69)).take(10).collect()
+
+    fn generate_log() -> Vec<LogEntry<Op>> {
+        let mut parent = 0;
+        (0..10)
+            .map(|request_number| {
+                let mut entry = LogEntry::new(1, request_number, Op::Add(69));
+                entry.seal(parent);
+                parent = entry.checksum;
+                entry
+            })
+            .collect()
+    }
+
+    fn generate_checkpoint() -> Checkpoint {
+        Checkpoint {
+            op_number: 3,
+            checksum: 42,
+            epoch_number: 1,
+            replicas: vec![0, 1, 2],
+            state: 69u64.to_le_bytes().to_vec(),
+            clients_table: vec![1; 24],
+        }
     }
 
-    fn generate_start_view_message() -> Message<Op> {
+    fn generate_start_view_message() -> Message<Op, u64> {
         let view_number = 1;
         let op_number = 2;
         let replica_id = 3;
         let commit_number = 4;
+        let checkpoint = generate_checkpoint();
         let log = generate_log();
 
         Message::StartView {
@@ -363,23 +944,62 @@ mod tests {
             op_number,
             replica_id,
             commit_number,
+            checkpoint,
             log,
         }
     }
 
-    fn generate_do_view_change_message() -> Message<Op> {
-        let view_number = 1;
+    fn generate_do_view_change_message() -> Message<Op, u64> {
+        let view_number = 5;
+        let last_normal_view = 1;
         let op_number = 2;
         let replica_id = 3;
         let commit_number = 4;
+        let checkpoint = generate_checkpoint();
         let log = generate_log();
 
         Message::DoViewChange {
             view_number,
+            last_normal_view,
             op_number,
             replica_id,
             commit_number,
+            checkpoint,
+            log,
+        }
+    }
+
+    fn generate_prepare_message() -> Message<Op, u64> {
+        let view_number = 1;
+        let op_number = 10;
+        let commit_number = 4;
+        let entries = generate_log();
+
+        Message::Prepare {
+            view_number,
+            op_number,
+            commit_number,
+            entries,
+        }
+    }
+
+    fn generate_recovery_response_message() -> Message<Op, u64> {
+        let view_number = 1;
+        let nonce = 2;
+        let replica_id = 3;
+        let op_number = 4;
+        let commit_number = 5;
+        let checkpoint = generate_checkpoint();
+        let log = generate_log();
+
+        Message::RecoveryResponse {
+            view_number,
+            nonce,
+            replica_id,
+            checkpoint,
             log,
+            op_number,
+            commit_number,
         }
     }
 
@@ -387,7 +1007,7 @@ mod tests {
     fn serializing_and_deserializing_start_view_message_should_maintain_correct_schema() {
         let message = generate_start_view_message();
         let bytes = message.to_bytes();
-        let message_deserialized = Message::parse_message(&bytes[4..]);
+        let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
 
         assert_eq!(message, message_deserialized);
     }
@@ -396,8 +1016,188 @@ mod tests {
     fn serializing_and_deserializing_do_view_change_message_should_maintain_correct_schema() {
         let message = generate_do_view_change_message();
         let bytes = message.to_bytes();
-        let message_deserialized = Message::parse_message(&bytes[4..]);
+        let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
+
+        assert_eq!(message, message_deserialized);
+    }
+
+    #[test]
+    fn serializing_and_deserializing_prepare_message_should_maintain_correct_schema() {
+        let message = generate_prepare_message();
+        let bytes = message.to_bytes();
+        let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
 
         assert_eq!(message, message_deserialized);
     }
+
+    #[test]
+    fn serializing_and_deserializing_recovery_response_message_should_maintain_correct_schema() {
+        let message = generate_recovery_response_message();
+        let bytes = message.to_bytes();
+        let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
+
+        assert_eq!(message, message_deserialized);
+    }
+
+    #[test]
+    fn serializing_and_deserializing_repair_message_should_maintain_correct_schema() {
+        let entry = generate_log().remove(3);
+        let message: Message<Op, u64> = Message::Repair {
+            view_number: 2,
+            op_number: 4,
+            entry,
+        };
+        let bytes = message.to_bytes();
+        let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
+
+        assert_eq!(message, message_deserialized);
+    }
+
+    #[test]
+    fn serializing_and_deserializing_new_state_message_should_maintain_correct_schema() {
+        for checkpoint in [None, Some(generate_checkpoint())] {
+            let message: Message<Op, u64> = Message::NewState {
+                view_number: 1,
+                checkpoint,
+                log: generate_log(),
+                op_number: 13,
+                commit_number: 5,
+            };
+            let bytes = message.to_bytes();
+            let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
+
+            assert_eq!(message, message_deserialized);
+        }
+    }
+
+    #[test]
+    fn serializing_and_deserializing_read_messages_should_maintain_correct_schema() {
+        let messages: [Message<Op, u64>; 5] = [
+            Message::Commit {
+                view_number: 1,
+                commit_number: 2,
+                timestamp: 3,
+            },
+            Message::CommitOk {
+                view_number: 1,
+                replica_id: 2,
+                timestamp: 3,
+            },
+            Message::Read {
+                client_id: 1,
+                request_number: 2,
+                op: Op::Nop,
+            },
+            Message::StaleRead {
+                client_id: 1,
+                request_number: 2,
+                min_commit_number: 3,
+                max_staleness_millis: 4,
+                op: Op::Add(5),
+            },
+            Message::Reply {
+                view_number: 1,
+                request_number: 2,
+                commit_number: 3,
+                result: 4,
+            },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
+
+            assert_eq!(message, message_deserialized);
+        }
+    }
+
+    #[test]
+    fn serializing_and_deserializing_epoch_messages_should_maintain_correct_schema() {
+        let messages: [Message<Op, u64>; 4] = [
+            Message::Reconfiguration {
+                client_id: 1,
+                request_number: 2,
+                epoch_number: 3,
+                replicas: vec![0, 2, 4],
+            },
+            Message::StartEpoch {
+                epoch_number: 1,
+                view_number: 2,
+                replica_id: 3,
+            },
+            Message::EpochStarted {
+                epoch_number: 1,
+                replica_id: 2,
+            },
+            Message::StaleRequest {
+                request_number: 1,
+                latest_request_number: 2,
+            },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            let message_deserialized = Message::parse_message(&bytes[4..]).unwrap();
+
+            assert_eq!(message, message_deserialized);
+        }
+    }
+
+    #[test]
+    fn envelope_should_carry_the_epoch_and_the_sender() {
+        let message = generate_prepare_message();
+        let envelope = Envelope {
+            cluster_id: 7,
+            epoch_number: 3,
+            replica_id: 2,
+        };
+        let bytes = message.to_envelope_bytes(envelope);
+        assert_eq!(
+            bytes.len() - 4,
+            u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize
+        );
+
+        let (parsed, message_deserialized) = Message::parse_envelope(&bytes[4..]).unwrap();
+        assert_eq!(parsed, Some(envelope));
+        assert_eq!(message, message_deserialized);
+
+        let (envelope, _) = Message::<Op, u64>::parse_envelope(&message.to_bytes()[4..]).unwrap();
+        assert_eq!(envelope, None);
+    }
+
+    #[test]
+    fn malformed_message_should_be_rejected() {
+        let request = Message::<Op, u64>::Request {
+            client_id: 1,
+            request_number: 2,
+            op: Op::Add(3),
+        };
+        let bytes = request.to_bytes();
+        for length in 4..bytes.len() {
+            assert_eq!(Message::<Op, u64>::parse_message(&bytes[4..length]), None);
+        }
+
+        let mut bytes = bytes[4..].to_vec();
+        bytes[0] = u8::MAX;
+        assert_eq!(Message::<Op, u64>::parse_message(&bytes), None);
+        assert_eq!(Message::<Op, u64>::parse_envelope(&[0u8; 8]), None);
+    }
+
+    #[test]
+    fn message_with_malformed_log_entry_should_be_rejected() {
+        let prepare = generate_prepare_message();
+        let mut bytes = prepare.to_bytes()[4..].to_vec();
+        // The payload tag of the first entry, past the fields and the header of the entry.
+        bytes[25 + 32] = u8::MAX;
+        assert_eq!(Message::<Op, u64>::parse_message(&bytes), None);
+
+        let repair = Message::<Op, u64>::Repair {
+            view_number: 1,
+            op_number: 2,
+            entry: generate_log().remove(0),
+        };
+        let bytes = repair.to_bytes();
+        assert_eq!(
+            Message::<Op, u64>::parse_message(&bytes[4..bytes.len() - 1]),
+            None
+        );
+    }
 }
@@ -0,0 +1,307 @@
+use client::{request::Request, Op};
+
+use crate::{
+    action::Action,
+    clock::{Clock, SimClock},
+    counter::Counter,
+    invariants::{InvariantChecker, Violation},
+    message::Message,
+    replica::Replica,
+    replica_config::ReplicaConfig,
+    simulator::panic_message,
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+};
+
+const REPLICA_COUNT: usize = 3;
+// Clients with a request in flight to the primary at the start, one request each.
+const CLIENT_COUNT: usize = 2;
+// Ticks a timeout may take, enough for the longest backoff of the view change.
+const MAX_TICKS_PER_TIMEOUT: usize = 64;
+
+/// Step from one state of the cluster to the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transition {
+    // The in-flight message at the index is delivered.
+    Deliver(usize),
+    // The timer of the replica ticks, until the replica has something to do.
+    Timeout(usize),
+}
+
+/// Message, that's been sent and not delivered yet. In-flight messages are delivered
+/// in any order or never, which covers the reordered, delayed and lost messages.
+#[derive(Clone)]
+struct Packet {
+    // `None` for the client requests.
+    from: Option<usize>,
+    to: usize,
+    bytes: Vec<u8>,
+}
+
+/// States explored by a search, that found no violation.
+#[derive(Debug)]
+pub struct Report {
+    pub states: usize,
+    pub max_depth: usize,
+}
+
+/// Explores every interleaving of the messages and timeouts of a cluster of three replicas,
+/// up to `max_depth` transitions, and checks the safety invariants in every state it reaches.
+/// The search is breadth first, so the violation comes with the shortest trace to it.
+///
+/// Replicas can't be cloned, so every state is rebuilt by replaying the transitions leading to it,
+/// the replicas are deterministic given their seeds. States are told apart by their hash,
+/// a state reached by several paths is explored once.
+pub struct ModelChecker {
+    max_depth: usize,
+    config: ReplicaConfig,
+    // Messages in flight in the initial state.
+    initial: Vec<Packet>,
+}
+
+impl ModelChecker {
+    /// Starts with a request of every client in flight to the primary.
+    pub fn new(max_depth: usize) -> Self {
+        let config = ReplicaConfig {
+            // Never connected to, the replicas are only told apart by their ids.
+            addresses: (0..REPLICA_COUNT)
+                .map(|id| SocketAddr::from(([127, 0, 0, 1], 1000 + id as u16)))
+                .collect(),
+            replicas: (0..REPLICA_COUNT).collect(),
+            // Every request is prepared right away, there are no batch windows to schedule.
+            max_batch_size: 1,
+            ..Default::default()
+        };
+        let primary_id = config.primary_id(0);
+        let initial = (1..=CLIENT_COUNT)
+            .map(|client_id| Packet {
+                from: None,
+                to: primary_id,
+                bytes: Request::new(client_id, 0, Op::Add(client_id as u64)).to_bytes(),
+            })
+            .collect();
+        Self {
+            max_depth,
+            config,
+            initial,
+        }
+    }
+
+    /// Adds a message to the initial state, as if it was sent by the `from` replica.
+    #[cfg(test)]
+    pub fn with_message(mut self, from: usize, to: usize, message: Message<Op, u64>) -> Self {
+        let bytes = message.to_envelope_bytes(crate::message::Envelope {
+            cluster_id: self.config.cluster_id,
+            epoch_number: self.config.epoch_number,
+            replica_id: from,
+        });
+        self.initial.push(Packet {
+            from: Some(from),
+            to,
+            bytes,
+        });
+        self
+    }
+
+    pub fn run(&self) -> Result<Report, Violation> {
+        let mut visited = HashSet::new();
+        visited.insert(self.replay(&[])?.expect("Initial state").digest());
+        let mut frontier: VecDeque<Vec<Transition>> = VecDeque::from([Vec::new()]);
+        while let Some(path) = frontier.pop_front() {
+            if path.len() == self.max_depth {
+                continue;
+            }
+            let world = self.replay(&path)?.expect("Explored state");
+            for transition in world.transitions() {
+                let mut next = path.clone();
+                next.push(transition);
+                let Some(world) = self.replay(&next)? else {
+                    continue;
+                };
+                if visited.insert(world.digest()) {
+                    frontier.push_back(next);
+                }
+            }
+        }
+        Ok(Report {
+            states: visited.len(),
+            max_depth: self.max_depth,
+        })
+    }
+
+    /// Rebuilds the state reached by the `path`, `None` if its last transition does nothing.
+    /// A replica panicking in a handler is a violation as well.
+    fn replay(&self, path: &[Transition]) -> Result<Option<World>, Violation> {
+        let mut world = World::new(&self.config, &self.initial);
+        for transition in path {
+            match panic::catch_unwind(AssertUnwindSafe(|| world.apply(*transition))) {
+                Ok(Ok(true)) => {}
+                Ok(Ok(false)) => return Ok(None),
+                Ok(Err(violation)) => return Err(violation),
+                Err(error) => {
+                    let invariant = format!("replica panicked: {}", panic_message(error));
+                    return Err(world.invariants.violation(invariant));
+                }
+            }
+        }
+        Ok(Some(world))
+    }
+}
+
+/// State of the cluster along with the invariants checked on the way to it.
+struct World {
+    clock: SimClock,
+    tick: u64,
+    replicas: Vec<Replica<Counter>>,
+    in_flight: Vec<Packet>,
+    invariants: InvariantChecker,
+}
+
+impl World {
+    fn new(config: &ReplicaConfig, initial: &[Packet]) -> Self {
+        let clock = SimClock::default();
+        let replicas = (0..REPLICA_COUNT)
+            .map(|id| Replica::with_clock(id, config.clone(), Box::new(clock.clone()), id as u64))
+            .collect();
+        Self {
+            clock,
+            tick: config.tick_interval.as_micros() as u64,
+            replicas,
+            in_flight: initial.to_vec(),
+            invariants: InvariantChecker::new(),
+        }
+    }
+
+    /// Transitions enabled in this state, copies of the same message are delivered only once.
+    fn transitions(&self) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        for (index, packet) in self.in_flight.iter().enumerate() {
+            let duplicate = self.in_flight[..index]
+                .iter()
+                .any(|other| other.to == packet.to && other.bytes == packet.bytes);
+            if !duplicate {
+                transitions.push(Transition::Deliver(index));
+            }
+        }
+        transitions.extend((0..REPLICA_COUNT).map(Transition::Timeout));
+        transitions
+    }
+
+    /// Returns whether the transition did anything.
+    fn apply(&mut self, transition: Transition) -> Result<bool, Violation> {
+        match transition {
+            Transition::Deliver(index) => {
+                let packet = self.in_flight.remove(index);
+                let (envelope, message) = Message::<Op, u64>::parse_envelope(&packet.bytes[4..])
+                    .expect("The cluster sends well-formed messages");
+                let sender = match packet.from {
+                    Some(from) => format!("replica: {}", from),
+                    None => "client".to_string(),
+                };
+                self.invariants.record(format!(
+                    "replica: {} received from {}: {:?}",
+                    packet.to, sender, message
+                ));
+                let replica = &self.replicas[packet.to];
+                let actions = match envelope {
+                    Some(envelope) => replica.on_peer_message(envelope, message),
+                    None => replica.on_message(message),
+                };
+                self.execute(packet.to, actions)?;
+                Ok(true)
+            }
+            Transition::Timeout(id) => {
+                // Time passes for every replica, only the timer of this one fires.
+                for ticks in 1..=MAX_TICKS_PER_TIMEOUT {
+                    self.clock.set(self.clock.now() + self.tick);
+                    let actions = self.replicas[id].on_timer();
+                    if !actions.is_empty() {
+                        self.invariants
+                            .record(format!("replica: {} timed out after {} ticks", id, ticks));
+                        self.execute(id, actions)?;
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    fn execute(
+        &mut self,
+        replica_id: usize,
+        actions: Vec<Action<Op, u64>>,
+    ) -> Result<(), Violation> {
+        self.invariants
+            .check(&self.replicas[replica_id], &actions)?;
+        for action in actions {
+            if let Action::SendToReplica {
+                replica_id: to,
+                envelope,
+                message,
+            } = action
+            {
+                self.in_flight.push(Packet {
+                    from: Some(replica_id),
+                    to,
+                    bytes: message.to_envelope_bytes(envelope),
+                });
+            }
+            // The replies only matter to the clients, the commits are checked by the invariants.
+        }
+        Ok(())
+    }
+
+    /// In-flight messages are hashed as a multiset, their order doesn't matter.
+    /// The time is left out like the replicas leave out their clocks, or no two paths would meet.
+    fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.replicas.hash(&mut hasher);
+        let mut in_flight: Vec<(Option<usize>, usize, &[u8])> = self
+            .in_flight
+            .iter()
+            .map(|packet| (packet.from, packet.to, packet.bytes.as_slice()))
+            .collect();
+        in_flight.sort_unstable();
+        in_flight.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checkpoint::Checkpoint, log::LogEntry};
+
+    #[test]
+    fn small_cluster_should_be_safe_up_to_the_bound() {
+        let report = ModelChecker::new(5)
+            .run()
+            .unwrap_or_else(|violation| panic!("{}", violation));
+        assert!(report.states > 100);
+    }
+
+    #[test]
+    fn forged_prepare_should_be_found_with_the_shortest_trace() {
+        // Commits a different op 1 on the backup, than the one the primary commits.
+        let mut entry = LogEntry::new(3, 0, Op::Add(100));
+        entry.seal(Checkpoint::default().checksum);
+        let forged = Message::Prepare {
+            view_number: 0,
+            op_number: 1,
+            commit_number: 1,
+            entries: vec![entry],
+        };
+        let violation = ModelChecker::new(6)
+            .with_message(0, 2, forged)
+            .run()
+            .unwrap_err();
+        // The request, the forged prepare and its ack, which completes the quorum for the real op 1.
+        assert_eq!(violation.trace.len(), 3, "{}", violation);
+        assert!(violation.invariant.contains("op: 1"));
+    }
+}
@@ -0,0 +1,86 @@
+use monoio::{
+    io::AsyncWriteRentExt,
+    net::{tcp::TcpOwnedWriteHalf, TcpStream},
+};
+use std::{cell::RefCell, collections::HashMap, net::SocketAddr};
+
+/// Sends the messages over the cached `TcpStream`s, the connections are opened on demand.
+/// The delivery is best effort.
+pub struct TcpNetwork {
+    addresses: Vec<SocketAddr>,
+    connections_cache: RefCell<HashMap<usize, TcpStream>>,
+    // Write halves of the inbound client connections, used to send the replies.
+    client_connections: RefCell<HashMap<usize, TcpOwnedWriteHalf>>,
+}
+
+impl TcpNetwork {
+    pub fn new(addresses: Vec<SocketAddr>) -> Self {
+        Self {
+            addresses,
+            connections_cache: Default::default(),
+            client_connections: Default::default(),
+        }
+    }
+
+    pub fn register_client_connection(&self, client_id: usize, connection: TcpOwnedWriteHalf) {
+        self.client_connections
+            .borrow_mut()
+            .insert(client_id, connection);
+    }
+
+    pub async fn send_to_replica(&self, replica_id: usize, bytes: Vec<u8>) {
+        // Take the stream out of the cache for the duration of the write,
+        // so that the cache is not borrowed across an await point.
+        let cached = self.connections_cache.borrow_mut().remove(&replica_id);
+        let mut stream = match cached {
+            Some(stream) => stream,
+            None => match TcpStream::connect(self.addresses[replica_id]).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to connect to replica with id: {}, {}",
+                        replica_id, e
+                    );
+                    return;
+                }
+            },
+        };
+        let (res, _) = stream.write_all(bytes).await;
+        match res {
+            Ok(_) => {
+                self.connections_cache
+                    .borrow_mut()
+                    .insert(replica_id, stream);
+            }
+            Err(e) => {
+                // Drop the broken stream, the next send will reconnect.
+                eprintln!(
+                    "Failed to send message to replica with id: {}, {}",
+                    replica_id, e
+                );
+            }
+        }
+    }
+
+    /// The client might be connected to another replica, the reply is dropped then.
+    pub async fn send_to_client(&self, client_id: usize, bytes: Vec<u8>) {
+        let Some(mut connection) = self.client_connections.borrow_mut().remove(&client_id) else {
+            return;
+        };
+        let (res, _) = connection.write_all(bytes).await;
+        match res {
+            Ok(_) => {
+                self.client_connections
+                    .borrow_mut()
+                    .entry(client_id)
+                    .or_insert(connection);
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to send reply to client with id: {}, {}",
+                    client_id, e
+                );
+            }
+        }
+    }
+}
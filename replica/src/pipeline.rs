@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+
+// Maximum number of ops, that were prepared but are not committed yet.
+pub const PIPELINE_MAX_OPS: usize = 1024;
+// Replica ids have to fit into the `AckSet`, the config is checked against it.
+pub const MAX_REPLICAS: usize = u64::BITS as usize;
+
+/// Set of replica ids, that acked an op.
+#[derive(Default, Clone, Copy, Hash)]
+struct AckSet(u64);
+
+impl AckSet {
+    fn insert(&mut self, replica_id: usize) {
+        assert!(
+            replica_id < MAX_REPLICAS,
+            "Replica id out of range of the ack set"
+        );
+        self.0 |= 1 << replica_id;
+    }
+
+    fn count(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+}
+
+#[derive(Hash)]
+struct Slot {
+    op_number: usize,
+    acks: AckSet,
+}
+
+/// Ops, that the primary has prepared and is waiting on a quorum of `PrepareOk` for,
+/// ordered by their op number.
+#[derive(Default, Hash)]
+pub struct Pipeline {
+    slots: VecDeque<Slot>,
+}
+
+impl Pipeline {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn has_room_for(&self, count: usize) -> bool {
+        self.len() + count <= PIPELINE_MAX_OPS
+    }
+
+    /// Adds a slot for the op, acked by the primary itself.
+    pub fn push(&mut self, op_number: usize, primary_id: usize) {
+        if let Some(last) = self.slots.back() {
+            assert_eq!(last.op_number + 1, op_number);
+        }
+        let mut acks = AckSet::default();
+        acks.insert(primary_id);
+        self.slots.push_back(Slot { op_number, acks });
+    }
+
+    /// Backups append ops in order, so `PrepareOk` for an op acks every op before it as well.
+    /// Duplicate acks are no-op.
+    pub fn ack(&mut self, replica_id: usize, op_number: usize) {
+        for slot in self
+            .slots
+            .iter_mut()
+            .take_while(|slot| slot.op_number <= op_number)
+        {
+            slot.acks.insert(replica_id);
+        }
+    }
+
+    /// Removes the contiguous prefix of ops, that reached the quorum
+    /// and returns the op number of the last one.
+    pub fn pop_committed(&mut self, quorum: usize) -> Option<usize> {
+        let mut committed = None;
+        while let Some(slot) = self.slots.front() {
+            if slot.acks.count() < quorum {
+                break;
+            }
+            committed = Some(slot.op_number);
+            self.slots.pop_front();
+        }
+        committed
+    }
+
+    /// Replaces the pipeline with the uncommitted ops `commit_number + 1..=op_number`,
+    /// used by the new primary after the view change.
+    pub fn reset(&mut self, commit_number: usize, op_number: usize, primary_id: usize) {
+        self.slots.clear();
+        for op_number in commit_number + 1..=op_number {
+            self.push(op_number, primary_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prepare_ok_should_ack_every_op_up_to_its_op_number() {
+        let mut pipeline = Pipeline::default();
+        for op_number in 1..=3 {
+            pipeline.push(op_number, 0);
+        }
+        pipeline.ack(1, 2);
+        assert_eq!(pipeline.pop_committed(2), Some(2));
+        assert_eq!(pipeline.len(), 1);
+        pipeline.ack(2, 3);
+        assert_eq!(pipeline.pop_committed(2), Some(3));
+        assert_eq!(pipeline.len(), 0);
+    }
+
+    #[test]
+    fn duplicate_acks_should_not_count_towards_the_quorum() {
+        let mut pipeline = Pipeline::default();
+        pipeline.push(1, 0);
+        pipeline.ack(1, 1);
+        pipeline.ack(1, 1);
+        pipeline.ack(0, 1);
+        assert_eq!(pipeline.pop_committed(3), None);
+        pipeline.ack(2, 1);
+        assert_eq!(pipeline.pop_committed(3), Some(1));
+    }
+}
@@ -1,179 +1,325 @@
-use client::Op;
-use monoio::{io::AsyncWriteRentExt, net::TcpStream};
-
 use crate::{
-    client_table::ClientTable, message::Message, replica_config::ReplicaConfig, status::Status,
-    stm::StateMachine,
+    action::Action,
+    checkpoint::Checkpoint,
+    client_table::{ClientTable, RequestStatus},
+    clock::{Clock, SystemClock},
+    journal::{Journal, Metadata},
+    lease::{Lease, PendingRead},
+    log::{self, LogEntry, Payload},
+    message::{Envelope, Message},
+    pipeline::Pipeline,
+    replica_config::ReplicaConfig,
+    rng::Rng,
+    stale_read::StaleRead,
+    status::Status,
+    stm::{Codec, StateMachine},
 };
 use std::{
     cell::RefCell,
-    collections::{hash_map::Entry, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Mutex,
     },
+    time::Duration,
 };
 
+// Number of ticks without a message from the primary, after which a backup starts a view change.
+const VIEW_CHANGE_TIMEOUT_TICKS: usize = 4;
+// Number of ticks a view change may take, before the replica moves on to the next view.
+// Doubled for every view abandoned since the last normal view, up to `2^VIEW_CHANGE_MAX_BACKOFF`.
+const VIEW_CHANGE_RETRY_TICKS: usize = 4;
+const VIEW_CHANGE_MAX_BACKOFF: usize = 4;
+// Number of corrupt entries asked for on every tick.
+const MAX_REPAIRS_PER_TICK: usize = 16;
+
 struct ViewSnapshot<Op> {
     view_number: usize,
+    last_normal_view: usize,
     op_number: usize,
     commit_number: usize,
-    log: Vec<Op>,
+    checkpoint: Checkpoint,
+    log: Vec<LogEntry<Op>>,
 }
 
-impl ViewSnapshot<Op> {
-    pub fn new(view_number: usize, op_number: usize, commit_number: usize, log: Vec<Op>) -> Self {
+impl<Op> ViewSnapshot<Op> {
+    pub fn new(
+        view_number: usize,
+        last_normal_view: usize,
+        op_number: usize,
+        commit_number: usize,
+        checkpoint: Checkpoint,
+        log: Vec<LogEntry<Op>>,
+    ) -> Self {
         Self {
             view_number,
+            last_normal_view,
             op_number,
             commit_number,
+            checkpoint,
             log,
         }
     }
 }
 
-pub struct Replica {
+pub struct Replica<S: StateMachine> {
     pub id: usize,
     pub status: RefCell<Status>,
-    pub config: ReplicaConfig,
-    pub clients_table: ClientTable,
+    // Membership changes with every epoch, the addresses of all the replicas are known upfront.
+    pub config: RefCell<ReplicaConfig>,
+    pub clients_table: RefCell<ClientTable<S::Result>>,
     //TODO: Op in the log should be ref counted.
-    pub log: RefCell<Vec<Op>>,
+    // Entries following the checkpoint.
+    pub log: RefCell<Vec<LogEntry<S::Op>>>,
+    // Latest checkpoint, it covers the ops up to its op number.
+    pub checkpoint: RefCell<Checkpoint>,
     pub view_number: AtomicUsize,
     pub op_number: AtomicUsize,
     pub commit_number: AtomicUsize,
+    // Latest view, in which the replica was in the normal status.
+    pub last_normal_view: AtomicUsize,
 
     // Used during view change to choose the new best log.
-    view_snapshot: Mutex<Option<ViewSnapshot<Op>>>,
-    connections_cache: Mutex<HashMap<usize, TcpStream>>,
-    acks: RefCell<HashMap<usize, usize>>,
+    view_snapshot: Mutex<Option<ViewSnapshot<S::Op>>>,
+    // Effects of the event being handled, returned to the driver once it's done.
+    actions: RefCell<Vec<Action<S::Op, S::Result>>>,
+    clock: Box<dyn Clock>,
+    // Source of the nonces and the jitter.
+    rng: RefCell<Rng>,
+    // Prepares in flight, waiting for a quorum of `PrepareOk`.
+    pipeline: RefCell<Pipeline>,
+    // Requests waiting to be prepared by the primary as a single batch.
+    batch: RefCell<Vec<LogEntry<S::Op>>>,
+    batch_number: AtomicUsize,
     backup_idle_ticks: AtomicUsize,
-    view_change_counter: RefCell<HashMap<usize, usize>>,
-    do_view_change_counter: RefCell<HashMap<usize, usize>>,
-    stm: StateMachine,
+    view_change_ticks: AtomicUsize,
+    // Prepares, that arrived out of order, keyed by the op number of their last entry.
+    prepare_buffer: RefCell<BTreeMap<usize, Vec<LogEntry<S::Op>>>>,
+    // Last op number asked for with `RequestPrepare`.
+    requested_op_number: AtomicUsize,
+    awaiting_state: AtomicBool,
+    state_transfer_attempts: AtomicUsize,
+    view_change_timeout: AtomicUsize,
+    // Replicas, that sent the `StartViewChange` and `DoViewChange` for the view.
+    view_change_counter: RefCell<HashMap<usize, HashSet<usize>>>,
+    do_view_change_counter: RefCell<HashMap<usize, HashSet<usize>>>,
+    // Nonce of the ongoing recovery, responses carrying a different nonce are stale.
+    recovery_nonce: RefCell<Option<u64>>,
+    recovery_responses: RefCell<HashMap<usize, ViewSnapshot<S::Op>>>,
+    // On-disk copy of the log and the view state, `None` unless running in the durable mode.
+    pub(crate) journal: Option<RefCell<Journal>>,
+    // Op numbers of the entries, that failed the verification and wait to be repaired by the peers.
+    // Those are neither applied, nor acknowledged.
+    pub(crate) faulty_ops: RefCell<BTreeSet<usize>>,
+    pub(crate) stm: RefCell<S>,
+    lease: RefCell<Lease>,
+    // Reads waiting for the lease to be confirmed, or the ops of the previous view to be committed.
+    pending_reads: RefCell<Vec<PendingRead<S::Op>>>,
+    // The `DoViewChange` is held back, until our lease promise to the previous primary expires.
+    do_view_change_deferred: AtomicBool,
+    // Reads from the clients, that don't need the latest state, waiting for ours to be fresh enough.
+    stale_reads: RefCell<Vec<StaleRead<S::Op>>>,
+    // Last time we were known to have committed everything the primary had.
+    synced_at: RefCell<Option<u64>>,
+    // Members of the previous epoch, the leaving ones announce the new epoch to the others.
+    previous_replicas: RefCell<Vec<usize>>,
+    // Set once we've entered an epoch, the `StartEpoch` is sent on the next tick.
+    announce_epoch: AtomicBool,
+    // Leaving replica: members of the new epoch, that have started it.
+    epoch_started: RefCell<HashSet<usize>>,
+    // The leaving replica shuts down, once the quorum of the new epoch is running.
+    retired: AtomicBool,
 }
 
-impl Replica {
+impl<S: StateMachine> Replica<S> {
     pub fn new(id: usize, config: ReplicaConfig) -> Self {
+        let seed = Rng::from_entropy().next_u64();
+        Self::with_clock(id, config, Box::new(SystemClock::new()), seed)
+    }
+
+    /// Creates a replica, that reads the time from the `clock` and draws its nonces from the `seed`.
+    pub fn with_clock(id: usize, config: ReplicaConfig, clock: Box<dyn Clock>, seed: u64) -> Self {
+        let lease = Lease::new(config.lease_duration, clock.now());
         Self {
             id,
-            config,
+            config: RefCell::new(config),
             status: Default::default(),
             clients_table: Default::default(),
             log: Default::default(),
+            checkpoint: Default::default(),
             view_number: Default::default(),
             op_number: Default::default(),
             commit_number: Default::default(),
+            last_normal_view: Default::default(),
             view_snapshot: Default::default(),
-            connections_cache: Default::default(),
-            acks: Default::default(),
+            actions: Default::default(),
+            clock,
+            rng: RefCell::new(Rng::new(seed)),
+            pipeline: Default::default(),
+            batch: Default::default(),
+            batch_number: Default::default(),
             backup_idle_ticks: Default::default(),
+            view_change_ticks: Default::default(),
+            prepare_buffer: Default::default(),
+            requested_op_number: Default::default(),
+            awaiting_state: Default::default(),
+            state_transfer_attempts: Default::default(),
+            view_change_timeout: Default::default(),
             view_change_counter: Default::default(),
             do_view_change_counter: Default::default(),
+            recovery_nonce: Default::default(),
+            recovery_responses: Default::default(),
+            journal: None,
+            faulty_ops: Default::default(),
             stm: Default::default(),
+            lease: RefCell::new(lease),
+            pending_reads: Default::default(),
+            do_view_change_deferred: Default::default(),
+            stale_reads: Default::default(),
+            synced_at: Default::default(),
+            previous_replicas: Default::default(),
+            announce_epoch: Default::default(),
+            epoch_started: Default::default(),
+            retired: Default::default(),
         }
     }
 
-    async fn send_msg_to_primary(&self, message: Message<Op>) {
-        let view_number = self.view_number();
-        let primary_id = self.config.primary_id(view_number);
-        let mut connections = self.connections_cache.lock().unwrap();
-        match connections.entry(primary_id) {
-            Entry::Occupied(mut conn) => {
-                let stream = conn.get_mut();
-                let bytes = message.to_bytes();
-                stream
-                    .write_all(bytes)
-                    .await
-                    .0
-                    .expect("Failed to send message to replica");
-            }
-            Entry::Vacant(entry) => {
-                let addr = self.config.get_replica_address(primary_id);
-                let mut stream = TcpStream::connect(addr).await.unwrap();
-                let bytes = message.to_bytes();
-                stream
-                    .write_all(bytes)
-                    .await
-                    .0
-                    .expect("Failed to send message to replica");
-                entry.insert(stream);
-            }
+    /// Creates a replica, that persists its log and view state in `data_dir`.
+    /// The state left there by a previous run is restored, committed ops are replayed.
+    pub fn open(
+        id: usize,
+        config: ReplicaConfig,
+        data_dir: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let (journal, checkpoint, log) = Journal::open(data_dir)?;
+        let mut replica = Self::new(id, config);
+        let metadata = journal.metadata();
+        replica.set_view_number(metadata.view_number);
+        replica
+            .last_normal_view
+            .store(metadata.last_normal_view, Ordering::Release);
+        let faulty_ops = log::find_faulty_ops(&log, &checkpoint);
+        for op_number in faulty_ops.iter() {
+            eprintln!("Found corrupt entry for op: {}, in the journal", op_number);
+        }
+        replica.install_checkpoint(checkpoint);
+        replica.set_op_number(replica.checkpoint_number() + log.len());
+        replica.log.replace(log);
+        replica.faulty_ops.replace(faulty_ops);
+        replica.commit_up_to(metadata.commit_number);
+        replica.rebuild_clients_table();
+        replica.status.replace(metadata.status);
+        // The uncommitted ops of a restarted primary get committed, once the backups ack them.
+        if replica.is_primary()
+            && metadata.status == Status::Normal
+            && replica.faulty_ops.borrow().is_empty()
+        {
+            replica
+                .pipeline
+                .borrow_mut()
+                .reset(replica.commit_number(), replica.op_number(), id);
+            replica.lease.borrow_mut().reset(replica.op_number());
         }
-    }
-
-    async fn send_msg_to_replica(&self, replica_id: usize, message: Message<Op>) {
         println!(
-            "Sending message: {:?} to replica with id: {}",
-            message, replica_id
+            "Restored replica: {}, in view: {}, op_number: {}, commit_number: {}",
+            id,
+            replica.view_number(),
+            replica.op_number(),
+            replica.commit_number()
         );
-        let mut connections = self.connections_cache.lock().unwrap();
-        match connections.entry(*replica_id) {
-            Entry::Occupied(mut conn) => {
-                let stream = conn.get_mut();
-                let bytes = message.to_bytes();
-                stream
-                    .write_all(bytes)
-                    .await
-                    .0
-                    .expect("Failed to send message to replica");
-            }
-            Entry::Vacant(entry) => {
-                let addr = self.config.get_replica_address(replica_id);
-                let mut stream = TcpStream::connect(addr).await.unwrap();
-                let bytes = message.to_bytes();
-                stream
-                    .write_all(bytes)
-                    .await
-                    .0
-                    .expect("Failed to send message to replica");
-                entry.insert(stream);
-            }
-        }
+        replica.journal = Some(RefCell::new(journal));
+        // The replayed ops were committed before the restart.
+        replica.take_actions();
+        Ok(replica)
     }
+
+    /// Persists the view number, status and commit number, if running in the durable mode.
+    /// Has to be called before the replica acts on a view change.
+    fn persist_metadata(&self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        let metadata = Metadata {
+            view_number: self.view_number(),
+            last_normal_view: self.last_normal_view(),
+            commit_number: self.commit_number(),
+            status: *self.status.borrow(),
+        };
+        journal
+            .borrow_mut()
+            .write_metadata(metadata)
+            .expect("Failed to persist the replica metadata");
     }
-    // TODO: When connecting to other replica, check if the connection exists in cache,
-    // otherwise create it and insert to the connection cache.
-    async fn send_msg_to_replicas(&self, message: Message<Op>) {
-        for (replica_id, addr) in self
-            .config
-            .replicas
-            .iter()
-            .zip(self.config.addresses.iter())
-        {
-            if *replica_id != self.id {
-                println!(
-                    "Sending message: {:?} to replica with id: {}",
-                    message, replica_id
-                );
-                let mut connections = self.connections_cache.lock().unwrap();
-                match connections.entry(*replica_id) {
-                    Entry::Occupied(mut conn) => {
-                        let stream = conn.get_mut();
-                        let bytes = message.to_bytes();
-                        stream
-                            .write_all(bytes)
-                            .await
-                            .0
-                            .expect("Failed to send message to replica");
-                    }
-                    Entry::Vacant(entry) => {
-                        let mut stream = TcpStream::connect(addr).await.unwrap();
-                        let bytes = message.to_bytes();
-                        stream
-                            .write_all(bytes)
-                            .await
-                            .0
-                            .expect("Failed to send message to replica");
-                        entry.insert(stream);
-                    }
-                }
+
+    fn send_msg_to_primary(&self, message: Message<S::Op, S::Result>) {
+        let primary_id = self.config.borrow().primary_id(self.view_number());
+        self.send_msg_to_replica(primary_id, message);
+    }
+
+    fn send_msg_to_replica(&self, replica_id: usize, message: Message<S::Op, S::Result>) {
+        let envelope = self.envelope();
+        self.actions.borrow_mut().push(Action::SendToReplica {
+            replica_id,
+            envelope,
+            message,
+        });
+    }
+
+    fn send_msg_to_replicas(&self, message: Message<S::Op, S::Result>) {
+        let replica_ids = self.config.borrow().replicas.clone();
+        self.send_msg_to_group(replica_ids, message);
+    }
+
+    /// The learners follow the log along with the backups.
+    fn send_msg_to_followers(&self, message: Message<S::Op, S::Result>) {
+        let replica_ids = self.config.borrow().replicas_and_learners();
+        self.send_msg_to_group(replica_ids, message);
+    }
+
+    fn send_msg_to_group(&self, replica_ids: Vec<usize>, message: Message<S::Op, S::Result>) {
+        for replica_id in replica_ids {
+            if replica_id != self.id {
+                self.send_msg_to_replica(replica_id, message.clone());
             }
         }
     }
 
+    /// Our epoch travels along with the message, so that the stale ones are told apart.
+    fn envelope(&self) -> Envelope {
+        Envelope {
+            cluster_id: self.config.borrow().cluster_id,
+            epoch_number: self.epoch_number(),
+            replica_id: self.id,
+        }
+    }
+
+    fn send_reply<R: Codec + Clone>(&self, client_id: usize, request_number: usize, result: R) {
+        let message: Message<S::Op, R> = Message::Reply {
+            view_number: self.view_number(),
+            request_number,
+            commit_number: self.commit_number(),
+            result,
+        };
+        let bytes = message.to_bytes();
+        self.actions
+            .borrow_mut()
+            .push(Action::SendToClient { client_id, bytes });
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Effects of the handled event, in the order they happened.
+    fn take_actions(&self) -> Vec<Action<S::Op, S::Result>> {
+        std::mem::take(&mut *self.actions.borrow_mut())
+    }
+
     fn number_of_replicas(&self) -> usize {
-        self.config.replicas.len()
+        self.config.borrow().replicas.len()
     }
 
     fn quorum(&self) -> usize {
@@ -181,28 +327,68 @@ impl Replica {
         replicas_count / 2 + 1
     }
 
-    pub fn quorum_for_op(&self, op_number: usize) -> bool {
-        let acks = *self.acks.borrow().get(&op_number).unwrap();
-        acks == self.quorum()
+    /// Returns `None` for the reconfiguration, the new epoch starts once it's committed.
+    pub fn commit_op(&self, op_number: usize) -> Option<S::Result> {
+        let entry = self.entry(op_number + 1);
+        self.actions.borrow_mut().push(Action::Commit {
+            op_number: op_number + 1,
+            checksum: entry.checksum,
+        });
+        match entry.payload {
+            Payload::Op(op) => {
+                let result = self.stm.borrow_mut().apply(op);
+                self.clients_table.borrow_mut().commit(
+                    entry.client_id,
+                    entry.request_number,
+                    result.clone(),
+                );
+                self.commit_number.fetch_add(1, Ordering::AcqRel);
+                Some(result)
+            }
+            Payload::Reconfiguration { replicas } => {
+                self.commit_number.fetch_add(1, Ordering::AcqRel);
+                self.enter_epoch(replicas);
+                None
+            }
+        }
     }
 
-    pub fn commit_op(&self, op_number: usize) {
-        let log = self.log.borrow();
-        let op = &log[op_number];
-        self.stm.apply(op.clone());
-        self.commit_number.fetch_add(1, Ordering::AcqRel);
+    /// Commits the ops up to `commit_number`, as far as our log reaches.
+    /// Stops before a corrupt entry, it's committed once repaired.
+    fn commit_up_to(&self, commit_number: usize) {
+        let commit_number = commit_number.min(self.op_number());
+        for op_number in self.commit_number()..commit_number {
+            if self.faulty_ops.borrow().contains(&(op_number + 1)) {
+                break;
+            }
+            self.commit_op(op_number);
+        }
     }
 
-    pub fn ack_op(&self, op_number: usize) {
-        self.acks
-            .borrow_mut()
-            .entry(op_number)
-            .and_modify(|ack| *ack += 1)
-            .or_insert(1);
+    /// Op number acknowledged by `PrepareOk`, acks are cumulative,
+    /// so a corrupt entry holds back the acks of the entries following it.
+    fn ack_op_number(&self) -> usize {
+        self.faulty_ops
+            .borrow()
+            .first()
+            .map(|op_number| op_number - 1)
+            .unwrap_or_else(|| self.op_number())
+    }
+
+    /// Has to be called after the log has been replaced and the committed ops applied,
+    /// so that requests, that didn't survive the view change can be retried by the clients.
+    fn rebuild_clients_table(&self) {
+        let log = self.log.borrow();
+        let uncommitted = self.commit_number() - self.checkpoint_number();
+        self.clients_table.borrow_mut().rebuild(
+            log[uncommitted..]
+                .iter()
+                .filter(|entry| !entry.is_reconfiguration()),
+        );
     }
 
     pub fn is_primary(&self) -> bool {
-        self.id == self.config.primary_id(self.view_number())
+        self.id == self.config.borrow().primary_id(self.view_number())
     }
 
     pub fn commit_number(&self) -> usize {
@@ -217,7 +403,53 @@ impl Replica {
         self.op_number.load(Ordering::Acquire)
     }
 
-    pub async fn on_message(&self, message: Message<Op>) {
+    pub fn epoch_number(&self) -> usize {
+        self.config.borrow().epoch_number
+    }
+
+    fn is_member(&self) -> bool {
+        self.config.borrow().is_member(self.id)
+    }
+
+    fn is_learner(&self) -> bool {
+        self.config.borrow().is_learner(self.id)
+    }
+
+    fn handle_message(&self, message: Message<S::Op, S::Result>) {
+        // A recovering replica has lost its state, it must not take part
+        // in the protocol until it has learned the state from the cluster.
+        if *self.status.borrow() == Status::Recovery
+            && !matches!(
+                message,
+                Message::RecoveryResponse { .. } | Message::StartEpoch { .. }
+            )
+        {
+            return;
+        }
+        // Learners follow the log, but don't take part in the view changes.
+        if self.is_learner()
+            && matches!(
+                message,
+                Message::StartViewChange { .. }
+                    | Message::DoViewChange { .. }
+                    | Message::Recovery { .. }
+            )
+        {
+            return;
+        }
+        // Outside of the current epoch, the replica only takes part in the state transfer.
+        if !self.is_member()
+            && !self.is_learner()
+            && !matches!(
+                message,
+                Message::GetState { .. }
+                    | Message::NewState { .. }
+                    | Message::StartEpoch { .. }
+                    | Message::EpochStarted { .. }
+            )
+        {
+            return;
+        }
         match message {
             Message::Request {
                 client_id,
@@ -227,226 +459,830 @@ impl Replica {
                 // Check if you are primary, otherwise drop the message.
                 // Increment op-number.
                 // Send `Prepare` message to other replicas.
-                self.on_request(client_id, request_number, op).await;
+                self.on_request(client_id, request_number, op);
             }
             Message::Prepare {
                 view_number,
-                op,
                 op_number,
                 commit_number,
+                entries,
             } => {
                 // Incremenet op-number.
                 // Append to log.
-                // Update clients table.
                 // Send `PrepareOk` to primary.
-                self.on_prepare(view_number, op_number, op, commit_number)
-                    .await
+                self.on_prepare(view_number, op_number, entries, commit_number)
             }
             Message::PrepareOk {
                 view_number,
                 op_number,
+                replica_id,
             } => {
                 // Check if received a quorum of `PrepareOk`
                 // Call the service code (app logic).
                 // Increment the commit-number.
                 // Reply to the client.
                 // Update clients table.
-                self.on_prepare_ok(view_number, op_number);
+                self.on_prepare_ok(view_number, op_number, replica_id);
             }
             Message::Commit {
                 view_number,
                 commit_number,
+                timestamp,
             } => {
                 // Check if the request is in the log
                 // Call the service code (app logic).
                 // Increment the commit-number.
                 // Update clients table.
-                self.on_commit(view_number, commit_number);
+                self.on_commit(view_number, commit_number, timestamp);
+            }
+            Message::CommitOk {
+                view_number,
+                replica_id,
+                timestamp,
+            } => {
+                self.on_commit_ok(view_number, replica_id, timestamp);
+            }
+            Message::Read {
+                client_id,
+                request_number,
+                op,
+            } => {
+                self.on_read(client_id, request_number, op);
+            }
+            Message::StaleRead {
+                client_id,
+                request_number,
+                min_commit_number,
+                max_staleness_millis,
+                op,
+            } => {
+                let read = StaleRead::new(
+                    client_id,
+                    request_number,
+                    op,
+                    min_commit_number,
+                    max_staleness_millis,
+                );
+                self.on_stale_read(read);
             }
             Message::StartViewChange {
                 view_number,
                 replica_id,
             } => {
-                self.on_start_view_change(view_number, replica_id).await;
+                self.on_start_view_change(view_number, replica_id);
             }
             Message::DoViewChange {
                 view_number,
+                last_normal_view,
                 op_number,
                 replica_id,
                 commit_number,
+                checkpoint,
                 log,
             } => {
-                self.on_do_view_change(view_number, op_number, replica_id, commit_number, log)
-                    .await;
+                let candidate = ViewSnapshot::new(
+                    view_number,
+                    last_normal_view,
+                    op_number,
+                    commit_number,
+                    checkpoint,
+                    log,
+                );
+                self.on_do_view_change(replica_id, candidate);
             }
             Message::StartView {
                 view_number,
                 op_number,
                 replica_id,
                 commit_number,
+                checkpoint,
                 log,
             } => {
-                self.on_start_view(view_number, op_number, replica_id, commit_number, log);
+                self.on_start_view(
+                    view_number,
+                    op_number,
+                    replica_id,
+                    commit_number,
+                    checkpoint,
+                    log,
+                );
             }
             Message::GetState {
                 replica_id,
                 view_number,
                 op_number,
             } => {
-                self.on_get_state(replica_id, view_number, op_number).await;
+                self.on_get_state(replica_id, view_number, op_number);
             }
             Message::NewState {
                 view_number,
+                checkpoint,
+                log,
+                op_number,
+                commit_number,
+            } => {
+                self.on_new_state(view_number, checkpoint, log, op_number, commit_number);
+            }
+            Message::Recovery { replica_id, nonce } => {
+                self.on_recovery(replica_id, nonce);
+            }
+            Message::RecoveryResponse {
+                view_number,
+                nonce,
+                replica_id,
+                checkpoint,
                 log,
                 op_number,
                 commit_number,
             } => {
-                self.on_new_state(view_number, log, op_number, commit_number)
-                    .await;
+                // Only replicas in the normal status respond to the `Recovery`.
+                let response = ViewSnapshot::new(
+                    view_number,
+                    view_number,
+                    op_number,
+                    commit_number,
+                    checkpoint,
+                    log,
+                );
+                self.on_recovery_response(nonce, replica_id, response);
+            }
+            Message::RequestPrepare {
+                replica_id,
+                view_number,
+                from_op_number,
+                to_op_number,
+            } => {
+                self.on_request_prepare(replica_id, view_number, from_op_number, to_op_number);
+            }
+            Message::RequestRepair {
+                replica_id,
+                view_number,
+                op_number,
+            } => {
+                self.on_request_repair(replica_id, view_number, op_number);
+            }
+            Message::Repair {
+                view_number,
+                op_number,
+                entry,
+            } => {
+                self.on_repair(view_number, op_number, entry);
             }
+            Message::Reconfiguration {
+                client_id,
+                request_number,
+                epoch_number,
+                replicas,
+            } => {
+                self.on_reconfiguration(client_id, request_number, epoch_number, replicas);
+            }
+            Message::StartEpoch {
+                epoch_number,
+                view_number,
+                replica_id,
+            } => {
+                self.on_start_epoch(epoch_number, view_number, replica_id);
+            }
+            Message::EpochStarted {
+                epoch_number,
+                replica_id,
+            } => {
+                self.on_epoch_started(epoch_number, replica_id);
+            }
+            Message::Reply { .. } | Message::StaleRequest { .. } => {
+                // Replies are sent to the clients, replicas never receive them.
+            }
+        }
+    }
+}
+
+// Events
+impl<S: StateMachine> Replica<S> {
+    /// Handles the message of a client, or of a replica that has already been unwrapped from its envelope.
+    pub fn on_message(&self, message: Message<S::Op, S::Result>) -> Vec<Action<S::Op, S::Result>> {
+        self.handle_message(message);
+        self.take_actions()
+    }
+
+    /// Handles the message of another replica, along with the envelope it came in.
+    pub fn on_peer_message(
+        &self,
+        envelope: Envelope,
+        message: Message<S::Op, S::Result>,
+    ) -> Vec<Action<S::Op, S::Result>> {
+        self.handle_peer_message(envelope, message);
+        self.take_actions()
+    }
+
+    /// Called every `tick_interval`, the timeouts are counted in ticks.
+    pub fn on_timer(&self) -> Vec<Action<S::Op, S::Result>> {
+        self.handle_timer();
+        self.take_actions()
+    }
+
+    /// Called once the delay of the `ScheduleFlush` has passed.
+    pub fn on_batch_window(&self, batch_number: usize) -> Vec<Action<S::Op, S::Result>> {
+        // The batch might have been filled up and flushed in the meantime.
+        if self.batch_number.load(Ordering::Acquire) == batch_number {
+            self.flush_batch();
         }
+        self.take_actions()
+    }
+
+    /// Runs the recovery protocol, `Recovery` messages are retransmitted on every tick
+    /// until enough replicas respond and the replica is back in `Status::Normal`.
+    pub fn recover(&self) -> Vec<Action<S::Op, S::Result>> {
+        self.start_recovery();
+        self.take_actions()
     }
 }
 
 // Handlers
-impl Replica {
-    async fn on_request(&self, client_id: usize, request_number: usize, op: Op) {
-        assert!(self.is_primary());
+impl<S: StateMachine> Replica<S> {
+    fn on_request(&self, client_id: usize, request_number: usize, op: S::Op) {
+        // Clients broadcast the request once the primary doesn't respond,
+        // backups just drop it.
+        if !self.is_primary() {
+            return;
+        }
         if *self.status.borrow() != Status::Normal {
             // TODO: Impl mechanism that teaches client to try again later on.
             return;
         }
-        // Check in client table, whether the request_number is subsequent.
-        // If it's smaller, drop the request (duplicate)
-        // If it's equal to current request_number, resend the response.
+        // No ops follow the reconfiguration in its epoch, the clients retry in the new one.
+        if self.reconfiguration_pending() {
+            return;
+        }
+        let request_status = self.clients_table.borrow().check(client_id, request_number);
+        match request_status {
+            RequestStatus::New => {}
+            RequestStatus::InProgress => return,
+            RequestStatus::Stale(latest_request_number) => {
+                let message: Message<S::Op, S::Result> = Message::StaleRequest {
+                    request_number,
+                    latest_request_number,
+                };
+                let bytes = message.to_bytes();
+                self.actions
+                    .borrow_mut()
+                    .push(Action::SendToClient { client_id, bytes });
+                return;
+            }
+            RequestStatus::Committed(result) => {
+                // The reply got lost, resend the cached one.
+                self.send_reply(client_id, request_number, result);
+                return;
+            }
+        }
+        self.clients_table
+            .borrow_mut()
+            .prepare(client_id, request_number);
+
+        // Add the request to the current batch.
+        let batch_len = {
+            let mut batch = self.batch.borrow_mut();
+            batch.push(LogEntry::new(client_id, request_number, op));
+            batch.len()
+        };
+        if batch_len >= self.config.borrow().max_batch_size {
+            self.flush_batch();
+        } else if batch_len == 1 {
+            // The first request of the batch waits for the others to arrive,
+            // unless the batch gets filled up and flushed in the meantime.
+            let action = Action::ScheduleFlush {
+                batch_number: self.batch_number.load(Ordering::Acquire),
+                delay: self.config.borrow().batch_window,
+            };
+            self.actions.borrow_mut().push(action);
+        }
+    }
+
+    fn flush_batch(&self) {
+        // Once the pipeline is full, the batch waits for the in-flight prepares to commit.
+        if !self
+            .pipeline
+            .borrow()
+            .has_room_for(self.batch.borrow().len())
+        {
+            return;
+        }
+        let batch = std::mem::take(&mut *self.batch.borrow_mut());
+        self.batch_number.fetch_add(1, Ordering::AcqRel);
+        if batch.is_empty() {
+            return;
+        }
+        self.prepare_entries(batch);
+    }
+
+    /// Appends the entries to our log and sends them to the backups as a single prepare.
+    fn prepare_entries(&self, mut entries: Vec<LogEntry<S::Op>>) {
+        // The view might have changed while the batch was being collected,
+        // the clients will retry against the new primary.
+        if !self.is_primary() || *self.status.borrow() != Status::Normal {
+            return;
+        }
 
-        // Append to log
-        self.append_to_log(op.clone());
-        // Ack the op ourselves
-        let op_number = self.op_number.load(Ordering::Acquire);
-        self.acks.borrow_mut().insert(1, op_number);
+        // Chain the entries to our log, the backups verify them against their own.
+        let mut parent = self.head_checksum();
+        for entry in entries.iter_mut() {
+            entry.seal(parent);
+            parent = entry.checksum;
+        }
+        // Append to log and ack the ops ourselves
+        for entry in entries.iter().cloned() {
+            self.append_to_log(entry);
+            self.pipeline.borrow_mut().push(self.op_number(), self.id);
+        }
+        // Our own ack counts towards the quorum, so the ops have to be durable first.
+        self.sync_log();
+        let op_number = self.op_number();
         // Send `Prepare` message to backups
         let commit_number = self.commit_number();
         let view_number = self.view_number();
         let message = Message::Prepare {
             view_number,
-            op,
             op_number,
             commit_number,
+            entries,
         };
-        self.send_msg_to_replicas(message).await;
+        self.send_msg_to_followers(message);
     }
 
-    async fn on_prepare(&self, view_number: usize, op_number: usize, op: Op, commit_number: usize) {
-        self.backup_idle_ticks.store(0, Ordering::Relaxed);
-        assert!(!self.is_primary());
-        if self.view_number() != view_number {
+    fn resend_prepare(&self) {
+        let commit_number = self.commit_number();
+        let message = Message::Prepare {
+            view_number: self.view_number(),
+            op_number: self.op_number(),
+            commit_number,
+            entries: self.log_suffix(commit_number),
+        };
+        self.send_msg_to_followers(message);
+    }
+
+    fn on_prepare(
+        &self,
+        view_number: usize,
+        op_number: usize,
+        entries: Vec<LogEntry<S::Op>>,
+        commit_number: usize,
+    ) {
+        if *self.status.borrow() != Status::Normal || view_number < self.view_number() {
+            return;
+        }
+        if view_number > self.view_number() {
             // This means that our backup has felt behind during the `ViewChange` protocol.
-            // Initiate the recovery process.
+            self.catch_up_view(view_number);
         }
+        self.backup_idle_ticks.store(0, Ordering::Relaxed);
 
-        let current_op_number = self.op_number.load(Ordering::Acquire);
-        if op_number <= current_op_number {
-            return;
+        // Buffer the prepare, it's appended to the log once the gap before it (if any) is filled.
+        // `op_number` is the op number of the last entry in the batch.
+        self.prepare_buffer.borrow_mut().insert(op_number, entries);
+        let appended = self.append_buffered_prepares();
+        self.commit_up_to(commit_number);
+        self.mark_synced(commit_number);
+        self.serve_stale_reads();
+        if !self.prepare_buffer.borrow().is_empty() {
+            self.request_missing_prepares();
         }
-        if op_number > current_op_number + 1 {
-            // Initiate state transfer
-            self.state_transfer().await;
+        if appended {
+            self.sync_log();
+        } else if op_number > self.ack_op_number() {
             return;
         }
-
-        // Append op to the log.
-        self.append_to_log(op);
-        for op_number in self.commit_number()..commit_number {
-            // Commit op
-            self.commit_op(op_number);
+        // A prepare we already hold is acked again, the previous ack might have been lost.
+        // The acks of the learners don't count towards the quorum.
+        if self.is_learner() {
+            return;
         }
         // Send message back to primary.
         let message = Message::PrepareOk {
             view_number: self.view_number(),
-            op_number: self.op_number.load(Ordering::Acquire),
+            op_number: self.ack_op_number(),
+            replica_id: self.id,
         };
-        println!(
-            "Sending message: {:?} to primary as response for prepare message",
-            message
-        );
-        self.send_msg_to_primary(message).await;
+        self.send_msg_to_primary(message);
     }
 
-    fn on_prepare_ok(&self, view_number: usize, op_number: usize) {
-        assert!(self.is_primary());
-        assert_eq!(self.view_number(), view_number);
+    fn on_prepare_ok(&self, view_number: usize, op_number: usize, replica_id: usize) {
+        // Late `PrepareOk` from the previous view, or from a replica, that doesn't vote.
+        if !self.is_primary()
+            || *self.status.borrow() != Status::Normal
+            || self.view_number() != view_number
+            || !self.config.borrow().is_member(replica_id)
+        {
+            return;
+        }
 
-        self.ack_op(op_number);
-        if self.quorum_for_op(op_number) {
-            // Commit op
-            self.commit_op(op_number - 1);
+        let committed = {
+            let mut pipeline = self.pipeline.borrow_mut();
+            pipeline.ack(replica_id, op_number);
+            pipeline.pop_committed(self.quorum())
+        };
+        let Some(committed) = committed else {
+            return;
+        };
+        // Commit every op, that reached the quorum, in order.
+        for op_number in self.commit_number()..committed {
+            if self.faulty_ops.borrow().contains(&(op_number + 1)) {
+                break;
+            }
+            let result = self.commit_op(op_number);
             // Send response to the client.
+            let entry = self.entry(op_number + 1);
+            let (client_id, request_number) = (entry.client_id, entry.request_number);
+            match result {
+                Some(result) => self.send_reply(client_id, request_number, result),
+                // The reconfiguration is answered with the number of the new epoch.
+                None => {
+                    let epoch_number = self.epoch_number() as u64;
+                    self.send_reply(client_id, request_number, epoch_number)
+                }
+            }
+        }
+        if !self.pending_reads.borrow().is_empty() {
+            self.serve_pending_reads();
+        }
+        self.serve_stale_reads();
+        // Requests, that didn't fit into the pipeline.
+        if !self.batch.borrow().is_empty() {
+            self.flush_batch();
         }
     }
 
-    fn on_commit(&self, view_number: usize, commit_number: usize) {
-        self.backup_idle_ticks.store(0, Ordering::Relaxed);
-        if *self.status.borrow() != Status::Normal {
+    fn on_commit(&self, view_number: usize, commit_number: usize, timestamp: u64) {
+        if *self.status.borrow() != Status::Normal || view_number < self.view_number() {
             return;
         }
-        let backup_view_number = self.view_number();
-        if view_number < backup_view_number {
+        if view_number > self.view_number() {
+            self.catch_up_view(view_number);
+        }
+        if self.is_primary() {
             return;
         }
-        assert_eq!(*self.status.borrow(), Status::Normal);
-        assert_eq!(backup_view_number, view_number);
+        // The `Commit` proves that the primary of our view is alive.
+        self.backup_idle_ticks.store(0, Ordering::Relaxed);
+        // Learners can't help starting a new view, so they don't renew the lease.
+        if !self.is_learner() {
+            self.lease.borrow_mut().promise(self.now());
+            let message = Message::CommitOk {
+                view_number: self.view_number(),
+                replica_id: self.id,
+                timestamp,
+            };
+            self.send_msg_to_primary(message);
+        }
 
-        let current_commit_number = self.commit_number.load(Ordering::Acquire);
-        if commit_number > current_commit_number {
+        let op_number = self.op_number();
+        self.commit_up_to(commit_number);
+        self.mark_synced(commit_number);
+        self.serve_stale_reads();
+        if commit_number > op_number && !self.awaiting_state.load(Ordering::Acquire) {
             // Perform state transfer
-            return;
+            self.state_transfer();
         }
+    }
 
-        for op_number in current_commit_number..commit_number {
-            // Commit the op
-            self.commit_op(op_number);
+    fn on_commit_ok(&self, view_number: usize, replica_id: usize, timestamp: u64) {
+        if !self.is_primary()
+            || *self.status.borrow() != Status::Normal
+            || self.view_number() != view_number
+            || !self.config.borrow().is_member(replica_id)
+        {
+            return;
         }
+        self.lease.borrow_mut().ack(replica_id, timestamp);
+        if !self.pending_reads.borrow().is_empty() {
+            self.serve_pending_reads();
+        }
+        self.serve_stale_reads();
     }
 
-    pub async fn on_timer(&self) {
-        if self.is_primary() {
-            // Send the `Commit` message to our backups.
-            let view_number = self.view_number();
-            let commit_number = self.commit_number();
-            /*
-            let message = Message::Commit {
-                view_number,
-                commit_number,
-            };
-
-            self.send_msg_to_replicas(message);
-            */
+    fn on_read(&self, client_id: usize, request_number: usize, op: S::Op) {
+        if !self.is_primary() || *self.status.borrow() != Status::Normal {
+            return;
+        }
+        if self.stm.borrow().query(&op).is_none() {
+            // Not a read-only op, it goes through the log like any other request.
+            self.on_request(client_id, request_number, op);
+            return;
+        }
+        let timestamp = self.now();
+        self.pending_reads.borrow_mut().push(PendingRead {
+            timestamp,
+            client_id,
+            request_number,
+            op,
+        });
+        if self.lease.borrow().is_valid(self.quorum(), timestamp) {
+            self.serve_pending_reads();
         } else {
-            let idle_ticks = self.backup_idle_ticks.fetch_add(1, Ordering::Relaxed);
-            if idle_ticks > 0 {
-                // Send the `StartViewChange` message to other backups.
-                self.view_change().await;
-            }
+            // The lease has lapsed, a quorum has to ack a heartbeat sent after the read arrived.
+            self.send_heartbeat();
         }
     }
 
-    async fn state_transfer(&self) {
-        self.status.replace(Status::Recovery);
-        let message = Message::GetState {
-            replica_id: self.id,
-            view_number: self.view_number(),
-            op_number: self.op_number(),
+    /// Answers the reads, that are confirmed either by the lease or by a heartbeat acked after
+    /// they arrived. The ops committed by the previous primary have to be committed first.
+    fn serve_pending_reads(&self) {
+        let ready = {
+            let lease = self.lease.borrow();
+            if self.commit_number() < lease.view_op_number() {
+                return;
+            }
+            let now = self.now();
+            let valid = lease.is_valid(self.quorum(), now);
+            let confirmed = lease.confirmed(self.quorum(), now);
+            let (ready, pending) = std::mem::take(&mut *self.pending_reads.borrow_mut())
+                .into_iter()
+                .partition(|read: &PendingRead<S::Op>| {
+                    valid || confirmed.is_some_and(|confirmed| read.timestamp <= confirmed)
+                });
+            self.pending_reads.replace(pending);
+            ready
         };
-        self.send_msg_to_primary(message).await;
+        for read in ready {
+            let result = self.stm.borrow().query(&read.op);
+            if let Some(result) = result {
+                self.send_reply(read.client_id, read.request_number, result);
+            }
+        }
     }
 
-    fn ack_start_view_change(&self, view_number: usize) {
-        self.view_change_counter
-            .borrow_mut()
-            .entry(view_number)
-            .and_modify(|v| *v += 1)
-            .or_insert(1);
+    fn on_stale_read(&self, read: StaleRead<S::Op>) {
+        if *self.status.borrow() != Status::Normal {
+            return;
+        }
+        if self.stm.borrow().query(&read.op).is_none() {
+            // Not a read-only op, only the primary takes it.
+            self.on_request(read.client_id, read.request_number, read.op);
+            return;
+        }
+        self.stale_reads.borrow_mut().push(read);
+        self.serve_stale_reads();
+        if self.is_primary() && !self.stale_reads.borrow().is_empty() {
+            // Our state is as fresh as the latest heartbeat acked by the quorum.
+            self.send_heartbeat();
+        }
+    }
+
+    /// Answers the stale reads, that our state satisfies.
+    fn serve_stale_reads(&self) {
+        if self.stale_reads.borrow().is_empty() || *self.status.borrow() != Status::Normal {
+            return;
+        }
+        let commit_number = self.commit_number();
+        let staleness = self.staleness();
+        let (ready, waiting): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut *self.stale_reads.borrow_mut())
+                .into_iter()
+                .partition(|read| read.is_satisfied(commit_number, staleness));
+        self.stale_reads.replace(waiting);
+        for read in ready {
+            let result = self.stm.borrow().query(&read.op);
+            if let Some(result) = result {
+                self.send_reply(read.client_id, read.request_number, result);
+            }
+        }
+    }
+
+    /// Called with the commit number learned from the primary.
+    fn mark_synced(&self, primary_commit_number: usize) {
+        if self.commit_number() >= primary_commit_number {
+            self.synced_at.replace(Some(self.now()));
+        }
+    }
+
+    /// Time since we were last known to be up to date. The primary was, when the quorum
+    /// last acked its heartbeat, it might have been replaced since, if it's cut off from the quorum.
+    fn staleness(&self) -> Option<Duration> {
+        let now = self.now();
+        let synced_at = if self.is_primary() {
+            let lease = self.lease.borrow();
+            // The ops committed by the previous primary might not be applied yet.
+            if self.commit_number() < lease.view_op_number() {
+                return None;
+            }
+            lease.confirmed(self.quorum(), now)
+        } else {
+            *self.synced_at.borrow()
+        };
+        synced_at.map(|synced_at| Duration::from_micros(now.saturating_sub(synced_at)))
+    }
+
+    fn send_heartbeat(&self) {
+        let message = Message::Commit {
+            view_number: self.view_number(),
+            commit_number: self.commit_number(),
+            timestamp: self.now(),
+        };
+        self.send_msg_to_followers(message);
+    }
+
+    fn handle_timer(&self) {
+        if *self.status.borrow() == Status::Recovery {
+            // Retransmit the `Recovery`, until enough replicas respond.
+            self.send_recovery();
+            return;
+        }
+        if self.retired.load(Ordering::Acquire) {
+            return;
+        }
+        if self.announce_epoch.swap(false, Ordering::AcqRel) {
+            self.send_start_epoch();
+        }
+        if !self.is_member() && !self.is_learner() {
+            // The leaving replica keeps announcing the new epoch, until the quorum has started it.
+            if self.previous_replicas.borrow().contains(&self.id) {
+                self.send_start_epoch();
+            }
+            return;
+        }
+        if !self.faulty_ops.borrow().is_empty() {
+            self.request_repair();
+        }
+        if *self.status.borrow() == Status::ViewChange {
+            if !self.lease.borrow().is_promised(self.now())
+                && self.faulty_ops.borrow().is_empty()
+                && self.do_view_change_deferred.swap(false, Ordering::AcqRel)
+            {
+                self.send_do_view_change(self.view_number());
+            }
+            let ticks = self.view_change_ticks.fetch_add(1, Ordering::Relaxed) + 1;
+            if ticks >= self.view_change_timeout.load(Ordering::Relaxed) {
+                // No `StartView` arrived in time, the new primary might be down as well.
+                println!(
+                    "View change to view: {} stalled, moving on to the next view",
+                    self.view_number()
+                );
+                self.view_change();
+            }
+            return;
+        }
+        // The commit number is persisted lazily, replaying fewer ops after a restart is safe.
+        self.persist_metadata();
+        if self.commit_number() - self.checkpoint_number()
+            >= self.config.borrow().checkpoint_interval
+        {
+            self.take_checkpoint();
+        }
+        if self.is_primary() {
+            // The prepares or their acks might have been lost, the uncommitted ops are sent again.
+            if self.op_number() > self.commit_number() {
+                self.resend_prepare();
+            }
+            // The `Commit` heartbeat renews the lease as well, so it's sent even while
+            // the `Prepare` messages keep the backups from starting a view change.
+            self.send_heartbeat();
+        } else {
+            let idle_ticks = self.backup_idle_ticks.fetch_add(1, Ordering::Relaxed);
+            // Learners wait for the new primary to reach out, those don't vote for it.
+            if idle_ticks + 1 >= VIEW_CHANGE_TIMEOUT_TICKS && !self.is_learner() {
+                // Send the `StartViewChange` message to other backups.
+                self.view_change();
+                return;
+            }
+            // Retry the state transfer, the response might have been lost.
+            if self.awaiting_state.load(Ordering::Acquire) {
+                self.state_transfer();
+            }
+            if !self.prepare_buffer.borrow().is_empty() {
+                self.requested_op_number.store(0, Ordering::Relaxed);
+                self.request_missing_prepares();
+            }
+        }
+    }
+
+    /// Rotates through the other replicas, starting with the primary,
+    /// so that a replica that's behind as well doesn't stall the state transfer.
+    fn state_transfer_target(&self) -> usize {
+        let attempt = self.state_transfer_attempts.fetch_add(1, Ordering::Relaxed);
+        let view_number = self.view_number();
+        let candidates: Vec<usize> = (0..self.number_of_replicas())
+            .map(|offset| self.config.borrow().primary_id(view_number + offset))
+            .filter(|replica_id| *replica_id != self.id)
+            .collect();
+        candidates[attempt % candidates.len()]
+    }
+
+    /// Asks for the ops, that follow our log.
+    fn state_transfer(&self) {
+        self.awaiting_state.store(true, Ordering::Release);
+        let message = Message::GetState {
+            replica_id: self.id,
+            view_number: self.view_number(),
+            op_number: self.op_number(),
+        };
+        let replica_id = self.state_transfer_target();
+        self.send_msg_to_replica(replica_id, message);
+    }
+
+    /// The view has changed without us, the uncommitted suffix of our log
+    /// might not have survived it, so it's truncated before the state transfer.
+    fn catch_up_view(&self, view_number: usize) {
+        let commit_number = self.commit_number();
+        self.truncate_log(commit_number);
+        self.prepare_buffer.borrow_mut().clear();
+        self.requested_op_number.store(0, Ordering::Relaxed);
+        self.enter_normal_status(view_number);
+        self.rebuild_clients_table();
+        self.state_transfer();
+    }
+
+    /// Appends the buffered prepares, that directly follow our log.
+    /// Returns whether any op got appended.
+    fn append_buffered_prepares(&self) -> bool {
+        let mut appended = false;
+        loop {
+            let mut buffer = self.prepare_buffer.borrow_mut();
+            let Some(prepare) = buffer.first_entry() else {
+                break;
+            };
+            let current_op_number = self.op_number();
+            let op_number = *prepare.key();
+            if op_number <= current_op_number {
+                // We already have these ops, unless our head is corrupt and this is its copy.
+                let entries = prepare.remove();
+                drop(buffer);
+                if op_number == current_op_number
+                    && self.faulty_ops.borrow().contains(&op_number)
+                    && entries
+                        .into_iter()
+                        .last()
+                        .is_some_and(|entry| self.repair_log(op_number, entry, true))
+                {
+                    println!("Repaired op: {}, of replica: {}", op_number, self.id);
+                }
+                continue;
+            }
+            let first_op_number = op_number + 1 - prepare.get().len();
+            if first_op_number > current_op_number + 1 {
+                break;
+            }
+            let entries = prepare.remove();
+            drop(buffer);
+            // Append the ops, that we don't have yet to the log.
+            let skip = current_op_number + 1 - first_op_number;
+            for entry in entries.into_iter().skip(skip) {
+                // The parent of the entry can't be checked against our own corrupt head.
+                let head_faulty = self.faulty_ops.borrow().contains(&self.op_number());
+                if !entry.is_valid() || (!head_faulty && entry.parent != self.head_checksum()) {
+                    eprintln!(
+                        "Rejected corrupt or diverging entry for op: {}",
+                        self.op_number() + 1
+                    );
+                    break;
+                }
+                self.append_to_log(entry);
+                appended = true;
+            }
+            if self.op_number() < op_number {
+                // The rest of the prepare is dropped, it's asked for again
+                // once a later prepare reveals the gap.
+                break;
+            }
+        }
+        if appended && self.prepare_buffer.borrow().is_empty() {
+            self.state_transfer_attempts.store(0, Ordering::Relaxed);
+        }
+        appended
+    }
+
+    /// Asks for the ops between our log and the first buffered prepare.
+    fn request_missing_prepares(&self) {
+        let Some(to_op_number) = self
+            .prepare_buffer
+            .borrow()
+            .iter()
+            .next()
+            .map(|(op_number, entries)| op_number - entries.len())
+        else {
+            return;
+        };
+        // The missing ops were already requested.
+        if self
+            .requested_op_number
+            .swap(to_op_number, Ordering::AcqRel)
+            == to_op_number
+        {
+            return;
+        }
+        let message = Message::RequestPrepare {
+            replica_id: self.id,
+            view_number: self.view_number(),
+            from_op_number: self.op_number() + 1,
+            to_op_number,
+        };
+        let replica_id = self.state_transfer_target();
+        self.send_msg_to_replica(replica_id, message);
+    }
+
+    /// Returns the number of distinct replicas, that sent `StartViewChange` for the view.
+    fn ack_start_view_change(&self, view_number: usize, replica_id: usize) -> usize {
+        let mut counter = self.view_change_counter.borrow_mut();
+        let acks = counter.entry(view_number).or_default();
+        acks.insert(replica_id);
+        acks.len()
     }
 
     fn set_view_change_status(&self) {
@@ -464,121 +1300,226 @@ impl Replica {
     fn enter_start_view_change_stage(&self, view_number: usize) {
         self.set_view_number(view_number);
         self.set_view_change_status();
+        // The clients retry the reads against the new primary.
+        self.pending_reads.borrow_mut().clear();
+        self.stale_reads.borrow_mut().clear();
+        // A restarted replica must not take part in the views it has already left.
+        self.persist_metadata();
+    }
+
+    fn enter_normal_status(&self, view_number: usize) {
+        self.set_view_number(view_number);
+        self.last_normal_view.store(view_number, Ordering::Release);
+        self.status.replace(Status::Normal);
+        self.persist_metadata();
+        self.lease.borrow_mut().reset(self.op_number());
+        self.backup_idle_ticks.store(0, Ordering::Relaxed);
+        self.collect_abandoned_views(view_number + 1);
+    }
+
+    /// Drops the view change state of the views older than `view_number`.
+    fn collect_abandoned_views(&self, view_number: usize) {
+        self.view_change_counter
+            .borrow_mut()
+            .retain(|view, _| *view >= view_number);
+        self.do_view_change_counter
+            .borrow_mut()
+            .retain(|view, _| *view >= view_number);
+        let mut view_snapshot = self.view_snapshot.lock().unwrap();
+        if matches!(&*view_snapshot, Some(snapshot) if snapshot.view_number < view_number) {
+            *view_snapshot = None;
+        }
+    }
+
+    fn last_normal_view(&self) -> usize {
+        self.last_normal_view.load(Ordering::Acquire)
     }
 
-    async fn view_change(&self) {
-        let current_view_number = self.view_number.load(Ordering::Acquire);
-        let view_number = (current_view_number + 1) % self.number_of_replicas();
+    fn view_change(&self) {
+        // View numbers grow without bound, the primary is picked by `ReplicaConfig::primary_id`.
+        let view_number = self.view_number() + 1;
+        self.start_view_change(view_number);
+    }
+
+    fn start_view_change(&self, view_number: usize) {
         self.enter_start_view_change_stage(view_number);
-        self.ack_start_view_change(view_number);
+        self.collect_abandoned_views(view_number);
+        // Back off exponentially with every abandoned view, the jitter keeps
+        // the replicas from timing out in lockstep.
+        let abandoned_views = view_number - self.last_normal_view() - 1;
+        let backoff = VIEW_CHANGE_RETRY_TICKS << abandoned_views.min(VIEW_CHANGE_MAX_BACKOFF);
+        let jitter = self.rng.borrow_mut().below(VIEW_CHANGE_RETRY_TICKS as u64) as usize;
+        self.view_change_timeout
+            .store(backoff + jitter, Ordering::Relaxed);
+        self.view_change_ticks.store(0, Ordering::Relaxed);
+        self.do_view_change_deferred.store(false, Ordering::Relaxed);
+        self.ack_start_view_change(view_number, self.id);
         let message = Message::StartViewChange {
             view_number,
             replica_id: self.id,
         };
-        self.send_msg_to_replicas(message).await;
+        self.send_msg_to_replicas(message);
     }
 
     fn on_start_view(
         &self,
         view_number: usize,
         op_number: usize,
-        replica_id: usize,
+        _replica_id: usize,
         commit_number: usize,
-        log: Vec<Op>,
+        checkpoint: Checkpoint,
+        log: Vec<LogEntry<S::Op>>,
     ) {
-        println!("Started new view: {}, for replica: {}", view_number, self.id);
-        self.status.replace(Status::Normal);
-        self.set_view_number(view_number);
-        self.set_op_number(op_number);
-        *self.log.borrow_mut() = log;
+        if view_number < self.view_number()
+            || (view_number == self.view_number() && *self.status.borrow() == Status::Normal)
+        {
+            return;
+        }
+        if !log::find_faulty_ops(&log, &checkpoint).is_empty() {
+            eprintln!("Rejected corrupt log of the view: {}", view_number);
+            return;
+        }
+        println!(
+            "Started new view: {}, for replica: {}",
+            view_number, self.id
+        );
+        self.enter_normal_status(view_number);
+        self.replace_log(checkpoint, log);
         // Commit uncommited ops.
-        // There is no need to send `PrepareOk` messages to the primary
-        // since we preemptively commit uncommited ops on primary
-        // once quorum for `DoViewChange` is reached.
         let current_commit_number = self.commit_number();
         if current_commit_number < commit_number {
             for uncommited_op in current_commit_number..commit_number {
                 self.commit_op(uncommited_op);
             }
         }
+        self.rebuild_clients_table();
+        // Ack the uncommitted ops, so that the new primary can commit them.
+        if op_number > commit_number && !self.is_learner() {
+            let message = Message::PrepareOk {
+                view_number,
+                op_number,
+                replica_id: self.id,
+            };
+            self.send_msg_to_primary(message);
+        }
     }
 
-    async fn on_start_view_change(&self, view_number: usize, replica_id: usize) {
+    fn on_start_view_change(&self, view_number: usize, replica_id: usize) {
         assert!(self.id != replica_id);
+        if view_number < self.view_number()
+            || (view_number == self.view_number() && *self.status.borrow() == Status::Normal)
+        {
+            return;
+        }
         if view_number > self.view_number() {
-            self.set_view_change_status();
-            // Ack our own `StartViewChange`
-            self.ack_start_view_change(view_number);
+            // Join the view change and let the others know about it.
+            self.start_view_change(view_number);
         }
         // Ack the incomming `StartViewChange`
-        self.ack_start_view_change(view_number);
+        let acks = self.ack_start_view_change(view_number, replica_id);
 
-        if *self.view_change_counter.borrow().get(&view_number).unwrap() >= self.quorum() {
-            // Send message to new primary.
-            let log = self.log.borrow().clone();
-            let op_number = self.op_number();
+        // A replica with corrupt entries can't vouch for its log, it sits the view change out
+        // and the view is started by the others.
+        if !self.faulty_ops.borrow().is_empty() {
+            return;
+        }
+        // Send the `DoViewChange` once, when the quorum is reached.
+        if acks == self.quorum() {
+            // We might have promised the lease to the previous primary, it could still be
+            // serving reads, so the new view can't start with our help until the promise expires.
+            if self.lease.borrow().is_promised(self.now()) {
+                self.do_view_change_deferred.store(true, Ordering::Release);
+                return;
+            }
+            self.send_do_view_change(view_number);
+        }
+    }
+
+    fn send_do_view_change(&self, view_number: usize) {
+        let checkpoint = self.checkpoint.borrow().clone();
+        let log = self.log.borrow().clone();
+        let op_number = self.op_number();
+        let commit_number = self.commit_number();
+        let last_normal_view = self.last_normal_view();
+        let primary_id = self.config.borrow().primary_id(view_number);
+        if primary_id == self.id {
+            let candidate = ViewSnapshot::new(
+                view_number,
+                last_normal_view,
+                op_number,
+                commit_number,
+                checkpoint,
+                log,
+            );
+            self.on_do_view_change(self.id, candidate);
+        } else {
             let message = Message::DoViewChange {
                 view_number,
+                last_normal_view,
                 op_number,
-                commit_number: self.commit_number(),
+                commit_number,
                 replica_id: self.id,
+                checkpoint,
                 log,
             };
-            self.send_msg_to_replica(view_number, message).await;
+            self.send_msg_to_replica(primary_id, message);
         }
     }
 
-    async fn on_do_view_change(
-        &self,
-        view_number: usize,
-        op_number: usize,
-        replica_id: usize,
-        commit_number: usize,
-        log: Vec<Op>,
-    ) {
-        if *self.status.borrow() == Status::Normal {
+    fn on_do_view_change(&self, replica_id: usize, candidate: ViewSnapshot<S::Op>) {
+        let view_number = candidate.view_number;
+        if self.config.borrow().primary_id(view_number) != self.id {
             return;
         }
-        // Store the best candidate for log transplant.
-        let mut view_snapshot = self.view_snapshot.lock().unwrap();
-        if let Some(snapshot) = &mut *view_snapshot {
-            if view_number > snapshot.view_number {
-                *snapshot = ViewSnapshot::new(view_number, op_number, commit_number, log);
-            } else if op_number > snapshot.op_number {
-                *snapshot = ViewSnapshot::new(view_number, op_number, commit_number, log);
+        if view_number < self.view_number()
+            || (view_number == self.view_number() && *self.status.borrow() == Status::Normal)
+        {
+            return;
+        }
+        if !log::find_faulty_ops(&candidate.log, &candidate.checkpoint).is_empty() {
+            eprintln!("Rejected corrupt log from replica: {}", replica_id);
+            return;
+        }
+        if view_number > self.view_number() {
+            // `DoViewChange` arrived before the quorum of `StartViewChange`.
+            self.start_view_change(view_number);
+        }
+        // Store the best candidate for log transplant, the log from the latest normal view wins,
+        // ties are broken by the op number.
+        {
+            let mut view_snapshot = self.view_snapshot.lock().unwrap();
+            match &mut *view_snapshot {
+                Some(snapshot) if snapshot.view_number == view_number => {
+                    let commit_number = candidate.commit_number.max(snapshot.commit_number);
+                    if (candidate.last_normal_view, candidate.op_number)
+                        > (snapshot.last_normal_view, snapshot.op_number)
+                    {
+                        *snapshot = candidate;
+                    }
+                    snapshot.commit_number = commit_number;
+                }
+                // Snapshot from the abandoned view change.
+                _ => {
+                    *view_snapshot = Some(candidate);
+                }
             }
-        } else {
-            *view_snapshot = Some(ViewSnapshot::new(
-                view_number,
-                op_number,
-                commit_number,
-                log,
-            ));
         }
-        drop(view_snapshot);
 
-        if *self
-            .do_view_change_counter
-            .borrow_mut()
-            .entry(view_number)
-            .and_modify(|v| *v += 1)
-            .or_insert(1)
-            >= self.quorum()
-        {
+        let acks = {
+            let mut counter = self.do_view_change_counter.borrow_mut();
+            let acks = counter.entry(view_number).or_default();
+            acks.insert(replica_id);
+            acks.len()
+        };
+        if acks == self.quorum() {
             println!("Starting new view...");
-            assert!(self.view_snapshot.lock().unwrap().is_some());
             // Take log from the most up to date replica.
             let snapshot = self.view_snapshot.lock().unwrap().take().unwrap();
-            let log = snapshot.log;
             let commit_number = snapshot.commit_number;
             let op_number = snapshot.op_number;
-            *self.log.borrow_mut() = log.clone();
-            // Set op number and commit number
-            self.set_op_number(op_number);
-            // Set the new view number.
-            self.set_view_number(view_number);
-            // Switch back to normal state.
-            self.status.replace(Status::Normal);
+            self.replace_log(snapshot.checkpoint, snapshot.log);
+            // Set the new view number and switch back to normal state.
+            self.enter_normal_status(view_number);
             // Commit uncommited ops.
             let current_commit_number = self.commit_number();
             if current_commit_number < commit_number {
@@ -586,6 +1527,11 @@ impl Replica {
                     self.commit_op(uncommited_op);
                 }
             }
+            self.rebuild_clients_table();
+            // Uncommitted ops get committed, once the backups ack them.
+            self.pipeline
+                .borrow_mut()
+                .reset(commit_number, op_number, self.id);
 
             // Send `StartView` Message to other replicas.
             let message = Message::StartView {
@@ -593,58 +1539,1098 @@ impl Replica {
                 commit_number,
                 op_number,
                 replica_id: self.id,
-                log,
+                checkpoint: self.checkpoint.borrow().clone(),
+                log: self.log.borrow().clone(),
             };
-            self.send_msg_to_replicas(message).await;
+            self.send_msg_to_followers(message);
         }
     }
 
-    async fn on_get_state(&self, replica_id: usize, view_number: usize, op_number: usize) {
+    fn on_get_state(&self, replica_id: usize, view_number: usize, op_number: usize) {
         let current_view_number = self.view_number();
         if current_view_number != view_number {
             return;
         }
-        if *self.status.borrow() == Status::ViewChange {
+        if *self.status.borrow() != Status::Normal {
+            return;
+        }
+        // Nothing the requesting replica doesn't have already.
+        if self.op_number() <= op_number {
             return;
         }
 
-        let log = self.log.borrow();
+        // Ops preceding our checkpoint are gone, the checkpoint is sent instead.
+        let (checkpoint, log) = if op_number < self.checkpoint_number() {
+            let checkpoint = self.checkpoint.borrow().clone();
+            (Some(checkpoint), self.log.borrow().clone())
+        } else {
+            (None, self.log_suffix(op_number))
+        };
         let message = Message::NewState {
             view_number: current_view_number,
-            log: log[..op_number].to_owned(),
+            checkpoint,
+            log,
             op_number: self.op_number(),
             commit_number: self.commit_number(),
         };
-        self.send_msg_to_replica(replica_id, message).await;
+        self.send_msg_to_replica(replica_id, message);
     }
 
-    async fn on_new_state(
+    fn on_new_state(
         &self,
         view_number: usize,
-        log: Vec<Op>,
+        checkpoint: Option<Checkpoint>,
+        log: Vec<LogEntry<S::Op>>,
         op_number: usize,
         commit_number: usize,
     ) {
-        assert_eq!(*self.status.borrow(), Status::Recovery);
+        if *self.status.borrow() != Status::Normal {
+            return;
+        }
         if self.view_number() != view_number {
             return;
         }
-
-        for op in log {
-            self.append_to_log(op);
+        self.awaiting_state.store(false, Ordering::Release);
+        let installed = match checkpoint {
+            Some(checkpoint) if checkpoint.op_number > self.commit_number() => {
+                self.install_checkpoint(checkpoint);
+                true
+            }
+            _ => false,
+        };
+        // The new state is delivered like a prepare of the whole log suffix.
+        self.prepare_buffer.borrow_mut().insert(op_number, log);
+        let appended = self.append_buffered_prepares();
+        if installed {
+            self.rebuild_clients_table();
         }
-        for op_number in self.commit_number()..commit_number {
-            self.commit_op(op_number);
+        if !appended && !installed {
+            return;
+        }
+        self.commit_up_to(commit_number);
+        self.sync_log();
+        // The sender might not be the primary, so it doesn't vouch for the freshness of our state.
+        self.serve_stale_reads();
+        if self.is_learner() {
+            return;
         }
-        assert_eq!(self.op_number(), op_number);
-        assert_eq!(self.commit_number(), commit_number);
-        self.status.replace(Status::Normal);
 
-        let view_number = self.view_number();
         let message = Message::PrepareOk {
+            op_number: self.ack_op_number(),
+            view_number,
+            replica_id: self.id,
+        };
+        self.send_msg_to_primary(message);
+    }
+
+    fn on_request_prepare(
+        &self,
+        replica_id: usize,
+        view_number: usize,
+        from_op_number: usize,
+        to_op_number: usize,
+    ) {
+        if *self.status.borrow() != Status::Normal || self.view_number() != view_number {
+            return;
+        }
+        let to_op_number = to_op_number.min(self.op_number());
+        // Op numbers start at 1, there's no op 0 to send.
+        if from_op_number == 0 || from_op_number > to_op_number {
+            return;
+        }
+        if from_op_number <= self.checkpoint_number() {
+            // The ops are compacted, the replica needs our checkpoint.
+            self.on_get_state(replica_id, view_number, from_op_number - 1);
+            return;
+        }
+        let checkpoint_number = self.checkpoint_number();
+        let entries = self.log.borrow()
+            [from_op_number - checkpoint_number - 1..to_op_number - checkpoint_number]
+            .to_owned();
+        let message = Message::Prepare {
+            view_number,
+            op_number: to_op_number,
+            commit_number: self.commit_number(),
+            entries,
+        };
+        self.send_msg_to_replica(replica_id, message);
+    }
+
+    /// Asks every peer for the corrupt entries, the first valid copy,
+    /// that chains to its neighbours is taken. Nothing follows the head of the log
+    /// to vouch for its copy, it's asked for with `RequestPrepare` instead.
+    fn request_repair(&self) {
+        if *self.status.borrow() != Status::Normal {
+            return;
+        }
+        let op_numbers: Vec<usize> = self
+            .faulty_ops
+            .borrow()
+            .iter()
+            .take(MAX_REPAIRS_PER_TICK)
+            .copied()
+            .collect();
+        for op_number in op_numbers {
+            if op_number == self.op_number() {
+                // The primary of the view has no one to ask, it's repaired by the next view change.
+                if !self.is_primary() {
+                    let message = Message::RequestPrepare {
+                        replica_id: self.id,
+                        view_number: self.view_number(),
+                        from_op_number: op_number,
+                        to_op_number: op_number,
+                    };
+                    self.send_msg_to_primary(message);
+                }
+                continue;
+            }
+            let message = Message::RequestRepair {
+                replica_id: self.id,
+                view_number: self.view_number(),
+                op_number,
+            };
+            self.send_msg_to_replicas(message);
+        }
+    }
+
+    fn on_request_repair(&self, replica_id: usize, view_number: usize, op_number: usize) {
+        // Our log might not be the one of the requester's view.
+        if *self.status.borrow() != Status::Normal
+            || self.view_number() != view_number
+            || op_number <= self.checkpoint_number()
+            || op_number > self.op_number()
+            || self.faulty_ops.borrow().contains(&op_number)
+        {
+            return;
+        }
+        let entry = self.entry(op_number);
+        let message = Message::Repair {
+            view_number,
+            op_number,
+            entry,
+        };
+        self.send_msg_to_replica(replica_id, message);
+    }
+
+    fn on_repair(&self, view_number: usize, op_number: usize, entry: LogEntry<S::Op>) {
+        if *self.status.borrow() != Status::Normal || self.view_number() != view_number {
+            return;
+        }
+        if self.repair_log(op_number, entry, false) {
+            println!("Repaired op: {}, of replica: {}", op_number, self.id);
+        }
+    }
+
+    fn on_recovery(&self, replica_id: usize, nonce: u64) {
+        if *self.status.borrow() != Status::Normal {
+            return;
+        }
+        // Only the primary sends its state, backups just confirm the view.
+        let (checkpoint, log, op_number, commit_number) = if self.is_primary() {
+            (
+                self.checkpoint.borrow().clone(),
+                self.log.borrow().clone(),
+                self.op_number(),
+                self.commit_number(),
+            )
+        } else {
+            (Checkpoint::default(), Vec::new(), 0, 0)
+        };
+        let message = Message::RecoveryResponse {
+            view_number: self.view_number(),
+            nonce,
+            replica_id: self.id,
+            checkpoint,
+            log,
             op_number,
+            commit_number,
+        };
+        self.send_msg_to_replica(replica_id, message);
+    }
+
+    fn on_recovery_response(&self, nonce: u64, replica_id: usize, response: ViewSnapshot<S::Op>) {
+        if *self.status.borrow() != Status::Recovery {
+            return;
+        }
+        if *self.recovery_nonce.borrow() != Some(nonce) {
+            return;
+        }
+        if !log::find_faulty_ops(&response.log, &response.checkpoint).is_empty() {
+            eprintln!("Rejected corrupt log from replica: {}", replica_id);
+            return;
+        }
+        let mut responses = self.recovery_responses.borrow_mut();
+        responses.insert(replica_id, response);
+        if responses.len() < self.quorum() {
+            return;
+        }
+        // Wait for the primary of the latest view we've learned about.
+        let view_number = responses.values().map(|r| r.view_number).max().unwrap();
+        let primary_id = self.config.borrow().primary_id(view_number);
+        if primary_id == self.id {
+            return;
+        }
+        match responses.get(&primary_id) {
+            Some(response) if response.view_number == view_number => {}
+            _ => return,
+        }
+        let snapshot = responses.remove(&primary_id).unwrap();
+        responses.clear();
+        drop(responses);
+
+        println!(
+            "Recovered replica: {}, in view: {}, op_number: {}, commit_number: {}",
+            self.id, snapshot.view_number, snapshot.op_number, snapshot.commit_number
+        );
+        self.replace_log(snapshot.checkpoint, snapshot.log);
+        for op_number in self.commit_number()..snapshot.commit_number {
+            self.commit_op(op_number);
+        }
+        self.rebuild_clients_table();
+        self.recovery_nonce.replace(None);
+        self.enter_normal_status(snapshot.view_number);
+    }
+}
+
+// Reconfiguration
+impl<S: StateMachine> Replica<S> {
+    /// Entry point for the messages of the other replicas. Those from another epoch
+    /// are answered with our `StartEpoch`, so that whichever replica is behind catches up.
+    fn handle_peer_message(&self, envelope: Envelope, message: Message<S::Op, S::Result>) {
+        if self.retired.load(Ordering::Acquire) {
+            return;
+        }
+        if envelope.cluster_id != self.config.borrow().cluster_id {
+            eprintln!(
+                "Dropped message from replica: {}, of cluster: {}",
+                envelope.replica_id, envelope.cluster_id
+            );
+            return;
+        }
+        let Envelope {
+            epoch_number,
+            replica_id,
+            ..
+        } = envelope;
+        let current_epoch_number = self.epoch_number();
+        match message {
+            // Those compare the epochs themselves.
+            Message::StartEpoch { .. } | Message::EpochStarted { .. } => {}
+            // The replica, that's behind, fetches the new epoch with the state transfer.
+            Message::GetState { .. } if epoch_number < current_epoch_number => {}
+            Message::NewState { .. } if epoch_number > current_epoch_number => {}
+            _ if epoch_number != current_epoch_number => {
+                let message = Message::StartEpoch {
+                    epoch_number: current_epoch_number,
+                    view_number: self.view_number(),
+                    replica_id: self.id,
+                };
+                self.send_msg_to_replica(replica_id, message);
+                return;
+            }
+            _ => {}
+        }
+        self.handle_message(message);
+    }
+
+    /// Whether the log holds a reconfiguration, that's not committed yet.
+    fn reconfiguration_pending(&self) -> bool {
+        let uncommitted = self.commit_number() - self.checkpoint_number();
+        self.log.borrow()[uncommitted..]
+            .iter()
+            .any(|entry| entry.is_reconfiguration())
+    }
+
+    fn on_reconfiguration(
+        &self,
+        client_id: usize,
+        request_number: usize,
+        epoch_number: usize,
+        replicas: Vec<usize>,
+    ) {
+        if !self.is_primary() || *self.status.borrow() != Status::Normal {
+            return;
+        }
+        let current_epoch_number = self.epoch_number();
+        if epoch_number < current_epoch_number {
+            // The epoch has been replaced already, the reply might have been lost.
+            self.send_reply(client_id, request_number, current_epoch_number as u64);
+            return;
+        }
+        if epoch_number > current_epoch_number
+            || !self.config.borrow().is_valid_membership(&replicas)
+        {
+            eprintln!(
+                "Rejected reconfiguration of epoch: {} to replicas: {:?}",
+                epoch_number, replicas
+            );
+            return;
+        }
+        // One reconfiguration at a time, the retried one is answered once it's committed.
+        // It doesn't go through the client table, the epoch number tells the duplicates apart.
+        if self.reconfiguration_pending() {
+            return;
+        }
+        // The batched requests are prepared first, nothing follows the reconfiguration.
+        self.flush_batch();
+        if !self.batch.borrow().is_empty() || !self.pipeline.borrow().has_room_for(1) {
+            return;
+        }
+        println!(
+            "Preparing reconfiguration of epoch: {} to replicas: {:?}",
+            epoch_number, replicas
+        );
+        let entry = LogEntry::reconfiguration(client_id, request_number, replicas);
+        self.prepare_entries(vec![entry]);
+    }
+
+    /// Called once the reconfiguration is committed, the following ops belong to the new epoch.
+    fn enter_epoch(&self, replicas: Vec<usize>) {
+        let previous_replicas = self.config.borrow().replicas.clone();
+        self.adopt_configuration(self.epoch_number() + 1, replicas);
+        self.previous_replicas.replace(previous_replicas);
+        self.epoch_started.borrow_mut().clear();
+        self.announce_epoch.store(true, Ordering::Release);
+        // The clients retry the reads against the primary of the new epoch.
+        self.pending_reads.borrow_mut().clear();
+        self.lease.borrow_mut().reset(self.op_number());
+        self.backup_idle_ticks.store(0, Ordering::Relaxed);
+        if self.is_primary() {
+            self.pipeline
+                .borrow_mut()
+                .reset(self.commit_number(), self.op_number(), self.id);
+        }
+    }
+
+    /// Replaces the membership, the caller makes sure the epoch is newer than ours.
+    pub(crate) fn adopt_configuration(&self, epoch_number: usize, replicas: Vec<usize>) {
+        println!(
+            "Replica: {} entered epoch: {}, with replicas: {:?}",
+            self.id, epoch_number, replicas
+        );
+        let mut config = self.config.borrow_mut();
+        config.epoch_number = epoch_number;
+        config.replicas = replicas;
+    }
+
+    /// Lets the members of both epochs know about the new one.
+    fn send_start_epoch(&self) {
+        let mut replica_ids = self.previous_replicas.borrow().clone();
+        replica_ids.extend(self.config.borrow().replicas.iter().copied());
+        replica_ids.sort_unstable();
+        replica_ids.dedup();
+        replica_ids.retain(|replica_id| *replica_id != self.id);
+        let message = Message::StartEpoch {
+            epoch_number: self.epoch_number(),
+            view_number: self.view_number(),
+            replica_id: self.id,
+        };
+        self.send_msg_to_group(replica_ids, message);
+    }
+
+    fn on_start_epoch(&self, epoch_number: usize, view_number: usize, replica_id: usize) {
+        let current_epoch_number = self.epoch_number();
+        let recovering = *self.status.borrow() == Status::Recovery;
+        if epoch_number < current_epoch_number && !recovering {
+            // The sender has missed the reconfiguration.
+            let message = Message::StartEpoch {
+                epoch_number: current_epoch_number,
+                view_number: self.view_number(),
+                replica_id: self.id,
+            };
+            self.send_msg_to_replica(replica_id, message);
+            return;
+        }
+        if epoch_number == current_epoch_number && !recovering {
+            // The leaving replica waits for the quorum of the new epoch.
+            if self.is_member() && !self.config.borrow().is_member(replica_id) {
+                let message = Message::EpochStarted {
+                    epoch_number,
+                    replica_id: self.id,
+                };
+                self.send_msg_to_replica(replica_id, message);
+            }
+            return;
+        }
+        if epoch_number <= current_epoch_number {
+            return;
+        }
+        println!(
+            "Replica: {} is behind epoch: {}, fetching the state from replica: {}",
+            self.id, epoch_number, replica_id
+        );
+        // Our uncommitted ops might not have made it into the new epoch. The epoch takes
+        // precedence over the view, so we take over the view of the sender.
+        self.truncate_log(self.commit_number());
+        self.prepare_buffer.borrow_mut().clear();
+        self.requested_op_number.store(0, Ordering::Relaxed);
+        self.recovery_nonce.replace(None);
+        self.enter_normal_status(view_number);
+        self.rebuild_clients_table();
+        self.awaiting_state.store(true, Ordering::Release);
+        // The reconfiguration is committed along with the state, which moves us into the epoch.
+        let message = Message::GetState {
+            replica_id: self.id,
             view_number,
+            op_number: self.op_number(),
+        };
+        self.send_msg_to_replica(replica_id, message);
+    }
+
+    fn on_epoch_started(&self, epoch_number: usize, replica_id: usize) {
+        if epoch_number != self.epoch_number()
+            || self.is_member()
+            || !self.config.borrow().is_member(replica_id)
+        {
+            return;
+        }
+        let started = {
+            let mut epoch_started = self.epoch_started.borrow_mut();
+            epoch_started.insert(replica_id);
+            epoch_started.len()
+        };
+        if started >= self.quorum() {
+            println!(
+                "Replica: {} left the cluster, epoch: {} has started",
+                self.id, epoch_number
+            );
+            self.retired.store(true, Ordering::Release);
+        }
+    }
+}
+
+// Recovery
+impl<S: StateMachine> Replica<S> {
+    fn start_recovery(&self) {
+        let nonce = self.rng.borrow_mut().next_u64();
+        self.status.replace(Status::Recovery);
+        self.persist_metadata();
+        self.recovery_nonce.replace(Some(nonce));
+        self.recovery_responses.borrow_mut().clear();
+        self.send_recovery();
+    }
+
+    fn send_recovery(&self) {
+        let Some(nonce) = *self.recovery_nonce.borrow() else {
+            return;
         };
-        self.send_msg_to_primary(message).await;
+        println!("Replica: {} is recovering with nonce: {}", self.id, nonce);
+        let message = Message::Recovery {
+            replica_id: self.id,
+            nonce,
+        };
+        self.send_msg_to_replicas(message);
+    }
+}
+
+// Model checking
+/// Hashes the state, that decides how the replica handles the next events, so that the model checker
+/// visits every state once. The clock, the rng and the lease are left out, those only delay the replica.
+impl<S: StateMachine> Hash for Replica<S> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let checksums = |log: &[LogEntry<S::Op>]| -> Vec<u64> {
+            log.iter().map(|entry| entry.checksum).collect()
+        };
+        let voters = |counter: &HashMap<usize, HashSet<usize>>| -> Vec<(usize, Vec<usize>)> {
+            let mut voters: Vec<(usize, Vec<usize>)> = counter
+                .iter()
+                .map(|(view_number, replicas)| {
+                    let mut replicas: Vec<usize> = replicas.iter().copied().collect();
+                    replicas.sort_unstable();
+                    (*view_number, replicas)
+                })
+                .collect();
+            voters.sort_unstable();
+            voters
+        };
+        self.id.hash(state);
+        self.status.borrow().hash(state);
+        self.epoch_number().hash(state);
+        self.config.borrow().replicas.hash(state);
+        self.view_number().hash(state);
+        self.op_number().hash(state);
+        self.commit_number().hash(state);
+        self.last_normal_view.load(Ordering::Acquire).hash(state);
+        checksums(&self.log.borrow()).hash(state);
+        let checkpoint = self.checkpoint.borrow();
+        (checkpoint.op_number, checkpoint.checksum).hash(state);
+        self.stm.borrow().snapshot().hash(state);
+        if let Some(snapshot) = &*self.view_snapshot.lock().unwrap() {
+            (
+                snapshot.view_number,
+                snapshot.last_normal_view,
+                snapshot.op_number,
+                snapshot.commit_number,
+                snapshot.checkpoint.op_number,
+                checksums(&snapshot.log),
+            )
+                .hash(state);
+        }
+        self.pipeline.borrow().hash(state);
+        for entry in self.batch.borrow().iter() {
+            (entry.client_id, entry.request_number).hash(state);
+        }
+        for (op_number, entries) in self.prepare_buffer.borrow().iter() {
+            (op_number, checksums(entries)).hash(state);
+        }
+        voters(&self.view_change_counter.borrow()).hash(state);
+        voters(&self.do_view_change_counter.borrow()).hash(state);
+        let mut recovered_from: Vec<usize> =
+            self.recovery_responses.borrow().keys().copied().collect();
+        recovered_from.sort_unstable();
+        recovered_from.hash(state);
+        self.recovery_nonce.borrow().hash(state);
+        self.faulty_ops.borrow().hash(state);
+        for counter in [
+            &self.batch_number,
+            &self.backup_idle_ticks,
+            &self.view_change_ticks,
+            &self.requested_op_number,
+            &self.state_transfer_attempts,
+            &self.view_change_timeout,
+        ] {
+            counter.load(Ordering::Relaxed).hash(state);
+        }
+        for flag in [
+            &self.awaiting_state,
+            &self.do_view_change_deferred,
+            &self.announce_epoch,
+            &self.retired,
+        ] {
+            flag.load(Ordering::Relaxed).hash(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimClock, counter::Counter, log::Payload};
+    use client::{
+        reply::{Reply, Response, StaleRequest},
+        Op,
+    };
+    use std::net::SocketAddr;
+
+    fn config(max_batch_size: usize) -> ReplicaConfig {
+        ReplicaConfig {
+            addresses: (0..3)
+                .map(|id| SocketAddr::from(([127, 0, 0, 1], 1000 + id)))
+                .collect(),
+            replicas: vec![0, 1, 2],
+            max_batch_size,
+            ..Default::default()
+        }
+    }
+
+    fn request(request_number: usize, value: u64) -> Message<Op, u64> {
+        Message::Request {
+            client_id: 1,
+            request_number,
+            op: Op::Add(value),
+        }
+    }
+
+    /// Delivers the messages addressed to the replica, returns the actions they led to.
+    fn deliver(actions: Vec<Action<Op, u64>>, to: &Replica<Counter>) -> Vec<Action<Op, u64>> {
+        actions
+            .into_iter()
+            .flat_map(|action| match action {
+                Action::SendToReplica {
+                    replica_id,
+                    envelope,
+                    message,
+                } if replica_id == to.id => to.on_peer_message(envelope, message),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Prepares `count` requests on the primary and a backup.
+    fn replicate(count: usize) -> (Replica<Counter>, Replica<Counter>) {
+        let primary = Replica::<Counter>::new(0, config(1));
+        let backup = Replica::<Counter>::new(1, config(1));
+        for request_number in 0..count {
+            deliver(primary.on_message(request(request_number, 5)), &backup);
+        }
+        assert_eq!(backup.op_number(), count);
+        (primary, backup)
+    }
+
+    /// Bit rot in the entry, as found in the journal at startup.
+    fn corrupt(replica: &Replica<Counter>, op_number: usize) {
+        replica.log.borrow_mut()[op_number - 1].payload = Payload::Op(Op::Add(70));
+        replica.faulty_ops.borrow_mut().insert(op_number);
+    }
+
+    fn request_repair(replica: &Replica<Counter>) -> Vec<Action<Op, u64>> {
+        replica.request_repair();
+        replica.take_actions()
+    }
+
+    #[test]
+    fn primary_should_prepare_the_request_for_the_backups() {
+        let primary = Replica::<Counter>::new(0, config(1));
+        let actions = primary.on_message(request(0, 5));
+        let receivers: Vec<usize> = actions
+            .iter()
+            .map(|action| match action {
+                Action::SendToReplica {
+                    replica_id,
+                    message:
+                        Message::Prepare {
+                            view_number: 0,
+                            op_number: 1,
+                            commit_number: 0,
+                            entries,
+                        },
+                    ..
+                } if entries.len() == 1 => *replica_id,
+                action => panic!("Unexpected action: {:?}", action),
+            })
+            .collect();
+        assert_eq!(receivers, vec![1, 2]);
+        assert_eq!(primary.op_number(), 1);
+    }
+
+    #[test]
+    fn quorum_of_acks_should_commit_and_reply() {
+        let primary = Replica::<Counter>::new(0, config(1));
+        let backup = Replica::<Counter>::new(1, config(1));
+        let Action::SendToReplica {
+            envelope, message, ..
+        } = primary.on_message(request(0, 5)).remove(0)
+        else {
+            panic!("Expected the prepare");
+        };
+
+        let mut actions = backup.on_peer_message(envelope, message);
+        assert_eq!(actions.len(), 1);
+        let Action::SendToReplica {
+            replica_id: 0,
+            envelope,
+            message,
+        } = actions.remove(0)
+        else {
+            panic!("Expected the ack to the primary");
+        };
+        assert_eq!(
+            message,
+            Message::PrepareOk {
+                view_number: 0,
+                op_number: 1,
+                replica_id: 1,
+            }
+        );
+
+        let actions = primary.on_peer_message(envelope, message);
+        let checksum = primary.entry(1).checksum;
+        assert_eq!(
+            actions[0],
+            Action::Commit {
+                op_number: 1,
+                checksum
+            }
+        );
+        let Action::SendToClient {
+            client_id: 1,
+            bytes,
+        } = &actions[1]
+        else {
+            panic!("Expected the reply to the client");
+        };
+        let reply = Reply::<u64>::from_bytes(&bytes[4..]).unwrap();
+        assert_eq!((reply.request_number, reply.result), (0, 5));
+        // The backup learns about the commit from the next prepare or heartbeat.
+        assert_eq!(backup.commit_number(), 0);
+    }
+
+    #[test]
+    fn batch_should_be_flushed_once_the_window_passes() {
+        let primary = Replica::<Counter>::new(0, config(64));
+        let actions = primary.on_message(request(0, 5));
+        let [Action::ScheduleFlush { batch_number, .. }] = actions[..] else {
+            panic!("Expected the batch window to be scheduled: {:?}", actions);
+        };
+        // Joins the batch, that's already waiting.
+        assert!(primary.on_message(request(1, 7)).is_empty());
+        assert_eq!(primary.op_number(), 0);
+
+        let actions = primary.on_batch_window(batch_number);
+        assert!(matches!(
+            &actions[0],
+            Action::SendToReplica {
+                message: Message::Prepare { entries, .. },
+                ..
+            } if entries.len() == 2
+        ));
+        // The window of the flushed batch.
+        assert!(primary.on_batch_window(batch_number).is_empty());
+    }
+
+    #[test]
+    fn corrupt_entry_should_be_repaired_from_a_peer() {
+        let (primary, backup) = replicate(3);
+        corrupt(&backup, 2);
+        let actions = request_repair(&backup);
+        assert!(actions.iter().all(|action| matches!(
+            action,
+            Action::SendToReplica {
+                message: Message::RequestRepair {
+                    view_number: 0,
+                    op_number: 2,
+                    ..
+                },
+                ..
+            }
+        )));
+
+        // A copy, that the following entry doesn't chain to, is rejected.
+        let mut forged = primary.entry(2);
+        forged.payload = Payload::Op(Op::Add(70));
+        forged.seal(primary.entry(1).checksum);
+        assert!(!backup.repair_log(2, forged, false));
+
+        deliver(deliver(actions, &primary), &backup);
+        assert!(backup.faulty_ops.borrow().is_empty());
+        assert_eq!(backup.entry(2), primary.entry(2));
+    }
+
+    #[test]
+    fn corrupt_head_should_be_repaired_from_a_prepare() {
+        let (primary, backup) = replicate(3);
+        corrupt(&backup, 3);
+        // Nothing follows the head to vouch for the copy.
+        assert!(!backup.repair_log(3, primary.entry(3), false));
+
+        let actions = request_repair(&backup);
+        assert!(matches!(
+            &actions[..],
+            [Action::SendToReplica {
+                replica_id: 0,
+                message: Message::RequestPrepare {
+                    from_op_number: 3,
+                    to_op_number: 3,
+                    ..
+                },
+                ..
+            }]
+        ));
+        let actions = deliver(deliver(actions, &primary), &backup);
+        assert!(backup.faulty_ops.borrow().is_empty());
+        assert_eq!(backup.entry(3), primary.entry(3));
+        // The head is acked, once it's repaired.
+        assert!(actions.iter().any(|action| matches!(
+            action,
+            Action::SendToReplica {
+                message: Message::PrepareOk { op_number: 3, .. },
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn repair_from_another_view_should_be_ignored() {
+        let (primary, backup) = replicate(3);
+        corrupt(&backup, 2);
+        let repairs = deliver(request_repair(&backup), &primary);
+        assert_eq!(repairs.len(), 1);
+
+        // The view changed, while the repair was in flight.
+        backup.set_view_number(1);
+        deliver(repairs, &backup);
+        assert!(backup.faulty_ops.borrow().contains(&2));
+        // Nor is the request of the next view answered by the previous one.
+        assert!(deliver(request_repair(&backup), &primary).is_empty());
+    }
+
+    #[test]
+    fn primary_cut_off_from_the_quorum_should_not_serve_fresh_stale_reads() {
+        let clock = SimClock::default();
+        let replica =
+            |id| Replica::<Counter>::with_clock(id, config(1), Box::new(clock.clone()), 0);
+        let (primary, backup) = (replica(0), replica(1));
+        let stale_read = |request_number| Message::StaleRead {
+            client_id: 1,
+            request_number,
+            min_commit_number: 0,
+            max_staleness_millis: 100,
+            op: Op::Nop,
+        };
+        let replied = |actions: &[Action<Op, u64>]| {
+            actions
+                .iter()
+                .any(|action| matches!(action, Action::SendToClient { client_id: 1, .. }))
+        };
+
+        // Never confirmed by the quorum, the read waits for the heartbeat to be acked.
+        let heartbeat = primary.on_message(stale_read(0));
+        assert!(!replied(&heartbeat));
+        let actions = deliver(deliver(heartbeat, &backup), &primary);
+        assert!(replied(&actions));
+        assert!(replied(&primary.on_message(stale_read(1))));
+
+        // Partitioned from the backups, the heartbeats are no longer acked.
+        clock.set(clock.now() + 1_000_000);
+        assert!(!replied(&primary.on_message(stale_read(2))));
+    }
+
+    #[test]
+    fn request_prepare_from_op_zero_should_be_ignored() {
+        let (primary, backup) = replicate(2);
+        let message = Message::RequestPrepare {
+            replica_id: backup.id,
+            view_number: 0,
+            from_op_number: 0,
+            to_op_number: 2,
+        };
+        assert!(primary
+            .on_peer_message(backup.envelope(), message)
+            .is_empty());
+    }
+
+    #[test]
+    fn stale_request_should_be_answered_with_the_latest_request_number() {
+        let primary = Replica::<Counter>::new(0, config(1));
+        primary.on_message(request(0, 5));
+        primary.on_message(request(1, 5));
+        // A restarted client, that starts over with its request numbers.
+        let actions = primary.on_message(request(0, 7));
+        let [Action::SendToClient {
+            client_id: 1,
+            bytes,
+        }] = &actions[..]
+        else {
+            panic!("Unexpected actions: {:?}", actions);
+        };
+        let Response::Stale(stale) = Response::<u64>::from_bytes(&bytes[4..]).unwrap() else {
+            panic!("Expected a stale request");
+        };
+        assert_eq!(
+            stale,
+            StaleRequest {
+                request_number: 0,
+                latest_request_number: 1,
+            }
+        );
+        assert_eq!(primary.op_number(), 2);
+    }
+
+    /// Copies of the recovery responses, as if they answered an earlier recovery of the replica.
+    fn with_another_nonce(actions: &[Action<Op, u64>]) -> Vec<Action<Op, u64>> {
+        actions
+            .iter()
+            .map(|action| {
+                let Action::SendToReplica {
+                    replica_id,
+                    envelope,
+                    message,
+                } = action
+                else {
+                    panic!("Expected the recovery response");
+                };
+                let mut message = message.clone();
+                if let Message::RecoveryResponse { nonce, .. } = &mut message {
+                    *nonce = nonce.wrapping_add(1);
+                }
+                Action::SendToReplica {
+                    replica_id: *replica_id,
+                    envelope: *envelope,
+                    message,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovery_response_with_another_nonce_should_be_ignored() {
+        let primary = Replica::<Counter>::new(0, config(1));
+        let backup = Replica::<Counter>::new(1, config(1));
+        let recovering = Replica::<Counter>::new(2, config(1));
+        let mut responses = deliver(recovering.recover(), &primary);
+        // The retransmitted `Recovery` carries the same nonce.
+        recovering.send_recovery();
+        responses.extend(deliver(recovering.take_actions(), &backup));
+        assert_eq!(responses.len(), 2);
+
+        deliver(with_another_nonce(&responses), &recovering);
+        assert_eq!(*recovering.status.borrow(), Status::Recovery);
+
+        deliver(responses, &recovering);
+        assert_eq!(*recovering.status.borrow(), Status::Normal);
+    }
+
+    #[test]
+    fn recovering_replica_should_adopt_the_primary_state_after_a_quorum_of_responses() {
+        let primary = Replica::<Counter>::new(0, config(1));
+        let backup = Replica::<Counter>::new(1, config(1));
+        for request_number in 0..3 {
+            deliver(
+                deliver(primary.on_message(request(request_number, 5)), &backup),
+                &primary,
+            );
+        }
+        assert_eq!(primary.commit_number(), 3);
+
+        let recovering = Replica::<Counter>::new(2, config(1));
+        deliver(deliver(recovering.recover(), &primary), &recovering);
+        // The response of the primary alone isn't a quorum.
+        assert_eq!(*recovering.status.borrow(), Status::Recovery);
+        assert_eq!((recovering.op_number(), recovering.commit_number()), (0, 0));
+
+        recovering.send_recovery();
+        let responses = deliver(recovering.take_actions(), &backup);
+        deliver(with_another_nonce(&responses), &recovering);
+        assert_eq!(*recovering.status.borrow(), Status::Recovery);
+        assert_eq!((recovering.op_number(), recovering.commit_number()), (0, 0));
+
+        deliver(responses, &recovering);
+        assert_eq!(*recovering.status.borrow(), Status::Normal);
+        assert_eq!((recovering.op_number(), recovering.commit_number()), (3, 3));
+        assert_eq!(*recovering.log.borrow(), *primary.log.borrow());
+    }
+
+    #[test]
+    fn view_change_should_pick_the_log_of_the_latest_normal_view_over_a_longer_one() {
+        let chain = |values: &[u64]| {
+            let mut parent = 0;
+            values
+                .iter()
+                .enumerate()
+                .map(|(request_number, value)| {
+                    let mut entry = LogEntry::new(1, request_number, Op::Add(*value));
+                    entry.seal(parent);
+                    parent = entry.checksum;
+                    entry
+                })
+                .collect::<Vec<_>>()
+        };
+        let do_view_change = |replica_id: usize, last_normal_view: usize, log: Vec<_>| {
+            let sender = Replica::<Counter>::new(replica_id, config(1));
+            let message = Message::DoViewChange {
+                view_number: 2,
+                last_normal_view,
+                op_number: log.len(),
+                commit_number: 0,
+                replica_id,
+                checkpoint: Checkpoint::default(),
+                log,
+            };
+            (sender.envelope(), message)
+        };
+        let older_log = chain(&[1, 2, 3, 4, 5]);
+        let newer_log = chain(&[6, 7, 8]);
+
+        // The primary of view 2 hears first from the replica with the longer log of view 0.
+        let primary = Replica::<Counter>::new(2, config(1));
+        let (envelope, message) = do_view_change(1, 0, older_log);
+        primary.on_peer_message(envelope, message);
+        let (envelope, message) = do_view_change(0, 1, newer_log.clone());
+        primary.on_peer_message(envelope, message);
+
+        assert_eq!(*primary.status.borrow(), Status::Normal);
+        assert_eq!(primary.view_number(), 2);
+        assert_eq!(primary.op_number(), 3);
+        assert_eq!(*primary.log.borrow(), newer_log);
+    }
+
+    #[test]
+    fn idle_primary_should_send_the_commit_heartbeat_on_the_timer() {
+        let primary = Replica::<Counter>::new(0, config(1));
+        let backup = Replica::<Counter>::new(1, config(1));
+        // The backup acks the prepare, it learns the commit from the heartbeat only.
+        deliver(
+            deliver(primary.on_message(request(0, 5)), &backup),
+            &primary,
+        );
+        assert_eq!((primary.commit_number(), backup.commit_number()), (1, 0));
+
+        for _ in 0..VIEW_CHANGE_TIMEOUT_TICKS {
+            let actions = primary.on_timer();
+            let receivers: Vec<usize> = actions
+                .iter()
+                .filter_map(|action| match action {
+                    Action::SendToReplica {
+                        replica_id,
+                        message:
+                            Message::Commit {
+                                view_number: 0,
+                                commit_number: 1,
+                                ..
+                            },
+                        ..
+                    } => Some(*replica_id),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(receivers, vec![1, 2]);
+            deliver(actions, &backup);
+            backup.on_timer();
+        }
+        assert_eq!(backup.commit_number(), 1);
+        // The quiet primary doesn't look dead to the backup.
+        assert_eq!(*backup.status.borrow(), Status::Normal);
+        assert_eq!(backup.view_number(), 0);
+    }
+
+    #[test]
+    fn stalled_view_change_should_back_off_exponentially_up_to_the_cap() {
+        let backup = Replica::<Counter>::new(1, config(1));
+        for _ in 0..VIEW_CHANGE_TIMEOUT_TICKS {
+            backup.on_timer();
+        }
+        assert_eq!(*backup.status.borrow(), Status::ViewChange);
+
+        // Nobody else answers, every view change stalls.
+        for abandoned_views in 0..VIEW_CHANGE_MAX_BACKOFF + 2 {
+            let view_number = backup.view_number();
+            assert_eq!(view_number, abandoned_views + 1);
+            let timeout = backup.view_change_timeout.load(Ordering::Relaxed);
+            let backoff = VIEW_CHANGE_RETRY_TICKS << abandoned_views.min(VIEW_CHANGE_MAX_BACKOFF);
+            assert!((backoff..backoff + VIEW_CHANGE_RETRY_TICKS).contains(&timeout));
+
+            for _ in 1..timeout {
+                backup.on_timer();
+            }
+            assert_eq!(backup.view_number(), view_number);
+            backup.on_timer();
+            assert_eq!(backup.view_number(), view_number + 1);
+        }
+    }
+
+    #[test]
+    fn backup_with_a_gap_should_request_only_the_missing_ops() {
+        let primary = Replica::<Counter>::new(0, config(1));
+        let backup = Replica::<Counter>::new(1, config(1));
+        deliver(primary.on_message(request(0, 5)), &backup);
+        // The prepare of op 2 gets lost.
+        primary.on_message(request(1, 6));
+        let actions = deliver(primary.on_message(request(2, 7)), &backup);
+        assert_eq!(backup.op_number(), 1);
+        assert!(matches!(
+            &actions[..],
+            [Action::SendToReplica {
+                replica_id: 0,
+                message: Message::RequestPrepare {
+                    from_op_number: 2,
+                    to_op_number: 2,
+                    ..
+                },
+                ..
+            }]
+        ));
+
+        // The missing op fills the gap, the buffered one follows it.
+        let actions = deliver(deliver(actions, &primary), &backup);
+        assert_eq!(backup.op_number(), 3);
+        assert_eq!(*backup.log.borrow(), *primary.log.borrow());
+        assert!(backup.prepare_buffer.borrow().is_empty());
+        assert!(actions.iter().any(|action| matches!(
+            action,
+            Action::SendToReplica {
+                message: Message::PrepareOk { op_number: 3, .. },
+                ..
+            }
+        )));
     }
 }
@@ -1,15 +1,118 @@
-use std::net::SocketAddr;
+use client::config::ClusterConfig;
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
 
-#[derive(Default, Clone)]
+use crate::pipeline::MAX_REPLICAS;
+
+const DEFAULT_BATCH_WINDOW: Duration = Duration::from_millis(5);
+const DEFAULT_MAX_BATCH_SIZE: usize = 64;
+const DEFAULT_CHECKPOINT_INTERVAL: usize = 1024;
+const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(1);
+const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
 pub struct ReplicaConfig {
+    // Messages from the replicas of another cluster are dropped.
+    pub cluster_id: u64,
+    // Addresses of all the replicas, including those outside of the current epoch.
     pub addresses: Vec<SocketAddr>,
+    // Members of the current epoch.
     pub replicas: Vec<usize>,
+    // Replicas, that follow the log without voting. Those never become primary
+    // and don't count towards the quorum.
+    pub learners: Vec<usize>,
+    // Bumped by every committed reconfiguration.
+    pub epoch_number: usize,
+    // Period of the timer, the timeouts are counted in its ticks.
+    pub tick_interval: Duration,
+    // How long the primary waits for more requests before preparing a batch.
+    pub batch_window: Duration,
+    // Batch is prepared right away, once it reaches this many requests.
+    pub max_batch_size: usize,
+    // Number of committed ops, after which a checkpoint is taken and the log compacted.
+    pub checkpoint_interval: usize,
+    // How long the acked heartbeat lets the primary serve reads, without going through the log.
+    // The view change waits at least this long after the backups' last ack.
+    pub lease_duration: Duration,
+}
+
+impl Default for ReplicaConfig {
+    fn default() -> Self {
+        Self {
+            cluster_id: 0,
+            addresses: Vec::new(),
+            replicas: Vec::new(),
+            learners: Vec::new(),
+            epoch_number: 0,
+            tick_interval: DEFAULT_TICK_INTERVAL,
+            batch_window: DEFAULT_BATCH_WINDOW,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            lease_duration: DEFAULT_LEASE_DURATION,
+        }
+    }
 }
 
 impl ReplicaConfig {
-    pub fn append_new(&mut self, id: usize, address: SocketAddr) {
-        self.addresses.push(address);
-        self.replicas.push(id);
+    /// The voters make up the initial epoch, standbys are only addressable.
+    /// Fails if the cluster has more replicas, than the acks can be tracked for.
+    pub fn from_cluster_config(cluster: &ClusterConfig) -> io::Result<Self> {
+        let addresses = cluster.addresses();
+        if addresses.len() > MAX_REPLICAS {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Cluster has {} replicas, at most {} are supported",
+                    addresses.len(),
+                    MAX_REPLICAS
+                ),
+            ));
+        }
+        let defaults = Self::default();
+        Ok(Self {
+            cluster_id: cluster.cluster_id,
+            addresses,
+            replicas: cluster.voters(),
+            learners: cluster.learners(),
+            tick_interval: cluster.tick.unwrap_or(defaults.tick_interval),
+            batch_window: cluster.batch_window.unwrap_or(defaults.batch_window),
+            max_batch_size: cluster.max_batch_size.unwrap_or(defaults.max_batch_size),
+            checkpoint_interval: cluster
+                .checkpoint_interval
+                .unwrap_or(defaults.checkpoint_interval),
+            lease_duration: cluster.lease_duration.unwrap_or(defaults.lease_duration),
+            ..defaults
+        })
+    }
+
+    pub fn is_member(&self, replica_id: usize) -> bool {
+        self.replicas.contains(&replica_id)
+    }
+
+    /// Learner, that hasn't been made a member by a reconfiguration.
+    pub fn is_learner(&self, replica_id: usize) -> bool {
+        self.learners.contains(&replica_id) && !self.is_member(replica_id)
+    }
+
+    /// Replicas, that receive the log from the primary.
+    pub fn replicas_and_learners(&self) -> Vec<usize> {
+        let learners = self.learners.iter().filter(|id| !self.is_member(**id));
+        self.replicas.iter().chain(learners).copied().collect()
+    }
+
+    /// Members of the next epoch have to be known, distinct replicas, with the ids the acks can be tracked for.
+    pub fn is_valid_membership(&self, replicas: &[usize]) -> bool {
+        let mut sorted = replicas.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        !replicas.is_empty()
+            && sorted.len() == replicas.len()
+            && replicas
+                .iter()
+                .all(|replica_id| *replica_id < self.addresses.len().min(MAX_REPLICAS))
     }
 
     pub fn get_replica_address(&self, replica_id: usize) -> SocketAddr {
@@ -21,3 +124,50 @@ impl ReplicaConfig {
         self.replicas[idx]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learners_should_neither_vote_nor_lead() {
+        let mut config = ReplicaConfig {
+            replicas: vec![0, 1, 2],
+            learners: vec![3],
+            ..Default::default()
+        };
+        assert!((0..6).all(|view_number| config.primary_id(view_number) != 3));
+        assert!(config.is_learner(3));
+        assert!(!config.is_member(3));
+        assert_eq!(config.replicas_and_learners(), vec![0, 1, 2, 3]);
+
+        // Promoted by a reconfiguration.
+        config.replicas.push(3);
+        assert!(!config.is_learner(3));
+        assert_eq!(config.replicas_and_learners(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn cluster_too_large_for_the_ack_set_should_be_rejected() {
+        let config = |count: usize| {
+            let mut text = String::from("cluster_id = 1\n");
+            for id in 0..count {
+                text += &format!(
+                    "[[replica]]\nid = {}\naddress = \"127.0.0.1:{}\"\n",
+                    id,
+                    1000 + id
+                );
+            }
+            ClusterConfig::parse(&text).unwrap()
+        };
+        assert!(ReplicaConfig::from_cluster_config(&config(MAX_REPLICAS)).is_ok());
+        assert!(ReplicaConfig::from_cluster_config(&config(MAX_REPLICAS + 1)).is_err());
+
+        let config = ReplicaConfig {
+            addresses: vec![SocketAddr::from(([127, 0, 0, 1], 1000)); MAX_REPLICAS + 1],
+            ..Default::default()
+        };
+        assert!(config.is_valid_membership(&[0, 1, 2]));
+        assert!(!config.is_valid_membership(&[0, 1, MAX_REPLICAS]));
+    }
+}
@@ -0,0 +1,55 @@
+use std::hash::{BuildHasher, Hasher, RandomState};
+
+/// Seedable pseudo random generator (SplitMix64), the same seed yields the same sequence.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seeded from `RandomState`, which is good enough for nonces and jitter.
+    pub fn from_entropy() -> Self {
+        Self::new(RandomState::new().build_hasher().finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..bound`, the bound has to be positive.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    /// Uniform in `low..=high`.
+    pub fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.below(high - low + 1)
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        // The top 53 bits make up the mantissa of a uniform float in `0..1`.
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_should_yield_the_same_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let sequence: Vec<u64> = (0..16).map(|_| a.next_u64()).collect();
+        assert!(sequence.iter().all(|value| *value == b.next_u64()));
+        assert_ne!(Rng::new(43).next_u64(), sequence[0]);
+        assert!((0..100).all(|_| a.between(3, 5) >= 3 && a.between(3, 5) <= 5));
+    }
+}
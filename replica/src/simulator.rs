@@ -0,0 +1,737 @@
+use client::{
+    history::{Event as HistoryEvent, History},
+    reply::Response,
+    request::Request,
+    Op,
+};
+
+use crate::{
+    action::Action,
+    clock::{Clock, SimClock},
+    counter::Counter,
+    invariants::InvariantChecker,
+    linearizability,
+    message::Message,
+    replica::Replica,
+    replica_config::ReplicaConfig,
+    rng::Rng,
+    stm::StateMachine,
+};
+use std::{
+    any::Any,
+    cmp::Reverse,
+    collections::BinaryHeap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
+const REPLICA_COUNT: usize = 3;
+const CLIENT_COUNT: usize = 3;
+const REQUESTS_PER_CLIENT: usize = 20;
+// Small enough for the checkpoints to be taken and shipped in the state transfer.
+const CHECKPOINT_INTERVAL: usize = 8;
+const CLIENT_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+// Share of the requests, that read the counter instead of adding to it.
+const READ_PROBABILITY: f64 = 0.3;
+// Latency of the healthy network.
+const MIN_LATENCY: Duration = Duration::from_millis(1);
+const MAX_LATENCY: Duration = Duration::from_millis(10);
+// Faults are injected until then, the cluster has to finish the requests and converge afterwards.
+const FAULT_PHASE: Duration = Duration::from_secs(60);
+const HEALED_PHASE: Duration = Duration::from_secs(300);
+// Period, at which the partitions and crashes are considered.
+const FAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+/// Rates of the injected faults, picked by the seed, so that the seeds explore different mixes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Faults {
+    pub drop_probability: f64,
+    pub duplicate_probability: f64,
+    // The delayed message overtakes the ones sent after it.
+    pub delay_probability: f64,
+    pub max_delay: Duration,
+    // Considered every `FAULT_INTERVAL`.
+    pub partition_probability: f64,
+    pub crash_probability: f64,
+}
+
+impl Faults {
+    fn random(rng: &mut Rng) -> Self {
+        let percent = |rng: &mut Rng, max: u64| rng.below(max + 1) as f64 / 100.0;
+        Self {
+            drop_probability: percent(rng, 10),
+            duplicate_probability: percent(rng, 5),
+            delay_probability: percent(rng, 20),
+            max_delay: Duration::from_millis(rng.between(100, 2000)),
+            partition_probability: percent(rng, 10),
+            crash_probability: percent(rng, 5),
+        }
+    }
+
+    fn none() -> Self {
+        Self {
+            drop_probability: 0.0,
+            duplicate_probability: 0.0,
+            delay_probability: 0.0,
+            max_delay: Duration::ZERO,
+            partition_probability: 0.0,
+            crash_probability: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+enum Endpoint {
+    Replica(usize),
+    Client(usize),
+}
+
+struct Packet {
+    from: Endpoint,
+    to: Endpoint,
+    bytes: Vec<u8>,
+}
+
+enum Event {
+    Deliver(Packet),
+    Tick(usize),
+    BatchWindow {
+        replica_id: usize,
+        batch_number: usize,
+    },
+    ClientTimeout {
+        client_id: usize,
+        request_number: usize,
+    },
+    Fault,
+}
+
+/// Event scheduled at `time`, the sequence number orders the events of the same time.
+struct Scheduled {
+    time: u64,
+    sequence: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.sequence) == (other.time, other.sequence)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.time, self.sequence).cmp(&(other.time, other.sequence))
+    }
+}
+
+/// Client, that adds to or reads the counter one request at a time and resends the request
+/// to every replica once it times out, like `client::Client` does.
+struct SimClient {
+    id: usize,
+    request_number: usize,
+    view_number: usize,
+    op: Op,
+    // Ops along with the counter values replied for them.
+    completed: Vec<(Op, u64)>,
+}
+
+impl SimClient {
+    fn is_done(&self) -> bool {
+        self.completed.len() == REQUESTS_PER_CLIENT
+    }
+}
+
+/// Result of a simulation, that passed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub seed: u64,
+    pub faults: Faults,
+    pub committed: usize,
+    pub events: usize,
+    pub elapsed: Duration,
+    // Hash of every delivered message along with its delivery time, equal for the runs of the same seed.
+    pub digest: u64,
+}
+
+/// Runs the cluster of `Replica`s in a single thread, over a simulated network and clock.
+/// Every decision, from the latencies to the crashes, is drawn from the one seeded `Rng`,
+/// so a failing seed reproduces exactly.
+///
+/// The simulator carries out the actions returned by the replicas, the durable mode is not used.
+/// A crashed replica loses its state and comes back through the recovery protocol,
+/// at most a minority of the replicas is down at a time.
+pub struct Simulator {
+    seed: u64,
+    rng: Rng,
+    clock: SimClock,
+    config: ReplicaConfig,
+    faults: Faults,
+    // Faults picked by the seed, those are healed at the end of `FAULT_PHASE`.
+    initial_faults: Faults,
+    // `None` while the replica is crashed.
+    replicas: Vec<Option<Replica<Counter>>>,
+    clients: Vec<SimClient>,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    sequence: u64,
+    // Partition of each replica, messages only flow within the partition.
+    partitions: Vec<usize>,
+    events: usize,
+    digest: DefaultHasher,
+    // Invocations and completions of the client requests, checked for linearizability at the end.
+    history: History<Op, u64>,
+    // Checks the safety of the replicas after every event, only in the debug builds.
+    invariants: Option<InvariantChecker>,
+}
+
+impl Simulator {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let faults = Faults::random(&mut rng);
+        let config = ReplicaConfig {
+            // Never connected to, the replicas are only told apart by their ids.
+            addresses: (0..REPLICA_COUNT)
+                .map(|id| SocketAddr::from(([127, 0, 0, 1], 1000 + id as u16)))
+                .collect(),
+            replicas: (0..REPLICA_COUNT).collect(),
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            ..Default::default()
+        };
+        let clients = (0..CLIENT_COUNT)
+            .map(|id| SimClient {
+                id: id + 1,
+                request_number: 0,
+                view_number: 0,
+                op: Op::Nop,
+                completed: Vec::new(),
+            })
+            .collect();
+        let mut simulator = Self {
+            seed,
+            rng,
+            clock: SimClock::default(),
+            config,
+            faults,
+            initial_faults: faults,
+            replicas: Vec::new(),
+            clients,
+            queue: BinaryHeap::new(),
+            sequence: 0,
+            partitions: vec![0; REPLICA_COUNT],
+            events: 0,
+            digest: DefaultHasher::new(),
+            history: History::default(),
+            invariants: cfg!(debug_assertions).then(InvariantChecker::new),
+        };
+        for id in 0..REPLICA_COUNT {
+            let replica = simulator.create_replica(id);
+            simulator.replicas.push(Some(replica));
+            // The timers of the replicas are out of phase.
+            let phase = simulator.rng.below(micros(simulator.config.tick_interval));
+            simulator.schedule(phase, Event::Tick(id));
+        }
+        simulator.schedule(micros(FAULT_INTERVAL), Event::Fault);
+        for client_index in 0..CLIENT_COUNT {
+            simulator.send_next_request(client_index);
+        }
+        simulator
+    }
+
+    fn create_replica(&mut self, id: usize) -> Replica<Counter> {
+        let seed = self.rng.next_u64();
+        Replica::with_clock(id, self.config.clone(), Box::new(self.clock.clone()), seed)
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    fn schedule(&mut self, delay: u64, event: Event) {
+        self.sequence += 1;
+        self.queue.push(Reverse(Scheduled {
+            time: self.now() + delay,
+            sequence: self.sequence,
+            event,
+        }));
+    }
+
+    /// Runs the simulation to the end, panics once the cluster misbehaves.
+    pub fn run(mut self) -> Outcome {
+        let healed_at = micros(FAULT_PHASE);
+        let deadline = healed_at + micros(HEALED_PHASE);
+        let mut healed = false;
+        while let Some(Reverse(scheduled)) = self.queue.pop() {
+            self.clock.set(scheduled.time);
+            if !healed && self.now() >= healed_at {
+                self.heal();
+                healed = true;
+            }
+            if healed && self.is_converged() {
+                break;
+            }
+            assert!(
+                self.now() < deadline,
+                "The cluster didn't converge after the faults were healed: {}",
+                self.describe()
+            );
+            self.events += 1;
+            self.handle(scheduled.event);
+        }
+        self.check_state();
+        Outcome {
+            seed: self.seed,
+            faults: self.initial_faults,
+            committed: self.replicas[0].as_ref().unwrap().commit_number(),
+            events: self.events,
+            elapsed: Duration::from_micros(self.now()),
+            digest: self.digest.finish(),
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Deliver(packet) => self.deliver(packet),
+            Event::Tick(id) => {
+                self.record(format!("replica: {} ticked", id));
+                if let Some(replica) = &self.replicas[id] {
+                    let actions = replica.on_timer();
+                    self.execute(id, actions);
+                }
+                let tick = micros(self.config.tick_interval);
+                self.schedule(tick, Event::Tick(id));
+            }
+            Event::BatchWindow {
+                replica_id,
+                batch_number,
+            } => {
+                self.record(format!(
+                    "replica: {} batch window: {}",
+                    replica_id, batch_number
+                ));
+                if let Some(replica) = &self.replicas[replica_id] {
+                    let actions = replica.on_batch_window(batch_number);
+                    self.execute(replica_id, actions);
+                }
+            }
+            Event::ClientTimeout {
+                client_id,
+                request_number,
+            } => {
+                let client = &self.clients[client_id - 1];
+                if client.request_number == request_number && !client.is_done() {
+                    // The primary might be down, every replica gets the request.
+                    for replica_id in 0..REPLICA_COUNT {
+                        self.send_request(client_id - 1, replica_id);
+                    }
+                    self.schedule(
+                        micros(CLIENT_REQUEST_TIMEOUT),
+                        Event::ClientTimeout {
+                            client_id,
+                            request_number,
+                        },
+                    );
+                }
+            }
+            Event::Fault => {
+                self.inject_fault();
+                self.schedule(micros(FAULT_INTERVAL), Event::Fault);
+            }
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        (self.now(), packet.from, packet.to, &packet.bytes).hash(&mut self.digest);
+        match packet.to {
+            Endpoint::Replica(id) => {
+                if let Endpoint::Replica(from) = packet.from {
+                    if self.partitions[from] != self.partitions[id] {
+                        return;
+                    }
+                }
+                let Some(replica) = &self.replicas[id] else {
+                    return;
+                };
+                let (envelope, message) = Message::<Op, u64>::parse_envelope(&packet.bytes[4..])
+                    .expect("The cluster sends well-formed messages");
+                let time = self.now();
+                if let Some(invariants) = &mut self.invariants {
+                    invariants.record(format!(
+                        "{}us replica: {} received from {:?}: {:?}",
+                        time, id, packet.from, message
+                    ));
+                }
+                let actions = match envelope {
+                    Some(envelope) => replica.on_peer_message(envelope, message),
+                    None => replica.on_message(message),
+                };
+                self.execute(id, actions);
+            }
+            Endpoint::Client(client_id) => {
+                // Duplicates of the earlier requests are stale, the clients ignore them.
+                let Response::Reply(reply) = Response::<u64>::from_bytes(&packet.bytes[4..])
+                    .expect("The replicas only send responses to the clients")
+                else {
+                    return;
+                };
+                let index = client_id - 1;
+                let client = &mut self.clients[index];
+                client.view_number = client.view_number.max(reply.view_number);
+                // Duplicates and replies to the earlier requests.
+                if reply.request_number != client.request_number || client.is_done() {
+                    return;
+                }
+                client.completed.push((client.op.clone(), reply.result));
+                let request_number = client.request_number;
+                client.request_number += 1;
+                let time = self.now();
+                self.history.record(HistoryEvent::Complete {
+                    client_id,
+                    request_number,
+                    result: reply.result,
+                    time,
+                });
+                self.send_next_request(index);
+            }
+        }
+    }
+
+    fn send_next_request(&mut self, index: usize) {
+        if self.clients[index].is_done() {
+            return;
+        }
+        let op = if self.rng.chance(READ_PROBABILITY) {
+            Op::Nop
+        } else {
+            Op::Add(self.rng.between(1, 100))
+        };
+        let time = self.now();
+        let client = &mut self.clients[index];
+        client.op = op.clone();
+        let (client_id, request_number) = (client.id, client.request_number);
+        self.history.record(HistoryEvent::Invoke {
+            client_id,
+            request_number,
+            op,
+            time,
+        });
+        let primary_id = self.config.primary_id(client.view_number);
+        self.send_request(index, primary_id);
+        self.schedule(
+            micros(CLIENT_REQUEST_TIMEOUT),
+            Event::ClientTimeout {
+                client_id,
+                request_number,
+            },
+        );
+    }
+
+    fn send_request(&mut self, index: usize, replica_id: usize) {
+        let client = &self.clients[index];
+        let (client_id, request_number, op) = (client.id, client.request_number, client.op.clone());
+        // The reads are served by the primary without going through the log.
+        let bytes = match op {
+            Op::Nop => Request::read(client_id, request_number, op).to_bytes(),
+            Op::Add(_) => Request::new(client_id, request_number, op).to_bytes(),
+        };
+        let packet = Packet {
+            from: Endpoint::Client(client.id),
+            to: Endpoint::Replica(replica_id),
+            bytes,
+        };
+        self.send(packet);
+    }
+
+    fn execute(&mut self, replica_id: usize, actions: Vec<Action<Op, u64>>) {
+        if let (Some(invariants), Some(replica)) =
+            (&mut self.invariants, &self.replicas[replica_id])
+        {
+            if let Err(violation) = invariants.check(replica, &actions) {
+                panic!("{}{}", violation, self.describe());
+            }
+        }
+        for action in actions {
+            match action {
+                Action::SendToReplica {
+                    replica_id: to,
+                    envelope,
+                    message,
+                } => {
+                    let packet = Packet {
+                        from: Endpoint::Replica(replica_id),
+                        to: Endpoint::Replica(to),
+                        bytes: message.to_envelope_bytes(envelope),
+                    };
+                    self.send(packet);
+                }
+                Action::SendToClient { client_id, bytes } => {
+                    let packet = Packet {
+                        from: Endpoint::Replica(replica_id),
+                        to: Endpoint::Client(client_id),
+                        bytes,
+                    };
+                    self.send(packet);
+                }
+                Action::Commit { .. } => {}
+                Action::ScheduleFlush {
+                    batch_number,
+                    delay,
+                } => {
+                    let event = Event::BatchWindow {
+                        replica_id,
+                        batch_number,
+                    };
+                    self.schedule(micros(delay), event);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, packet: Packet) {
+        let faults = self.faults;
+        if self.rng.chance(faults.drop_probability) {
+            return;
+        }
+        if self.rng.chance(faults.duplicate_probability) {
+            let duplicate = Packet {
+                bytes: packet.bytes.clone(),
+                ..packet
+            };
+            let latency = self.latency();
+            self.schedule(latency, Event::Deliver(duplicate));
+        }
+        let latency = self.latency();
+        self.schedule(latency, Event::Deliver(packet));
+    }
+
+    fn latency(&mut self) -> u64 {
+        let latency = self.rng.between(micros(MIN_LATENCY), micros(MAX_LATENCY));
+        if self.rng.chance(self.faults.delay_probability) {
+            latency + self.rng.below(micros(self.faults.max_delay) + 1)
+        } else {
+            latency
+        }
+    }
+
+    fn inject_fault(&mut self) {
+        let faults = self.faults;
+        if self.rng.chance(faults.partition_probability) {
+            if self.partitions.iter().all(|partition| *partition == 0) {
+                // Every replica picks one of two sides, one of them might end up empty.
+                self.partitions = (0..REPLICA_COUNT)
+                    .map(|_| self.rng.below(2) as usize)
+                    .collect();
+                println!(
+                    "Simulator: partitioned the replicas into {:?}",
+                    self.partitions
+                );
+            } else {
+                self.partitions = vec![0; REPLICA_COUNT];
+                println!("Simulator: healed the partition");
+            }
+        }
+        if self.rng.chance(faults.crash_probability) {
+            let id = self.rng.below(REPLICA_COUNT as u64) as usize;
+            if self.replicas[id].is_none() {
+                self.restart(id);
+            } else if self.unavailable() < REPLICA_COUNT / 2 {
+                println!("Simulator: crashed replica: {}", id);
+                self.replicas[id] = None;
+                self.record(format!("replica: {} crashed", id));
+                if let Some(invariants) = &mut self.invariants {
+                    invariants.crashed(id);
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, event: String) {
+        if let Some(invariants) = &mut self.invariants {
+            let time = self.clock.now();
+            invariants.record(format!("{}us {}", time, event));
+        }
+    }
+
+    /// Crashed and recovering replicas, those can't help the cluster make progress.
+    fn unavailable(&self) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| {
+                replica.as_ref().is_none_or(|replica| {
+                    *replica.status.borrow() == crate::status::Status::Recovery
+                })
+            })
+            .count()
+    }
+
+    /// The restarted replica has lost its state, it learns it from the others.
+    fn restart(&mut self, id: usize) {
+        println!("Simulator: restarted replica: {}", id);
+        self.record(format!("replica: {} restarted", id));
+        let replica = self.create_replica(id);
+        let actions = replica.recover();
+        self.replicas[id] = Some(replica);
+        self.execute(id, actions);
+    }
+
+    fn heal(&mut self) {
+        println!("Simulator: healing all the faults");
+        self.faults = Faults::none();
+        self.partitions = vec![0; REPLICA_COUNT];
+        for id in 0..REPLICA_COUNT {
+            if self.replicas[id].is_none() {
+                self.restart(id);
+            }
+        }
+    }
+
+    /// Every client got its replies and the replicas have caught up with each other.
+    fn is_converged(&self) -> bool {
+        if !self.clients.iter().all(|client| client.is_done()) {
+            return false;
+        }
+        let replicas: Vec<&Replica<Counter>> = self.replicas.iter().flatten().collect();
+        replicas.len() == REPLICA_COUNT
+            && replicas.iter().all(|replica| {
+                *replica.status.borrow() == crate::status::Status::Normal
+                    && replica.commit_number() == replicas[0].commit_number()
+                    && replica.op_number() == replicas[0].commit_number()
+            })
+    }
+
+    /// Safety of the converged cluster: every request is applied exactly once,
+    /// the replicas ended up with the same state and the clients saw a linearizable history.
+    fn check_state(&self) {
+        let replicas: Vec<&Replica<Counter>> = self.replicas.iter().flatten().collect();
+        let snapshot = replicas[0].stm.borrow().snapshot();
+        for replica in &replicas {
+            assert_eq!(
+                replica.stm.borrow().snapshot(),
+                snapshot,
+                "Replicas diverged: {}",
+                self.describe()
+            );
+        }
+        let total: u64 = self
+            .clients
+            .iter()
+            .flat_map(|client| client.completed.iter())
+            .map(|(op, _)| match op {
+                Op::Add(value) => *value,
+                Op::Nop => 0,
+            })
+            .sum();
+        let mut counter = Counter::default();
+        counter.restore(&snapshot);
+        assert_eq!(
+            counter.query(&Op::Nop),
+            Some(total),
+            "Requests weren't applied exactly once: {}",
+            self.describe()
+        );
+        // The counter only grows, so no two adds can observe the same value.
+        let mut results: Vec<u64> = self
+            .clients
+            .iter()
+            .flat_map(|client| client.completed.iter())
+            .filter(|(op, _)| matches!(op, Op::Add(_)))
+            .map(|(_, result)| *result)
+            .collect();
+        results.sort_unstable();
+        let replied = results.len();
+        results.dedup();
+        assert_eq!(results.len(), replied, "Two adds got the same result");
+        if let Err(violation) = linearizability::check::<Counter>(&self.history) {
+            panic!("{}{}", violation, self.describe());
+        }
+    }
+
+    fn describe(&self) -> String {
+        let replicas: Vec<String> = self
+            .replicas
+            .iter()
+            .enumerate()
+            .map(|(id, replica)| match replica {
+                Some(replica) => format!(
+                    "replica: {} {:?} view: {} op_number: {} commit_number: {}",
+                    id,
+                    *replica.status.borrow(),
+                    replica.view_number(),
+                    replica.op_number(),
+                    replica.commit_number()
+                ),
+                None => format!("replica: {} crashed", id),
+            })
+            .collect();
+        let completed: Vec<usize> = self
+            .clients
+            .iter()
+            .map(|client| client.completed.len())
+            .collect();
+        format!(
+            "seed: {}, time: {:?}, {}, completed requests: {:?}",
+            self.seed,
+            Duration::from_micros(self.now()),
+            replicas.join(", "),
+            completed
+        )
+    }
+}
+
+/// Runs the simulation for the `seed`, the failure carries the panic message.
+pub fn simulate(seed: u64) -> Result<Outcome, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| Simulator::new(seed).run())).map_err(|error| {
+        format!(
+            "Simulation with seed: {} failed: {}",
+            seed,
+            panic_message(error)
+        )
+    })
+}
+
+pub(crate) fn panic_message(error: Box<dyn Any + Send>) -> String {
+    error
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| {
+            error
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster_should_survive_the_faults() {
+        for seed in 0..16 {
+            if let Err(failure) = simulate(seed) {
+                panic!("{}", failure);
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_should_reproduce_the_run() {
+        let first = simulate(7).unwrap();
+        let second = simulate(7).unwrap();
+        assert_eq!(first, second);
+        assert_ne!(simulate(8).unwrap().digest, first.digest);
+    }
+}
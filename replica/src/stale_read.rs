@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+// Sent by the clients, that don't bound the staleness.
+pub const UNBOUNDED_STALENESS_MILLIS: u64 = u64::MAX;
+
+/// Read-only request sent to any replica, which answers it once its state is fresh enough.
+pub struct StaleRead<Op> {
+    pub client_id: usize,
+    pub request_number: usize,
+    pub op: Op,
+    // Op, that the replica has to have committed, e.g. the client's own latest write.
+    pub min_commit_number: usize,
+    pub max_staleness: Option<Duration>,
+}
+
+impl<Op> StaleRead<Op> {
+    pub fn new(
+        client_id: usize,
+        request_number: usize,
+        op: Op,
+        min_commit_number: usize,
+        max_staleness_millis: u64,
+    ) -> Self {
+        let max_staleness = (max_staleness_millis != UNBOUNDED_STALENESS_MILLIS)
+            .then(|| Duration::from_millis(max_staleness_millis));
+        Self {
+            client_id,
+            request_number,
+            op,
+            min_commit_number,
+            max_staleness,
+        }
+    }
+
+    /// `staleness` is the time since the replica was last known to have committed everything
+    /// the primary had, `None` if it never was.
+    pub fn is_satisfied(&self, commit_number: usize, staleness: Option<Duration>) -> bool {
+        let fresh = match (self.max_staleness, staleness) {
+            (None, _) => true,
+            (Some(max_staleness), Some(staleness)) => staleness <= max_staleness,
+            (Some(_), None) => false,
+        };
+        fresh && commit_number >= self.min_commit_number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_should_wait_for_both_bounds() {
+        let read = StaleRead::new(1, 0, (), 5, 100);
+        let fresh = Some(Duration::from_millis(50));
+        assert!(!read.is_satisfied(4, fresh));
+        assert!(read.is_satisfied(5, fresh));
+        assert!(!read.is_satisfied(5, Some(Duration::from_millis(150))));
+        assert!(!read.is_satisfied(5, None));
+
+        let read = StaleRead::new(1, 0, (), 0, UNBOUNDED_STALENESS_MILLIS);
+        assert!(read.is_satisfied(0, None));
+    }
+}
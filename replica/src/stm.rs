@@ -1,22 +1,25 @@
-use std::cell::RefCell;
+use std::fmt::Debug;
 
-use client::Op;
+pub use client::Codec;
 
-#[derive(Default)]
-pub struct StateMachine {
-    inner: RefCell<u64>,
-}
+/// Service replicated by the `Replica`. Ops are applied in the log order on every replica,
+/// so `apply` has to be deterministic.
+pub trait StateMachine: Default {
+    type Op: Codec + Clone + Debug + PartialEq;
+    type Result: Codec + Clone + Debug + PartialEq;
+
+    /// Applies the committed op and returns the result, that's sent back to the client.
+    fn apply(&mut self, op: Self::Op) -> Self::Result;
 
-impl StateMachine {
-    pub fn apply(&self, op: Op) {
-        match op {
-            Op::Add(val) => {
-                let mut inner = self.inner.borrow_mut();
-                *inner += val;
-            }
-            Op::Nop => {
-                println!("Applying No operation to state machine");
-            }
-        }
+    /// Evaluates a read-only op without going through the log.
+    /// Returns `None` if the op changes the state, it's applied through the log then.
+    fn query(&self, _op: &Self::Op) -> Option<Self::Result> {
+        None
     }
+
+    /// Serializes the whole state for a checkpoint.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Restores the state captured by `snapshot`, an empty snapshot is the initial state.
+    fn restore(&mut self, snapshot: &[u8]);
 }